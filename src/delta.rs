@@ -0,0 +1,402 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining incremental synchronization with a server. Instead of exchanging whole lists, a
+//! client sends only the items it has modified since it last synced, and the server merges them into
+//! its list itself. Every sync that changes the items of the server increments its revision, and the
+//! server records the revision in which each item or tombstone last changed. The server answers with
+//! the items and tombstones changed since the revision of the sync token the client got on its
+//! previous sync. If the server cannot tell what has changed since a token, for example because the
+//! tombstones of removed items have expired, it sends all of its items instead.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, ItemState, Limits, ListReport, ReportItem, Result, SyncItem, SyncList, SyncReport, Task, TdList, Todo};
+
+/// Identifies the revision of a server list a client has synced with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SyncToken {
+    // The device id of the server list, so that tokens of other servers, or of a server list that
+    // has been replaced, are not used.
+    server_id: u64,
+    revision: u64,
+}
+
+impl SyncToken {
+    // Compares the revisions of the server a client has synced with. Tokens of different servers
+    // cannot be compared.
+    pub(crate) fn cmp_revision(&self, other: &SyncToken) -> Option<Ordering> {
+        if self.server_id == other.server_id {
+            Some(self.revision.cmp(&other.revision))
+        } else {
+            None
+        }
+    }
+}
+
+/// The items and tombstones of a server list before a sync. See `TdList::stamp_revisions`.
+pub(crate) struct Snapshot {
+    todos: (HashMap<u64, Todo>, BTreeSet<u64>),
+    tasks: (HashMap<u64, Task>, BTreeSet<u64>),
+}
+
+/// The items a client has modified since it last synced.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DeltaRequest {
+    token: Option<SyncToken>,
+    // A client list containing only the modified items. It is serialized like a whole list, so it is
+    // migrated from older schema versions.
+    changes: Value,
+}
+
+impl DeltaRequest {
+    /// Creates a request containing the unsynced changes of a client list.
+    pub(crate) fn new(td_list: &TdList) -> Result<Self> {
+        let mut changes = TdList::new_client();
+        changes.todos = td_list.todos.unsynced();
+        changes.tasks = td_list.tasks.unsynced();
+
+        Ok(Self { token: td_list.sync_token, changes: serde_json::from_str(&changes.to_json()?)? })
+    }
+
+    /// Gets the token of the server state the client last synced with.
+    pub(crate) fn token(&self) -> Option<SyncToken> {
+        self.token
+    }
+
+    /// Gets the modified items as a client list. The items are checked against the limits before
+    /// the list is parsed.
+    pub(crate) fn changes(&self, limits: &Limits) -> Result<TdList> {
+        limits.check_list(&self.changes)?;
+        TdList::new_from_json(&self.changes.to_string())
+    }
+}
+
+/// The items and tombstones changed on the server since the token of a `DeltaRequest`.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DeltaResponse {
+    token: SyncToken,
+    // If true, the list contains all the items of the server instead of the changed ones.
+    full: bool,
+    list: Value,
+    // The changes the server made to its own items.
+    report: SyncReport,
+}
+
+impl DeltaResponse {
+    /// Gets the changed items as a server list. The items are checked against the limits before the
+    /// list is parsed.
+    pub(crate) fn list(&self, limits: &Limits) -> Result<TdList> {
+        limits.check_list(&self.list)?;
+        TdList::new_from_json(&self.list.to_string())
+    }
+}
+
+impl TdList {
+    /// Merges the modified items of a client into self, a server, like `sync` does. Returns the items
+    /// changed since the given token, which include the merged items.
+    pub(crate) fn merge_delta(&mut self, mut changes: TdList, token: Option<SyncToken>) -> Result<DeltaResponse> {
+        if !self.server {
+            return Err(Error::ServerOnlyOperation);
+        }
+        if changes.server {
+            return Err(Error::ClientOnlyOperation);
+        }
+
+        let requested_todos = changes.todos.sync_ids();
+        let requested_tasks = changes.tasks.sync_ids();
+
+        let mut report = changes.sync(self);
+        // The client reports the changes made to its own items.
+        report.set_client_changes(SyncReport::default());
+
+        let expired_revision = self.todos.expired_revision.max(self.tasks.expired_revision);
+        let since = token
+            .filter(|t| t.server_id == self.device_id && t.revision <= self.revision && t.revision >= expired_revision)
+            .map(|t| t.revision);
+
+        let mut list = TdList::new_server();
+        list.todos = self.todos.changes_since(since, &requested_todos);
+        list.tasks = self.tasks.changes_since(since, &requested_tasks);
+
+        Ok(DeltaResponse {
+            token: SyncToken { server_id: self.device_id, revision: self.revision },
+            full: since.is_none(),
+            list: serde_json::from_str(&list.to_json()?)?,
+            report,
+        })
+    }
+
+    // Returns the items and tombstones of self, a server, for detecting which of them a sync changes.
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        Snapshot { todos: self.todos.snapshot(), tasks: self.tasks.snapshot() }
+    }
+
+    // Sets a new revision for the items and tombstones of self, a server, that a sync changed since
+    // the snapshot, and gives the client the token of the revision it synced with.
+    pub(crate) fn stamp_revisions(&mut self, snapshot: Snapshot, client: &mut TdList) {
+        let revision = self.revision + 1;
+        let todos_changed = self.todos.stamp_revisions(snapshot.todos, revision);
+        let tasks_changed = self.tasks.stamp_revisions(snapshot.tasks, revision);
+        if todos_changed || tasks_changed {
+            self.revision = revision;
+        }
+
+        client.sync_token = Some(SyncToken { server_id: self.device_id, revision: self.revision });
+    }
+
+    /// Applies the response of a server to the `DeltaRequest` of self, a client. `list` is the list
+    /// of the response. Returns a `SyncReport` describing the changes made to both lists.
+    pub(crate) fn apply_delta(&mut self, response: DeltaResponse, mut list: TdList) -> SyncReport {
+        let client_report = if response.full {
+            self.sync(&mut list)
+        } else {
+            self.remove_old_todos();
+            SyncReport {
+                todos: self.todos.apply_delta(&list.todos),
+                tasks: self.tasks.apply_delta(&list.tasks),
+            }
+        };
+
+        self.sync_token = Some(response.token);
+
+        let mut report = response.report;
+        report.set_client_changes(client_report);
+        report
+    }
+}
+
+impl<T: SyncItem + Clone + PartialEq> SyncList<T> {
+    // Returns a list containing only the items of self, a client list, which haven't been synced.
+    fn unsynced(&self) -> Self {
+        let mut list = SyncList::new(false);
+        list.items = self.items.iter().filter(|i| i.state() != ItemState::Unchanged).cloned().collect();
+        list.last_synced = self.last_synced;
+        list
+    }
+
+    // Returns the items and the sync_ids of the tombstones of self, a server list, for detecting
+    // which of them a sync changes. Items modified on the server itself since it last synced are
+    // left out, so that they count as changed.
+    fn snapshot(&self) -> (HashMap<u64, T>, BTreeSet<u64>) {
+        (
+            self.items.iter().filter(|i| i.state() == ItemState::Unchanged).map(|i| (i.sync_id(), i.clone())).collect(),
+            self.tombstones.keys().copied().collect(),
+        )
+    }
+
+    // Sets the revision of the items and tombstones that differ from the snapshot. Returns true if
+    // any of them did.
+    fn stamp_revisions(&mut self, (old_items, old_tombstones): (HashMap<u64, T>, BTreeSet<u64>), revision: u64) -> bool {
+        let changed_items = self.items.iter()
+            .filter(|i| old_items.get(&i.sync_id()) != Some(*i))
+            .map(|i| i.sync_id());
+        let new_tombstones = self.tombstones.keys()
+            .filter(|sync_id| !old_tombstones.contains(sync_id))
+            .copied();
+
+        let changed: Vec<u64> = changed_items.chain(new_tombstones).collect();
+        for sync_id in &changed {
+            self.revisions.insert(*sync_id, revision);
+        }
+
+        !changed.is_empty()
+    }
+
+    // Returns the items and tombstones of self, a server list, changed after the given revision, or
+    // all the items if the revision is None. The requested items and tombstones are always included
+    // so that the client gets the results of merging its modifications.
+    fn changes_since(&self, since: Option<u64>, requested: &BTreeSet<u64>) -> Self {
+        let changed = |sync_id: &u64| {
+            requested.contains(sync_id) || since.is_none_or(|since| self.revisions.get(sync_id).copied().unwrap_or(0) > since)
+        };
+
+        let mut list = SyncList::new(true);
+        list.items = self.items.iter().filter(|i| changed(&i.sync_id())).cloned().collect();
+        list.tombstones = self.tombstones.iter()
+            .filter(|(sync_id, _)| requested.contains(sync_id) || (since.is_some() && changed(sync_id)))
+            .map(|(sync_id, removed)| (*sync_id, *removed))
+            .collect();
+        list
+    }
+
+    // Applies the items and tombstones changed on the server to self, a client list. Unlike sync,
+    // items missing from the other list are kept. The server has already merged the modifications
+    // of the client, so its items replace the ones of the client. Returns the changes made to the
+    // client.
+    fn apply_delta(&mut self, delta: &Self) -> ListReport {
+        let mut report = ListReport::default();
+
+        for d_item in delta.items.iter() {
+            match self.get_item_by_sync_id(d_item.sync_id()) {
+                Some(item) => {
+                    if item.state() != ItemState::Removed && item != d_item {
+                        d_item.update_old(item);
+                        report.updated_on_client.push(ReportItem::new(item.sync_id(), item.body()));
+                    }
+                }
+                None => {
+                    self.add(d_item.clone());
+                    report.added_to_client.push(ReportItem::new(d_item.sync_id(), d_item.body()));
+                }
+            }
+        }
+
+        for sync_id in delta.tombstones.keys() {
+            if let Some(item) = self.get_item_by_sync_id(*sync_id) {
+                if item.state() != ItemState::Removed {
+                    item.set_state(ItemState::Removed);
+                    report.removed_from_client.push(ReportItem::new(item.sync_id(), item.body()));
+                }
+            }
+        }
+
+        self.sync_self();
+        self.last_synced = Utc::now();
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{Limits, SyncReport, TdList, Todo};
+    use crate::delta::DeltaRequest;
+
+    // Syncs like MtdNetMgr does. Returns the number of items in the response, whether the response
+    // contained all the items and the report.
+    fn delta_sync(client: &mut TdList, server: &mut TdList) -> (usize, bool, SyncReport) {
+        client.remove_old_todos();
+        let request = DeltaRequest::new(client).unwrap();
+        let response = server.merge_delta(request.changes(&Limits::default()).unwrap(), request.token()).unwrap();
+
+        let list = response.list(&Limits::default()).unwrap();
+        let items = list.todos.items.len() + list.tasks.items.len();
+        let full = response.full;
+
+        (items, full, client.apply_delta(response, list))
+    }
+
+    #[test]
+    fn delta_sync_sends_only_changed_items() {
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo 1".to_string()));
+        laptop.add_todo(Todo::new_undated("Todo 2".to_string()));
+        assert!(delta_sync(&mut laptop, &mut server).1);
+        delta_sync(&mut phone, &mut server);
+
+        phone.add_todo(Todo::new_undated("Todo 3".to_string()));
+        delta_sync(&mut phone, &mut server);
+
+        let (items, full, report) = delta_sync(&mut laptop, &mut server);
+
+        assert_eq!(items, 1);
+        assert!(!full);
+        assert_eq!(report.todos().added_to_client()[0].body(), "Todo 3");
+        assert_eq!(laptop.todos().len(), 3);
+        assert_eq!(delta_sync(&mut laptop, &mut server).0, 0);
+    }
+
+    #[test]
+    fn delta_sync_reports_changes_of_both() {
+        let mut client = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        server.add_todo(Todo::new_undated("Server".to_string()));
+        client.add_todo(Todo::new_undated("Client".to_string()));
+
+        let (_, _, report) = delta_sync(&mut client, &mut server);
+
+        assert_eq!(report.todos().added_to_server().len(), 1);
+        assert_eq!(report.todos().added_to_server()[0].body(), "Client");
+        assert_eq!(report.todos().added_to_client().len(), 1);
+        assert_eq!(report.todos().added_to_client()[0].body(), "Server");
+    }
+
+    #[test]
+    fn delta_sync_removes_items_removed_on_other_clients() {
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo 1".to_string()));
+        laptop.add_todo(Todo::new_undated("Todo 2".to_string()));
+        delta_sync(&mut laptop, &mut server);
+        delta_sync(&mut phone, &mut server);
+
+        phone.remove_todo(0).unwrap();
+        delta_sync(&mut phone, &mut server);
+
+        let (items, full, report) = delta_sync(&mut laptop, &mut server);
+
+        assert_eq!(items, 0);
+        assert!(!full);
+        assert_eq!(report.todos().removed_from_client()[0].body(), "Todo 1");
+        assert_eq!(laptop.todos(), vec![&Todo::new_undated("Todo 2".to_string())]);
+    }
+
+    #[test]
+    fn delta_sync_client_without_items_does_not_remove_server_items() {
+        let mut client = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        client.add_todo(Todo::new_undated("Todo".to_string()));
+        delta_sync(&mut client, &mut server);
+
+        // For example a client whose list was lost.
+        let mut client = TdList::new_client();
+        delta_sync(&mut client, &mut server);
+
+        assert_eq!(server.todos().len(), 1);
+        assert_eq!(client.todos().len(), 1);
+    }
+
+    #[test]
+    fn delta_sync_sends_all_items_if_changes_since_token_are_unknown() {
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo 1".to_string()));
+        laptop.add_todo(Todo::new_undated("Todo 2".to_string()));
+        delta_sync(&mut laptop, &mut server);
+        delta_sync(&mut phone, &mut server);
+
+        phone.remove_todo(0).unwrap();
+        delta_sync(&mut phone, &mut server);
+        server.expire_tombstones(Duration::ZERO);
+
+        let (items, full, _) = delta_sync(&mut laptop, &mut server);
+
+        assert_eq!(items, 1);
+        assert!(full);
+        assert_eq!(laptop.todos().len(), 1);
+
+        // Tokens of other servers aren't used either.
+        let mut other_server = TdList::new_server();
+        assert!(delta_sync(&mut laptop, &mut other_server).1);
+    }
+}
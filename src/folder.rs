@@ -0,0 +1,225 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining synchronization through a shared folder, for example one synced with Syncthing
+//! or a network drive, without a server. Every device writes its own replica of the items into the
+//! folder and merges the replicas of the other devices into it. Replicas are server lists, so they
+//! keep tombstones of removed items like servers do, and a replica arriving late cannot bring back a
+//! removed item. Merging the replicas in any order gives the same items. Replicas are encrypted like
+//! saved lists if the `Config` enables data encryption.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{Config, Error, load_with_backup, Loaded, Result, save_atomic, SyncReport, TdList};
+
+/// The extension of replica files. Other files in the folder, such as backups, are ignored.
+const REPLICA_EXTENSION: &str = "json";
+
+/// Reads the replica of a device. Replicas are migrated from older schema versions like lists.
+fn parse_replica(json: &str) -> Result<TdList> {
+    let list = TdList::new_from_json(json)?;
+    if !list.server {
+        return Err(Error::ServerOnlyOperation);
+    }
+    Ok(list)
+}
+
+impl TdList {
+    /// Synchronizes the list with a folder shared between devices, without a server. The items of
+    /// the replicas other devices have written into the folder are merged like in `sync`, and the
+    /// result is written into the folder as the replica of this device. Replicas that cannot be read
+    /// yet, for example because they are still being copied, are merged on a later sync.
+    ///
+    /// Removing an item wins over modifying it on another device. Removed items are remembered for
+    /// the `tombstone_expiry` of the `Config`, see `expire_tombstones`. Returns a `SyncReport` in
+    /// which the server is the shared folder.
+    ///
+    /// If the `Config` enables data encryption, the replica is encrypted with the data encryption
+    /// password like the saved list. Only replicas encrypted with the same password can be read, so
+    /// all devices syncing through the folder need to use the same data encryption password.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::env;
+    /// use mtd::{Config, TdList, Todo};
+    ///
+    /// let folder = env::temp_dir().join("mtd-sync-folder-example");
+    /// # let _ = std::fs::remove_dir_all(&folder);
+    /// let conf = Config::new_default(Vec::new(), format!("folder:{}", folder.display()), None);
+    ///
+    /// let mut laptop = TdList::new_client();
+    /// let mut phone = TdList::new_client();
+    ///
+    /// laptop.add_todo(Todo::new_undated("Todo 1".to_string()));
+    /// laptop.sync_folder(&folder, &conf).unwrap();
+    /// phone.sync_folder(&folder, &conf).unwrap();
+    ///
+    /// assert_eq!(phone.todos(), vec![&Todo::new_undated("Todo 1".to_string())]);
+    /// ```
+    pub fn sync_folder(&mut self, folder: &Path, config: &Config) -> Result<SyncReport> {
+        if self.server {
+            return Err(Error::ClientOnlyOperation);
+        }
+
+        fs::create_dir_all(folder)?;
+        let own_path = self.replica_path(folder);
+
+        let parse = |data: &[u8]| parse_replica(&config.decode_data(data)?);
+
+        let mut replica = load_with_backup(&own_path, parse)?
+            .map(Loaded::into_value)
+            .unwrap_or_else(TdList::new_server);
+
+        for path in replica_paths(folder, &own_path)? {
+            // Replicas that are incomplete or written by a newer version are merged once they can be
+            // read.
+            if let Ok(other) = fs::read(&path).map_err(Error::from).and_then(|data| parse(&data)) {
+                replica.todos.merge_replica(&other.todos);
+                replica.tasks.merge_replica(&other.tasks);
+            }
+        }
+
+        let report = self.sync(&mut replica);
+        replica.expire_tombstones(config.tombstone_expiry());
+
+        save_atomic(&own_path, &config.encode_data(&replica.to_json()?)?)?;
+
+        Ok(report)
+    }
+
+    /// Encrypts the replica this device has written into a shared folder before data encryption was
+    /// enabled. See `Config::encrypt_saved_data`.
+    pub fn encrypt_folder_replica(&self, folder: &Path, config: &Config) -> Result<()> {
+        config.encrypt_saved_data(&self.replica_path(folder))
+    }
+
+    fn replica_path(&self, folder: &Path) -> PathBuf {
+        folder.join(format!("{:016x}.{}", self.device_id, REPLICA_EXTENSION))
+    }
+}
+
+/// Gets the paths of the replicas of other devices in a folder in a stable order.
+fn replica_paths(folder: &Path, own_path: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(folder)? {
+        let path = entry?.path();
+        if path != own_path && path.is_file() && path.extension().is_some_and(|e| e == REPLICA_EXTENSION) {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use std::path::PathBuf;
+
+    use crate::{Config, SecretSource, TdList, Todo};
+
+    fn config() -> Config {
+        Config::new_default(Vec::new(), String::new(), None)
+    }
+
+    fn test_folder(name: &str) -> PathBuf {
+        let folder = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&folder);
+        folder
+    }
+
+    #[test]
+    fn sync_folder_merges_items_of_all_devices() {
+        let folder = test_folder("mtd-folder-test-merge");
+        let mut device1 = TdList::new_client();
+        let mut device2 = TdList::new_client();
+
+        device1.add_todo(Todo::new_undated("Todo 1".to_string()));
+        device2.add_todo(Todo::new_undated("Todo 2".to_string()));
+
+        device1.sync_folder(&folder, &config()).unwrap();
+        device2.sync_folder(&folder, &config()).unwrap();
+        device1.sync_folder(&folder, &config()).unwrap();
+
+        assert_eq!(device1.todos().len(), 2);
+        assert_eq!(device2.todos().len(), 2);
+    }
+
+    #[test]
+    fn sync_folder_removal_is_not_undone_by_late_replica() {
+        let folder = test_folder("mtd-folder-test-removal");
+        let mut device1 = TdList::new_client();
+        let mut device2 = TdList::new_client();
+
+        device1.add_todo(Todo::new_undated("Todo 1".to_string()));
+        device1.sync_folder(&folder, &config()).unwrap();
+        device2.sync_folder(&folder, &config()).unwrap();
+
+        // Device 2 removes the todo while device 1 modifies it and its replica arrives later.
+        let late_folder = test_folder("mtd-folder-test-removal-late");
+        device1.get_todo_mut(0).unwrap().set_body("Modified".to_string());
+        device1.sync_folder(&late_folder, &config()).unwrap();
+
+        device2.remove_todo(0).unwrap();
+        device2.sync_folder(&folder, &config()).unwrap();
+
+        for entry in fs::read_dir(&late_folder).unwrap() {
+            let path = entry.unwrap().path();
+            fs::copy(&path, folder.join(path.file_name().unwrap())).unwrap();
+        }
+
+        device1.sync_folder(&folder, &config()).unwrap();
+        device2.sync_folder(&folder, &config()).unwrap();
+
+        assert!(device1.todos().is_empty());
+        assert!(device2.todos().is_empty());
+    }
+
+    #[test]
+    fn sync_folder_ignores_unreadable_replicas() {
+        let folder = test_folder("mtd-folder-test-unreadable");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("0123456789abcdef.json"), "{\"todos\":").unwrap();
+
+        let mut device = TdList::new_client();
+        device.add_todo(Todo::new_undated("Todo 1".to_string()));
+
+        device.sync_folder(&folder, &config()).unwrap();
+        assert_eq!(device.todos().len(), 1);
+    }
+
+    #[test]
+    fn sync_folder_encrypts_replicas_with_data_encryption() {
+        let folder = test_folder("mtd-folder-test-encrypted");
+        let mut conf = config();
+        conf.set_data_encryption(Some(SecretSource::Prompt));
+        conf.set_data_password(b"data password".to_vec());
+
+        let mut device1 = TdList::new_client();
+        let mut device2 = TdList::new_client();
+        device1.add_todo(Todo::new_undated("Secret todo".to_string()));
+
+        device1.sync_folder(&folder, &conf).unwrap();
+        device2.sync_folder(&folder, &conf).unwrap();
+
+        assert_eq!(device2.todos(), vec![&Todo::new_undated("Secret todo".to_string())]);
+        for entry in fs::read_dir(&folder).unwrap() {
+            assert!(!String::from_utf8_lossy(&fs::read(entry.unwrap().path()).unwrap()).contains("Secret todo"));
+        }
+    }
+}
@@ -0,0 +1,236 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining the completion history of `Task`s. Every occurrence a `Task` has been done or
+//! skipped on is kept in the history with an optional note. The entries of the history are merged
+//! one by one when synchronizing, so completions made on different devices are all kept.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Local, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{epoch, ItemState, Resolution, Task};
+
+/// The completion history of a `Task` by the dates of the occurrences.
+pub(crate) type History = BTreeMap<NaiveDate, Completion>;
+
+/// What was done on an occurrence of a `Task`.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub enum CompletionStatus {
+    /// The occurrence was done.
+    Done,
+    /// The occurrence was intentionally skipped. Skipped occurrences don't count as missed.
+    Skipped,
+    /// The occurrence was marked as done or skipped earlier, but that was undone.
+    Undone,
+}
+
+/// The completion of a single occurrence of a `Task`.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Completion {
+    status: CompletionStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+    // Used for choosing between the completions of different devices when synchronizing.
+    #[serde(default = "epoch")]
+    modified: DateTime<Utc>,
+}
+
+impl Completion {
+    /// Gets the `CompletionStatus` of the occurrence.
+    pub fn status(&self) -> CompletionStatus {
+        self.status
+    }
+
+    /// Gets the note of the occurrence, if one was given.
+    pub fn note(&self) -> Option<&str> {
+        self.note.as_deref()
+    }
+}
+
+impl Task {
+    /// Sets the completion of the `Task` for the given date with an optional note. The completion
+    /// replaces any earlier completion of the same date.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chrono::{NaiveDate, Weekday};
+    /// use mtd::{CompletionStatus, Task};
+    ///
+    /// let mut task = Task::new("Run".to_string(), vec![Weekday::Mon]);
+    /// let date = NaiveDate::from_ymd(2022, 6, 13);
+    ///
+    /// task.set_completion(date, CompletionStatus::Skipped, Some("Sprained ankle".to_string()));
+    ///
+    /// assert_eq!(task.completion(date).unwrap().status(), CompletionStatus::Skipped);
+    /// assert_eq!(task.completion(date).unwrap().note(), Some("Sprained ankle"));
+    /// assert!(!task.done(date));
+    /// ```
+    pub fn set_completion(&mut self, date: NaiveDate, status: CompletionStatus, note: Option<String>) {
+        self.history.insert(date, Completion { status, note, modified: Utc::now() });
+        self.state = ItemState::Changed;
+    }
+
+    /// Gets the completion of the `Task` for the given date. Returns `None` if the `Task` has never
+    /// been marked done or skipped for the date.
+    pub fn completion(&self, date: NaiveDate) -> Option<&Completion> {
+        self.history.get(&date)
+    }
+
+    /// Returns `true` if the `Task` was skipped for the given date.
+    pub fn skipped(&self, date: NaiveDate) -> bool {
+        self.completion(date).is_some_and(|c| c.status == CompletionStatus::Skipped)
+    }
+
+    /// Gets the completion history of the `Task` ordered by date, including occurrences that were
+    /// later marked undone.
+    pub fn history(&self) -> impl Iterator<Item=(NaiveDate, &Completion)> {
+        self.history.iter().map(|(date, completion)| (*date, completion))
+    }
+
+    /// Returns the share of the occurrences of the `Task` during the last `weeks` weeks, today
+    /// included, that were done. See `completion_rate_between`.
+    pub fn completion_rate(&self, weeks: u32) -> Option<f64> {
+        let today = Local::today().naive_local();
+        self.completion_rate_between(today - chrono::Duration::weeks(weeks.into()) + chrono::Duration::days(1), today)
+    }
+
+    /// Returns the share of the occurrences of the `Task` between the given dates, both included,
+    /// that were done. Skipped occurrences are not counted. Returns `None` if there are no
+    /// occurrences to count.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chrono::{NaiveDate, Weekday};
+    /// use mtd::{CompletionStatus, Task};
+    ///
+    /// let mut task = Task::new("Clean".to_string(), vec![Weekday::Mon]);
+    ///
+    /// task.set_done(true, NaiveDate::from_ymd(2022, 6, 6));
+    /// task.set_completion(NaiveDate::from_ymd(2022, 6, 13), CompletionStatus::Skipped, None);
+    ///
+    /// // Done on 6th, skipped on 13th and not done on 20th.
+    /// let rate = task.completion_rate_between(NaiveDate::from_ymd(2022, 6, 1), NaiveDate::from_ymd(2022, 6, 26));
+    /// assert_eq!(rate, Some(0.5));
+    /// ```
+    pub fn completion_rate_between(&self, from: NaiveDate, to: NaiveDate) -> Option<f64> {
+        let mut occurrences = 0;
+        let mut done = 0;
+
+        for date in from.iter_days().take_while(|date| *date <= to).filter(|date| self.for_date(*date)) {
+            match self.completion(date).map(Completion::status) {
+                Some(CompletionStatus::Done) => { done += 1; }
+                Some(CompletionStatus::Skipped) => { continue; }
+                Some(CompletionStatus::Undone) | None => {}
+            }
+            occurrences += 1;
+        }
+
+        if occurrences == 0 {
+            None
+        } else {
+            Some(done as f64 / occurrences as f64)
+        }
+    }
+}
+
+// Merges the completions of other into history, keeping the later modified completion of each date.
+// On ties the current completion is kept. Returns how a conflict was resolved if the completion of
+// some date was modified differently on both after since.
+pub(crate) fn merge_history(history: &mut History, other: &History, since: DateTime<Utc>) -> Option<Resolution> {
+    let mut resolution = None;
+
+    for (date, theirs) in other {
+        let ours = match history.get_mut(date) {
+            Some(ours) => { ours }
+            None => {
+                history.insert(*date, theirs.clone());
+                continue;
+            }
+        };
+
+        let conflict = (ours.status, &ours.note) != (theirs.status, &theirs.note) && ours.modified > since && theirs.modified > since;
+        let take_other = theirs.modified > ours.modified;
+
+        if take_other {
+            *ours = theirs.clone();
+        }
+
+        if conflict && (take_other || resolution.is_none()) {
+            resolution = Some(if take_other { Resolution::KeptClient } else { Resolution::KeptServer });
+        }
+    }
+
+    resolution
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, Weekday};
+
+    use crate::{CompletionStatus, Task, TdList};
+
+    #[test]
+    fn task_history_keeps_every_completion() {
+        let mut task = Task::new("Task".to_string(), vec![Weekday::Mon]);
+
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 6));
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 13));
+        task.set_done(false, NaiveDate::from_ymd(2022, 6, 13));
+
+        assert!(task.done(NaiveDate::from_ymd(2022, 6, 6)));
+        assert!(!task.done(NaiveDate::from_ymd(2022, 6, 13)));
+        assert_eq!(task.history().map(|(date, c)| (date, c.status())).collect::<Vec<_>>(), vec![
+            (NaiveDate::from_ymd(2022, 6, 6), CompletionStatus::Done),
+            (NaiveDate::from_ymd(2022, 6, 13), CompletionStatus::Undone),
+        ]);
+    }
+
+    #[test]
+    fn task_completion_rate_without_occurrences_is_none() {
+        let task = Task::new("Task".to_string(), vec![Weekday::Mon]);
+
+        assert_eq!(task.completion_rate_between(NaiveDate::from_ymd(2022, 6, 7), NaiveDate::from_ymd(2022, 6, 12)), None);
+    }
+
+    #[test]
+    fn sync_merges_completions_of_all_devices() {
+        let mut server = TdList::new_server();
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+
+        laptop.add_task(Task::new("Task".to_string(), vec![Weekday::Mon]));
+        laptop.sync(&mut server);
+        phone.sync(&mut server);
+
+        laptop.get_task_mut(0).unwrap().set_done(true, NaiveDate::from_ymd(2022, 6, 6));
+        phone.get_task_mut(0).unwrap().set_done(true, NaiveDate::from_ymd(2022, 6, 13));
+
+        let report = laptop.sync(&mut server);
+        assert!(report.tasks().conflicts().is_empty());
+        let report = phone.sync(&mut server);
+        assert!(report.tasks().conflicts().is_empty());
+        laptop.sync(&mut server);
+
+        for list in [&laptop, &phone, &server] {
+            assert!(list.tasks()[0].done(NaiveDate::from_ymd(2022, 6, 6)));
+            assert!(list.tasks()[0].done(NaiveDate::from_ymd(2022, 6, 13)));
+        }
+    }
+}
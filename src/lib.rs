@@ -57,17 +57,44 @@ see <https://www.gnu.org/licenses/>.
 #![warn(missing_docs)]
 
 use std::{io, result};
-use std::borrow::BorrowMut;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt::{Debug, Display, Formatter};
+use std::path::PathBuf;
+use std::time::Duration;
 
-use chrono::{Datelike, Local, NaiveDate, Weekday};
+use chrono::{Datelike, DateTime, Local, NaiveDate, TimeZone, Utc, Weekday};
 use rand::random;
 use serde::{Deserialize, Serialize};
 
-pub use network::{Config, MtdNetMgr};
-
+pub use history::{Completion, CompletionStatus};
+pub use network::{Config, Limits, MtdNetMgr, ShutdownHandle};
+pub use recurrence::{Frequency, Recurrence};
+pub use report::{Conflict, ListReport, ReportItem, Resolution, SyncReport};
+pub use schema::{CONFIG_SCHEMA_VERSION, TD_LIST_SCHEMA_VERSION};
+pub use secret::{DerivedKey, SecretSource};
+pub use stats::{Occurrences, Period, PeriodStats, Stats, TaskStats, WeekdayStats};
+pub use storage::{backup_path, FileLock, load_with_backup, Loaded, save_atomic};
+pub use transport::{CommandStream, CommandTransport, Listener, MemoryListener, MemoryStream, MemoryTransport, NoListener, StdioStream, Stream, TcpTransport, Transport};
+#[cfg(unix)]
+pub use transport::{UnixSocketListener, UnixTransport};
+
+use delta::SyncToken;
+use history::History;
+use sealed::SealedList;
+
+mod delta;
+mod folder;
+mod history;
 mod network;
+mod recurrence;
+mod report;
+mod schema;
+mod sealed;
+mod secret;
+mod stats;
+mod storage;
+mod transport;
 // Methods ending with _wtd are used for unit testing and internal implementations. They allow
 // supplying today with any date.
 
@@ -90,14 +117,49 @@ pub enum Error {
     IOErr(io::Error),
     /// Serialization failed. Wrapper for `serde_json::Error`s.
     SerdeErr(serde_json::Error),
+    /// A secret such as a password couldn't be read from its source. Contains the reason.
+    SecretUnavailable(String),
+    /// Another process is using the file at the given path and didn't release it in time.
+    FileLocked(PathBuf),
     /// Authentication of the client/server failed.
     AuthFailed,
+    /// The client and the server use different passwords.
+    WrongPassword,
+    /// The client's `DerivedKey` was derived with a different salt than the server uses.
+    KeySaltMismatch,
+    /// A message received from the remote was modified, replayed or reordered, or the remote isn't
+    /// who it claims to be.
+    TamperedMessage,
     /// Trying to do a server only operation as a client.
     ServerOnlyOperation,
     /// Trying to do a client only operation as a server.
     ClientOnlyOperation,
     /// Operation not supported for local-only instances.
     OnlineOnlyOperation,
+    /// The remote end uses a wire protocol version that isn't supported. Contains the remote's
+    /// protocol version, `0` if the remote is an old version that doesn't send one.
+    UnsupportedProtocolVersion(u16),
+    /// Data was written by a newer version of mtd, or a server cannot read the data of a newer client.
+    /// Contains the schema version of the data and the newest supported schema version.
+    UnsupportedSchemaVersion(u32, u32),
+    /// A message received over the network is larger than the configured limit. Contains the size
+    /// of the message and the limit.
+    FrameTooLarge(u32, u32),
+    /// A list received over the network contains more items than the configured limit. Contains the
+    /// number of items and the limit.
+    TooManyItems(usize, usize),
+    /// An item received over the network has a longer body than the configured limit. Contains the
+    /// length of the body and the limit.
+    BodyTooLong(usize, usize),
+    /// An end-to-end encrypted item received over the network is larger than the configured limit.
+    /// Contains the size of the item and the limit.
+    RecordTooLarge(usize, usize),
+    /// Another client replaced the end-to-end encrypted items on the server during the
+    /// synchronization. Synchronizing again merges the changes of both clients.
+    ConcurrentSync,
+    /// Data encryption is enabled but the saved data isn't encrypted. Data saved before the
+    /// encryption was enabled needs to be encrypted with `Config::encrypt_saved_data`.
+    UnencryptedData,
     /// Unspecified error for rare edge cases that most of the time are handled internally.
     Unknown,
 }
@@ -123,9 +185,24 @@ impl Display for Error {
             Error::SerdeErr(e) => {
                 write!(f, "Parsing data failed: {}", e)
             }
+            Error::SecretUnavailable(reason) => {
+                write!(f, "Cannot read secret: {}", reason)
+            }
+            Error::FileLocked(path) => {
+                write!(f, "The file \"{}\" is being used by another mtd process.", path.display())
+            }
             Error::AuthFailed => {
                 write!(f, "Remote authentication failed.")
             }
+            Error::WrongPassword => {
+                write!(f, "The client and the server use different encryption passwords.")
+            }
+            Error::KeySaltMismatch => {
+                write!(f, "The server uses a different key salt than the derived key of the client.")
+            }
+            Error::TamperedMessage => {
+                write!(f, "A message from the remote was tampered with or replayed.")
+            }
             Error::Unknown => {
                 write!(f, "Unknown error.")
             }
@@ -138,6 +215,30 @@ impl Display for Error {
             Error::OnlineOnlyOperation => {
                 write!(f, "Operation not permitted for local-only instances.")
             }
+            Error::FrameTooLarge(size, limit) => {
+                write!(f, "Received a message of {} bytes which exceeds the limit of {} bytes.", size, limit)
+            }
+            Error::TooManyItems(items, limit) => {
+                write!(f, "Received {} items which exceeds the limit of {} items.", items, limit)
+            }
+            Error::BodyTooLong(len, limit) => {
+                write!(f, "Received an item with a body of {} bytes which exceeds the limit of {} bytes.", len, limit)
+            }
+            Error::RecordTooLarge(size, limit) => {
+                write!(f, "Received an encrypted item of {} bytes which exceeds the limit of {} bytes.", size, limit)
+            }
+            Error::ConcurrentSync => {
+                write!(f, "Another client synchronized at the same time. Try synchronizing again.")
+            }
+            Error::UnencryptedData => {
+                write!(f, "The saved data is not encrypted although data encryption is enabled. Encrypt the existing data with 'mtd encrypt-data'.")
+            }
+            Error::UnsupportedSchemaVersion(v, supported) => {
+                write!(f, "Unsupported schema version {}, the newest supported version is {}. Update the older mtd installation.", v, supported)
+            }
+            Error::UnsupportedProtocolVersion(v) => {
+                write!(f, "Unsupported protocol version: \"{}\". Both ends should run the same version of mtd.", v)
+            }
         }
     }
 }
@@ -171,9 +272,9 @@ fn weekday_to_date_wtd(weekday: Weekday, mut today: NaiveDate) -> NaiveDate {
     }
 }
 
-/// Represents a one-time task to be done at a specific date. The date is specified as a weekday
-/// from now. If no weekday is given, the current weekday will be used. After the given weekday, the
-/// `Todo` will show up for the current day.
+/// Represents a one-time task to be done at a specific date. The date is specified either as a weekday
+/// from now or as an explicit calendar date. If no date is given, the current date will be used.
+/// After the given date, the `Todo` will show up for the current day.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Todo {
     body: String,
@@ -182,6 +283,8 @@ pub struct Todo {
     done: Option<NaiveDate>,
     sync_id: u64,
     state: ItemState,
+    #[serde(default)]
+    modified: TodoModified,
 }
 
 impl Todo {
@@ -194,6 +297,7 @@ impl Todo {
             done: None,
             sync_id: random(),
             state: ItemState::Unchanged,
+            modified: TodoModified::default(),
         }
     }
 
@@ -206,12 +310,24 @@ impl Todo {
             done: None,
             sync_id: random(),
             state: ItemState::Unchanged,
+            modified: TodoModified::default(),
         }
     }
 
-    // Used for unit testing with non-today dependant date
-    #[cfg(test)]
-    fn new_specific_date(body: String, date: NaiveDate) -> Todo {
+    /// Creates a new `Todo` that shows up to be done at a specific date. Unlike weekdays, dates can
+    /// be arbitrarily far in the future.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chrono::NaiveDate;
+    /// use mtd::Todo;
+    ///
+    /// let todo = Todo::new_for_date("Renew passport".to_string(), NaiveDate::from_ymd(2031, 3, 14));
+    ///
+    /// assert_eq!(todo.date(), NaiveDate::from_ymd(2031, 3, 14));
+    /// ```
+    pub fn new_for_date(body: String, date: NaiveDate) -> Todo {
         Todo {
             body,
             date,
@@ -219,6 +335,7 @@ impl Todo {
             done: None,
             sync_id: random(),
             state: ItemState::Unchanged,
+            modified: TodoModified::default(),
         }
     }
 
@@ -247,7 +364,7 @@ impl Todo {
         if self.date < date {
             date == today
         } else {
-            date.weekday() == self.date.weekday()
+            date == self.date
         }
     }
 
@@ -261,6 +378,11 @@ impl Todo {
         self.date.weekday()
     }
 
+    /// Gets the date the `Todo` is scheduled for.
+    pub fn date(&self) -> NaiveDate {
+        self.date
+    }
+
     /// Gets the `id` of the `Todo`.
     pub fn id(&self) -> u64 {
         self.id
@@ -269,12 +391,19 @@ impl Todo {
     /// Sets the `body` of the `Todo`.
     pub fn set_body(&mut self, body: String) {
         self.body = body;
+        self.modified.body = Utc::now();
         self.state = ItemState::Changed;
     }
 
     /// Sets the weekday of the `Todo`.
     pub fn set_weekday(&mut self, weekday: Weekday) {
-        self.date = weekday_to_date_wtd(weekday, Local::today().naive_local());
+        self.set_date(weekday_to_date_wtd(weekday, Local::today().naive_local()));
+    }
+
+    /// Sets the date of the `Todo`.
+    pub fn set_date(&mut self, date: NaiveDate) {
+        self.date = date;
+        self.modified.date = Utc::now();
         self.state = ItemState::Changed;
     }
 
@@ -294,13 +423,10 @@ impl Todo {
         } else {
             self.done = None;
         }
+        self.modified.done = Utc::now();
         self.state = ItemState::Changed;
     }
 
-    fn set_id(&mut self, id: u64) {
-        self.id = id;
-    }
-
     /// Returns `true` if the `Todo` can be removed. A `Todo` can be removed one day after its
     /// completion.
     pub fn can_remove(&self) -> bool {
@@ -330,15 +456,21 @@ impl PartialEq for Todo {
     }
 }
 
-/// Represents a reoccurring task for the given weekday(s).
+/// Represents a reoccurring task for the given weekday(s). By default a `Task` repeats every week,
+/// but a `Recurrence` can be used for other rules such as every other week or monthly.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Task {
     body: String,
     weekdays: Vec<Weekday>,
-    done_map: HashMap<Weekday, NaiveDate>,
+    #[serde(default)]
+    recurrence: Recurrence,
+    #[serde(default)]
+    history: History,
     id: u64,
     state: ItemState,
     sync_id: u64,
+    #[serde(default)]
+    modified: TaskModified,
 }
 
 impl Task {
@@ -351,7 +483,21 @@ impl Task {
         if weekdays.is_empty() {
             panic!("Cannot create a task without specifying at least one weekday.")
         }
-        Task { body, weekdays, id: 0, done_map: HashMap::new(), sync_id: random(), state: ItemState::Unchanged }
+        Task { body, weekdays, recurrence: Recurrence::default(), id: 0, history: History::new(), sync_id: random(), state: ItemState::Unchanged, modified: TaskModified::default() }
+    }
+
+    /// Creates a new task following the given `Recurrence`. The weekdays are used with daily and
+    /// weekly recurrences to choose the weekdays the task is for. With daily recurrences an empty
+    /// weekdays list means any weekday.
+    ///
+    /// # Panics
+    ///
+    /// If the given weekdays list is empty and the recurrence is weekly.
+    pub fn new_recurring(body: String, weekdays: Vec<Weekday>, recurrence: Recurrence) -> Task {
+        if weekdays.is_empty() && recurrence.frequency() == Frequency::Weekly {
+            panic!("Cannot create a weekly task without specifying at least one weekday.")
+        }
+        Task { body, weekdays, recurrence, id: 0, history: History::new(), sync_id: random(), state: ItemState::Unchanged, modified: TaskModified::default() }
     }
 
     /// Gets the `body` of the `Task`.
@@ -372,16 +518,26 @@ impl Task {
     /// Sets the `body` of the `Task`.
     pub fn set_body(&mut self, body: String) {
         self.body = body;
+        self.modified.body = Utc::now();
         self.state = ItemState::Changed;
     }
 
-    fn set_id(&mut self, id: u64) {
-        self.id = id;
-    }
-
     /// Sets the `weekdays` of the `Task`.
     pub fn set_weekdays(&mut self, weekdays: Vec<Weekday>) {
         self.weekdays = weekdays;
+        self.modified.weekdays = Utc::now();
+        self.state = ItemState::Changed;
+    }
+
+    /// Gets the `Recurrence` of the `Task`.
+    pub fn recurrence(&self) -> &Recurrence {
+        &self.recurrence
+    }
+
+    /// Sets the `Recurrence` of the `Task`.
+    pub fn set_recurrence(&mut self, recurrence: Recurrence) {
+        self.recurrence = recurrence;
+        self.modified.recurrence = Utc::now();
         self.state = ItemState::Changed;
     }
 
@@ -389,6 +545,7 @@ impl Task {
     pub fn add_weekday(&mut self, weekday: Weekday) {
         // It doesn't matter if there are duplicate weekdays.
         self.weekdays.push(weekday);
+        self.modified.weekdays = Utc::now();
         self.state = ItemState::Changed;
     }
 
@@ -416,7 +573,7 @@ impl Task {
 
         for wd in &self.weekdays {
             if wd != &removed_wd {
-                new_weekdays.push(wd.clone());
+                new_weekdays.push(*wd);
             }
         }
 
@@ -438,7 +595,25 @@ impl Task {
     /// assert!(task.for_date(NaiveDate::from_ymd(2022, 6, 12))); // Sunday
     /// ```
     pub fn for_date(&self, date: NaiveDate) -> bool {
-        self.weekdays.contains(&date.weekday())
+        self.recurrence.occurs_on(date, &self.weekdays)
+    }
+
+    /// Returns the first date on or after the given date that the `Task` is for. Returns `None` if
+    /// the `Task` doesn't occur within the following 48 intervals, which happens for example with a
+    /// weekly `Task` without any weekdays.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chrono::{NaiveDate, Weekday};
+    /// use mtd::{Recurrence, Task};
+    ///
+    /// let task = Task::new_recurring("Pay rent".to_string(), vec![], Recurrence::monthly_on_days(1, NaiveDate::from_ymd(2022, 1, 1), vec![15]));
+    ///
+    /// assert_eq!(task.next_date(NaiveDate::from_ymd(2022, 6, 16)), Some(NaiveDate::from_ymd(2022, 7, 15)));
+    /// ```
+    pub fn next_date(&self, from: NaiveDate) -> Option<NaiveDate> {
+        self.recurrence.next_occurrence(from, &self.weekdays)
     }
 
     /// Returns `true` if the `Task` is done for the given date. Always returns `true` if the task
@@ -471,16 +646,13 @@ impl Task {
     /// ```
     pub fn done(&self, date: NaiveDate) -> bool {
         if self.for_date(date) {
-            if let Some(d) = self.done_map.get(&date.weekday()) {
-                return *d >= date;
-            }
-            return false;
+            return self.completion(date).is_some_and(|c| c.status() == CompletionStatus::Done);
         }
         true
     }
 
-
-    /// Sets the done state of the `Task` for the given date.
+    /// Sets the done state of the `Task` for the given date. The note of the date is kept. See
+    /// `set_completion` for skipping the date instead.
     ///
     /// # Example
     ///
@@ -498,11 +670,9 @@ impl Task {
     /// assert!(!task.done(NaiveDate::from_ymd(2022, 6, 13)));
     /// ```
     pub fn set_done(&mut self, done: bool, date: NaiveDate) {
-        if done {
-            self.done_map.insert(date.weekday(), date);
-        } else {
-            self.done_map.remove(&date.weekday());
-        }
+        let status = if done { CompletionStatus::Done } else { CompletionStatus::Undone };
+        let note = self.completion(date).and_then(|c| c.note()).map(str::to_string);
+        self.set_completion(date, status, note);
     }
 }
 
@@ -516,10 +686,96 @@ impl PartialEq for Task {
     fn eq(&self, other: &Self) -> bool {
         self.body == other.body &&
             self.weekdays == other.weekdays &&
-            self.done_map == other.done_map
+            self.recurrence == other.recurrence &&
+            self.history == other.history
     }
 }
 
+// Modification times of the fields of a Todo. These are used for resolving sync conflicts field by
+// field so that edits to different fields on different devices are all kept.
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+struct TodoModified {
+    body: DateTime<Utc>,
+    date: DateTime<Utc>,
+    done: DateTime<Utc>,
+}
+
+impl Default for TodoModified {
+    // Fields that were never modified (or were saved before modification times existed) lose to
+    // any modification.
+    fn default() -> Self {
+        Self { body: epoch(), date: epoch(), done: epoch() }
+    }
+}
+
+// Modification times of the fields of a Task. See TodoModified. The completions in the history have
+// modification times of their own.
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+struct TaskModified {
+    body: DateTime<Utc>,
+    weekdays: DateTime<Utc>,
+    recurrence: DateTime<Utc>,
+}
+
+impl Default for TaskModified {
+    fn default() -> Self {
+        Self { body: epoch(), weekdays: epoch(), recurrence: epoch() }
+    }
+}
+
+// Replaces a field with the other value if the other value was modified later. On ties the current
+// value is kept so that merging is deterministic. Returns how a conflict was resolved if both values
+// were modified after the given time.
+fn merge_field<T: Clone + PartialEq>(
+    field: &mut T,
+    modified: &mut DateTime<Utc>,
+    other_field: &T,
+    other_modified: DateTime<Utc>,
+    since: DateTime<Utc>,
+) -> Option<Resolution> {
+    let conflict = field != other_field && *modified > since && other_modified > since;
+    let take_other = other_modified > *modified;
+
+    if take_other {
+        *field = other_field.clone();
+        *modified = other_modified;
+    }
+
+    if !conflict {
+        None
+    } else if take_other {
+        Some(Resolution::KeptClient)
+    } else {
+        Some(Resolution::KeptServer)
+    }
+}
+
+fn epoch() -> DateTime<Utc> {
+    Utc.timestamp(0, 0)
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+// The sync_ids of removed items and the times they were removed.
+type Tombstones = BTreeMap<u64, DateTime<Utc>>;
+
+// Adds the tombstones of other to tombstones, keeping the latest removal time of each item.
+fn merge_tombstones(tombstones: &mut Tombstones, other: &Tombstones) {
+    for (sync_id, removed) in other {
+        tombstones.entry(*sync_id)
+            .and_modify(|time| *time = (*time).max(*removed))
+            .or_insert(*removed);
+    }
+}
+
+// Returns the earliest removal time of tombstones that are kept when tombstones expire after
+// max_age, or None if no tombstones expire.
+fn tombstone_expiry_time(max_age: Duration) -> Option<DateTime<Utc>> {
+    chrono::Duration::from_std(max_age).ok().and_then(|age| Utc::now().checked_sub_signed(age))
+}
+
 #[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
 enum ItemState {
     New,
@@ -531,9 +787,14 @@ enum ItemState {
 trait SyncItem {
     fn set_state(&mut self, state: ItemState);
     fn state(&self) -> ItemState;
+    fn id(&self) -> u64;
     fn set_id(&mut self, id: u64);
     fn sync_id(&self) -> u64;
+    fn body(&self) -> &str;
     fn update_old(&self, old: &mut Self);
+    // Merges the fields of a client item into self, a server item, keeping the most recently modified
+    // value of each field. Returns the conflicting fields that were modified on both after since.
+    fn merge(&mut self, other: &Self, since: DateTime<Utc>) -> Vec<(&'static str, Resolution)>;
 }
 
 impl SyncItem for Todo {
@@ -545,6 +806,10 @@ impl SyncItem for Todo {
         self.state
     }
 
+    fn id(&self) -> u64 {
+        self.id
+    }
+
     fn set_id(&mut self, id: u64) {
         self.id = id;
     }
@@ -552,10 +817,23 @@ impl SyncItem for Todo {
         self.sync_id
     }
 
+    fn body(&self) -> &str {
+        &self.body
+    }
+
     fn update_old(&self, old: &mut Self) {
         old.body = self.body.clone();
-        old.date = self.date.clone();
-        old.done = self.done.clone();
+        old.date = self.date;
+        old.done = self.done;
+        old.modified = self.modified;
+    }
+
+    fn merge(&mut self, other: &Self, since: DateTime<Utc>) -> Vec<(&'static str, Resolution)> {
+        [
+            ("body", merge_field(&mut self.body, &mut self.modified.body, &other.body, other.modified.body, since)),
+            ("date", merge_field(&mut self.date, &mut self.modified.date, &other.date, other.modified.date, since)),
+            ("done", merge_field(&mut self.done, &mut self.modified.done, &other.done, other.modified.done, since)),
+        ].into_iter().filter_map(|(field, res)| res.map(|r| (field, r))).collect()
     }
 }
 
@@ -568,6 +846,10 @@ impl SyncItem for Task {
         self.state
     }
 
+    fn id(&self) -> u64 {
+        self.id
+    }
+
     fn set_id(&mut self, id: u64) {
         self.id = id;
     }
@@ -575,17 +857,48 @@ impl SyncItem for Task {
         self.sync_id
     }
 
+    fn body(&self) -> &str {
+        &self.body
+    }
+
     fn update_old(&self, old: &mut Self) {
         old.body = self.body.clone();
         old.weekdays = self.weekdays.clone();
-        old.done_map = self.done_map.clone();
+        old.recurrence = self.recurrence.clone();
+        old.history = self.history.clone();
+        old.modified = self.modified;
+    }
+
+    fn merge(&mut self, other: &Self, since: DateTime<Utc>) -> Vec<(&'static str, Resolution)> {
+        [
+            ("body", merge_field(&mut self.body, &mut self.modified.body, &other.body, other.modified.body, since)),
+            ("weekdays", merge_field(&mut self.weekdays, &mut self.modified.weekdays, &other.weekdays, other.modified.weekdays, since)),
+            ("recurrence", merge_field(&mut self.recurrence, &mut self.modified.recurrence, &other.recurrence, other.modified.recurrence, since)),
+            ("history", history::merge_history(&mut self.history, &other.history, since)),
+        ].into_iter().filter_map(|(field, res)| res.map(|r| (field, r))).collect()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Ids are local to a SyncList and never reused or changed once given. Items are matched between
+// lists using their sync_ids which map to different ids in different lists. Clients store the time
+// they last synchronized for detecting conflicting modifications. Servers store tombstones of removed
+// items, so that clients which haven't synced since the removal cannot add the items back. Servers
+// also store the revision in which each item or tombstone last changed, see the delta module.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct SyncList<T: SyncItem + Clone> {
     items: Vec<T>,
     server: bool,
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default = "epoch")]
+    last_synced: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tombstones: Tombstones,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    revisions: BTreeMap<u64, u64>,
+    // The latest revision of the expired tombstones. Changes since older revisions are unknown.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    expired_revision: u64,
 }
 
 impl<T: SyncItem + Clone + PartialEq> SyncList<T> {
@@ -593,38 +906,39 @@ impl<T: SyncItem + Clone + PartialEq> SyncList<T> {
         Self {
             items: Vec::new(),
             server,
+            next_id: 0,
+            last_synced: epoch(),
+            tombstones: Tombstones::new(),
+            revisions: BTreeMap::new(),
+            expired_revision: 0,
         }
     }
     fn add(&mut self, mut item: T) {
         item.set_state(ItemState::New);
+        self.push(item);
+    }
+    // Adds an item keeping its state.
+    fn push(&mut self, mut item: T) {
+        // Lists saved before next_id existed have it set to zero, so never go below existing ids.
+        let id = self.items.iter().map(|i| i.id() + 1).max().unwrap_or(0).max(self.next_id);
+        self.next_id = id + 1;
+
+        item.set_id(id);
         self.items.push(item);
     }
     fn mark_removed(&mut self, id: u64) -> Result<()> {
-        if id >= self.items.len() as u64 {
-            return Err(Error::Unknown);
-        }
-        let item = self.items[id as usize].borrow_mut();
-
         // Do not allow the removal of items already removed.
-        if item.state() == ItemState::Removed {
-            return Err(Error::Unknown);
-        }
+        let item = self.get_item_mut(id).ok_or(Error::Unknown)?;
 
         item.set_state(ItemState::Removed);
 
         // Servers remove the items immediately.
         if self.server {
-            self.items.retain(|item| item.state() != ItemState::Removed);
-            self.map_indices_to_ids();
+            self.drop_removed();
         }
 
         Ok(())
     }
-    fn map_indices_to_ids(&mut self) {
-        for (new_id, item) in self.items.iter_mut().enumerate() {
-            item.set_id(new_id as u64);
-        }
-    }
     fn items(&self) -> Vec<&T> {
         let mut items = Vec::new();
         for item in &self.items {
@@ -636,16 +950,34 @@ impl<T: SyncItem + Clone + PartialEq> SyncList<T> {
         items
     }
     fn get_item_mut(&mut self, id: u64) -> Option<&mut T> {
-        self.items.get_mut(id as usize)
+        self.items.iter_mut().find(|i| i.id() == id && i.state() != ItemState::Removed)
+    }
+    // Marks synced items as changed, so that they are added to or merged with a list they haven't
+    // been synced with instead of being removed.
+    fn mark_unsynced(&mut self) {
+        for item in self.items.iter_mut() {
+            if item.state() == ItemState::Unchanged {
+                item.set_state(ItemState::Changed);
+            }
+        }
     }
     fn sync_self(&mut self) {
-        self.items.retain(|item| item.state() != ItemState::Removed);
-        self.map_indices_to_ids();
+        self.drop_removed();
         for item in self.items.iter_mut() {
             item.set_state(ItemState::Unchanged);
         }
     }
-    fn sync(&mut self, other: &mut Self) {
+    // Actually removes the items marked as removed. Servers add tombstones for them.
+    fn drop_removed(&mut self) {
+        if self.server {
+            let now = Utc::now();
+            for item in self.items.iter().filter(|i| i.state() == ItemState::Removed) {
+                self.tombstones.insert(item.sync_id(), now);
+            }
+        }
+        self.items.retain(|item| item.state() != ItemState::Removed);
+    }
+    fn sync(&mut self, other: &mut Self) -> ListReport {
         if self.server && other.server {
             panic!("Both self and other are servers.");
         } else if !self.server && !other.server {
@@ -662,14 +994,17 @@ impl<T: SyncItem + Clone + PartialEq> SyncList<T> {
             client_list = self;
         }
 
+        let mut report = ListReport::default();
+        let last_synced = client_list.last_synced;
+
         for item in client_list.items.iter_mut() {
+            let report_item = ReportItem::new(item.sync_id(), item.body());
+
             match item.state() {
-                ItemState::New => {
-                    server_list.add(item.clone());
-                }
                 ItemState::Removed => {
                     if let Some(s_item) = server_list.get_item_by_sync_id(item.sync_id()) {
                         s_item.set_state(ItemState::Removed);
+                        report.removed_from_server.push(report_item);
                     }
                 }
                 ItemState::Unchanged => {
@@ -678,68 +1013,251 @@ impl<T: SyncItem + Clone + PartialEq> SyncList<T> {
                         if s_item != item {
                             // Update the client item to match the server item.
                             s_item.update_old(item);
+                            report.updated_on_client.push(ReportItem::new(item.sync_id(), item.body()));
                         }
                     } else {
                         item.set_state(ItemState::Removed);
+                        report.removed_from_client.push(report_item);
                     }
                 }
-                ItemState::Changed => {
+                // Removing an item wins over modifying it, so the modifications of removed items are
+                // discarded.
+                ItemState::New | ItemState::Changed if server_list.tombstones.contains_key(&item.sync_id()) => {
+                    item.set_state(ItemState::Removed);
+                    report.removed_from_client.push(report_item);
+                }
+                // New items copied from a peer may have been added to the server by the peer
+                // already, so they are handled like modified items.
+                ItemState::New | ItemState::Changed => {
                     if let Some(s_item) = server_list.get_item_by_sync_id(item.sync_id()) {
-                        item.update_old(s_item);
+                        // The item may have been modified on the server as well. Keep the latest
+                        // modification of each field and give the result to the client too.
+                        let old_server_item = s_item.clone();
+                        let conflicts = s_item.merge(item, last_synced);
+                        let merged_item = ReportItem::new(s_item.sync_id(), s_item.body());
+
+                        if *s_item != old_server_item {
+                            report.updated_on_server.push(merged_item.clone());
+                        }
+                        if s_item != item {
+                            report.updated_on_client.push(merged_item.clone());
+                        }
+                        for (field, resolution) in conflicts {
+                            report.conflicts.push(Conflict::new(merged_item.clone(), field, resolution));
+                        }
+
+                        s_item.update_old(item);
                     } else {
                         // The modified item doesn't exist on the server therefore it needs to be
                         // added.
                         server_list.add(item.clone());
+                        report.added_to_server.push(report_item);
                     }
                 }
             }
         }
 
         for item in server_list.items.iter() {
-            if item.state() != ItemState::Removed {
-                if client_list.get_item_by_sync_id(item.sync_id()).is_none() {
-                    client_list.add(item.clone());
-                }
+            if item.state() != ItemState::Removed && client_list.get_item_by_sync_id(item.sync_id()).is_none() {
+                client_list.add(item.clone());
+                report.added_to_client.push(ReportItem::new(item.sync_id(), item.body()));
             }
         }
 
         client_list.sync_self();
         server_list.sync_self();
+        client_list.last_synced = Utc::now();
+
+        report
+    }
+
+    // Merges two client lists with each other. Unlike sync, the states of the items are kept so
+    // that both clients still send their modifications and removals to the server, and the items the
+    // other client has synced with the server are shared as synced. newer tells how the server
+    // revision self last synced with compares to the one of other, Equal if they cannot be compared.
+    fn sync_peer(&mut self, other: &mut Self, newer: Ordering) -> ListReport {
+        if self.server || other.server {
+            panic!("Either self or other is a server.");
+        }
+
+        let mut report = ListReport::default();
+        let since = self.last_synced.min(other.last_synced);
+
+        for item in self.items.iter_mut() {
+            let o_item = match other.get_item_by_sync_id(item.sync_id()) {
+                Some(o_item) => { o_item }
+                None => { continue; }
+            };
+            let report_item = ReportItem::new(item.sync_id(), item.body());
+
+            // Removing an item wins over modifying it on the other client.
+            if item.state() == ItemState::Removed || o_item.state() == ItemState::Removed {
+                if item.state() != ItemState::Removed {
+                    item.set_state(ItemState::Removed);
+                    report.removed_from_client.push(report_item);
+                } else if o_item.state() != ItemState::Removed {
+                    o_item.set_state(ItemState::Removed);
+                    report.removed_from_server.push(report_item);
+                }
+                continue;
+            }
+
+            // Unmodified items only differ if the clients have synced with the server at different
+            // times, in which case the most recent modifications are the ones on the server.
+            let state = match (item.state(), o_item.state()) {
+                (ItemState::Unchanged, ItemState::Unchanged) => { ItemState::Unchanged }
+                (ItemState::New, _) | (_, ItemState::New) => { ItemState::New }
+                _ => { ItemState::Changed }
+            };
+
+            let mut merged = o_item.clone();
+            let conflicts = merged.merge(item, since);
+            let merged_item = ReportItem::new(merged.sync_id(), merged.body());
+
+            if merged != *item {
+                report.updated_on_client.push(merged_item.clone());
+            }
+            if merged != *o_item {
+                report.updated_on_server.push(merged_item.clone());
+            }
+            for (field, resolution) in conflicts {
+                report.conflicts.push(Conflict::new(merged_item.clone(), field, resolution));
+            }
+
+            merged.update_old(item);
+            merged.update_old(o_item);
+            item.set_state(state);
+            o_item.set_state(state);
+        }
+
+        let self_ids = self.sync_ids();
+        let other_ids = other.sync_ids();
+
+        // An unmodified item missing from a client which synced with a newer revision of the server
+        // has been removed from the server. Otherwise, the item is copied to the other client.
+        for item in self.items.iter_mut().filter(|i| !other_ids.contains(&i.sync_id())) {
+            if item.state() == ItemState::Unchanged && newer == Ordering::Less {
+                item.set_state(ItemState::Removed);
+                report.removed_from_client.push(ReportItem::new(item.sync_id(), item.body()));
+            } else if item.state() != ItemState::Removed {
+                other.push(item.clone());
+                report.added_to_server.push(ReportItem::new(item.sync_id(), item.body()));
+            }
+        }
+        for item in other.items.iter_mut().filter(|i| !self_ids.contains(&i.sync_id())) {
+            if item.state() == ItemState::Unchanged && newer == Ordering::Greater {
+                item.set_state(ItemState::Removed);
+                report.removed_from_server.push(ReportItem::new(item.sync_id(), item.body()));
+            } else if item.state() != ItemState::Removed {
+                self.push(item.clone());
+                report.added_to_client.push(ReportItem::new(item.sync_id(), item.body()));
+            }
+        }
+
+        report
     }
 
     fn get_item_by_sync_id(&mut self, sync_id: u64) -> Option<&mut T> {
-        self.items.iter_mut().filter(|i| i.sync_id() == sync_id).next()
+        self.items.iter_mut().find(|i| i.sync_id() == sync_id)
     }
+
+    // Merges the items of another server list into self, a server list, keeping the most recently
+    // modified value of each field. Unlike sync, items missing from the other list are kept unless
+    // the other list has a tombstone for them.
+    fn merge_replica(&mut self, other: &Self) {
+        for item in other.items.iter().filter(|i| i.state() != ItemState::Removed) {
+            match self.get_item_by_sync_id(item.sync_id()) {
+                Some(s_item) => {
+                    s_item.merge(item, Utc::now());
+                }
+                None => {
+                    self.add(item.clone());
+                }
+            }
+        }
+        self.merge_tombstones(&other.tombstones);
+        self.sync_self();
+    }
+
+    // Adds the given tombstones to the tombstones of self, a server list, and removes the items
+    // they belong to.
+    fn merge_tombstones(&mut self, tombstones: &Tombstones) {
+        merge_tombstones(&mut self.tombstones, tombstones);
+        self.items.retain(|i| !self.tombstones.contains_key(&i.sync_id()));
+    }
+
+    // Forgets the tombstones of items removed before the given time.
+    fn expire_tombstones(&mut self, before: DateTime<Utc>) {
+        let expired: Vec<u64> = self.tombstones.iter()
+            .filter(|(_, removed)| **removed < before)
+            .map(|(sync_id, _)| *sync_id)
+            .collect();
+
+        for sync_id in expired {
+            self.tombstones.remove(&sync_id);
+            if let Some(revision) = self.revisions.remove(&sync_id) {
+                self.expired_revision = self.expired_revision.max(revision);
+            }
+        }
+    }
+
+    fn sync_ids(&self) -> BTreeSet<u64> {
+        self.items.iter().map(|i| i.sync_id()).collect()
+    }
+
 }
 
 /// A synchronizable list used for containing and managing all `Todo`s and `Task`s. `Todo`s and
-/// `Task`s have `id`s that match their `id`s within the `TdList`.
-#[derive(Debug, Serialize, Deserialize)]
+/// `Task`s have short `id`s that are given when they are added to the `TdList`. The `id`s are local
+/// to the `TdList`, stay the same across synchronizations and are never reused for other items.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TdList {
     todos: SyncList<Todo>,
     tasks: SyncList<Task>,
     server: bool,
+    // End-to-end encrypted items stored on a server. These are only read and merged by clients.
+    #[serde(default, skip_serializing_if = "SealedList::is_empty")]
+    sealed: SealedList,
+    // The salt of the end-to-end encrypted items a client last synced with, or None if it synced with
+    // readable items. Items are only removed if they were removed from the same items they were
+    // synced with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end_to_end_salt: Option<[u8; 16]>,
+    // Identifies the device in shared folders, and the list of a server in sync tokens. Lists saved
+    // before device ids existed get a new one.
+    #[serde(default = "random")]
+    device_id: u64,
+    // The current revision of a server. Incremented by every sync that changes the items.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    revision: u64,
+    // The server state a client last synced with, or None if the next sync needs all the items.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sync_token: Option<SyncToken>,
+    // The done dates of the done todos a client has removed by their sync ids, for statistics. These
+    // are not synchronized.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    completed_todos: BTreeMap<u64, NaiveDate>,
 }
 
 impl TdList {
     /// Creates a new empty client `TdList`.
     pub fn new_client() -> Self {
-        Self { todos: SyncList::new(false), tasks: SyncList::new(false), server: false }
+        Self { todos: SyncList::new(false), tasks: SyncList::new(false), server: false, sealed: SealedList::default(), end_to_end_salt: None, device_id: random(), revision: 0, sync_token: None, completed_todos: BTreeMap::new() }
     }
 
     /// Creates a new empty server `TdList`.
     pub fn new_server() -> Self {
-        Self { todos: SyncList::new(true), tasks: SyncList::new(true), server: true }
+        Self { todos: SyncList::new(true), tasks: SyncList::new(true), server: true, sealed: SealedList::default(), end_to_end_salt: None, device_id: random(), revision: 0, sync_token: None, completed_todos: BTreeMap::new() }
     }
 
     /// Creates a ´TdList` from a JSON string.
     pub fn new_from_json(json: &str) -> Result<Self> {
-        Ok(serde_json::from_str(json)?)
+        schema::td_list_from_json(json)
     }
 
     /// Creates a JSON string from the `TdList`.
     pub fn to_json(&self) -> Result<String> {
-        Ok(serde_json::to_string(self)?)
+        schema::td_list_to_json(self)
     }
 
     /// Gets all the `Todo`s in the list.
@@ -758,20 +1276,21 @@ impl TdList {
     }
 
     /// Adds a `Todo` to the list and updates its id.
-    pub fn add_todo(&mut self, mut todo: Todo) {
-        todo.set_id(self.todos.items.len() as u64);
+    pub fn add_todo(&mut self, todo: Todo) {
         self.todos.add(todo);
     }
 
     /// Adds a `Task` to the list and updates its id.
-    pub fn add_task(&mut self, mut task: Task) {
-        task.set_id(self.tasks.items.len() as u64);
+    pub fn add_task(&mut self, task: Task) {
         self.tasks.add(task)
     }
 
     /// Removes the `Todo` that matches the given id. If no `Todo` with the given `id` exists, returns
     /// a `MtdError`.
     pub fn remove_todo(&mut self, id: u64) -> Result<()> {
+        if let Some(todo) = self.todos.items.iter().find(|todo| todo.id == id && todo.state != ItemState::Removed) {
+            self.remember_completed_todo(todo.sync_id, todo.done);
+        }
         self.todos.mark_removed(id).map_err(|_| Error::NoTodoWithGivenId(id))
     }
 
@@ -793,6 +1312,29 @@ impl TdList {
         self.tasks.get_item_mut(id).ok_or(Error::NoTaskWithGivenId(id))
     }
 
+    /// Moves the `Todo` that matches the given id to a new date. If no `Todo` with the given `id`
+    /// exists, returns a `Error::NoTodoWithGivenId`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chrono::NaiveDate;
+    /// use mtd::{TdList, Todo};
+    ///
+    /// let mut list = TdList::new_client();
+    /// list.add_todo(Todo::new_undated("Book flights".to_string()));
+    ///
+    /// list.reschedule_todo(0, NaiveDate::from_ymd(2030, 1, 2)).unwrap();
+    ///
+    /// assert_eq!(list.undone_todos_for_date(NaiveDate::from_ymd(2030, 1, 2)).len(), 1);
+    /// // Todos are no longer shown on each matching weekday before their date.
+    /// assert!(list.undone_todos_for_date(NaiveDate::from_ymd(2029, 12, 26)).is_empty());
+    /// ```
+    pub fn reschedule_todo(&mut self, id: u64, date: NaiveDate) -> Result<()> {
+        self.get_todo_mut(id)?.set_date(date);
+        Ok(())
+    }
+
     /// Returns all `Todo`s for a given date that are not yet done.
     pub fn undone_todos_for_date(&self, date: NaiveDate) -> Vec<&Todo> {
         self.undone_todos_for_date_wtd(date, Local::today().naive_local())
@@ -827,12 +1369,12 @@ impl TdList {
         done_todos
     }
 
-    /// Returns all `Task`s for a given date that are not yet done.
+    /// Returns all `Task`s for a given date that are not yet done. Skipped `Task`s are not included.
     pub fn undone_tasks_for_date(&self, date: NaiveDate) -> Vec<&Task> {
         let mut undone_tasks = Vec::new();
 
         for task in self.tasks.items() {
-            if task.for_date(date) && !task.done(date) {
+            if task.for_date(date) && !task.done(date) && !task.skipped(date) {
                 undone_tasks.push(task);
             }
         }
@@ -863,26 +1405,58 @@ impl TdList {
     fn remove_old_todos_wtd(&mut self, today: NaiveDate) {
         for todo in &mut self.todos.items {
             if todo.can_remove_wtd(today) {
+                // Can't use remember_completed_todo while the items are borrowed.
+                if let (false, Some(done)) = (self.server, todo.done) {
+                    self.completed_todos.insert(todo.sync_id, done);
+                }
                 todo.state = ItemState::Removed;
             }
         }
         if self.server {
-            self.todos.items.retain(|todo| todo.state != ItemState::Removed);
+            self.todos.drop_removed();
+        }
+    }
+
+    // Remembers the done date of a done todo removed from a client, see stats.
+    fn remember_completed_todo(&mut self, sync_id: u64, done: Option<NaiveDate>) {
+        if let (false, Some(done)) = (self.server, done) {
+            self.completed_todos.insert(sync_id, done);
         }
     }
 
-    /// Synchronizes the list with itself actually removing items. Synchronizing may change the `id`s
-    /// of both `Todo`s and `Task`s. Additionally removes old `Todo`s.
+    /// Synchronizes the list with itself actually removing items. Additionally removes old `Todo`s.
     pub fn self_sync(&mut self) {
         self.remove_old_todos();
         self.todos.sync_self();
         self.tasks.sync_self();
     }
 
+    /// Forgets the removed items of a server that were removed more than `max_age` ago. Servers
+    /// remember the items removed from them, so that a client which hasn't synced since the removal
+    /// removes the item instead of adding it back, even if the client has modified it. A client that
+    /// syncs for the first time after the removal has expired adds its modified items back.
+    pub fn expire_tombstones(&mut self, max_age: Duration) {
+        if let Some(before) = tombstone_expiry_time(max_age) {
+            self.todos.expire_tombstones(before);
+            self.tasks.expire_tombstones(before);
+            self.sealed.expire_tombstones(before);
+        }
+    }
+
+    // Marks all synced items as changed. See SyncList::mark_unsynced.
+    fn mark_unsynced(&mut self) {
+        self.todos.mark_unsynced();
+        self.tasks.mark_unsynced();
+    }
+
     // This method is only unit tested using Todos which is fine as long as the internal sync impl
     // of todos and tasks is the same because then these tests cover Tasks as well.
-    /// Synchronizes the list with another list actually removing items. Synchronizing may change the `id`s
-    /// of both `Todo`s and `Task`s. Additionally removes old `Todo`s.
+    /// Synchronizes the list with another list actually removing items. Items copied from the other
+    /// list get new `id`s, but the `id`s of existing items never change. If an item has been modified
+    /// on both lists, the most recent modification of each field is kept. If an item has been
+    /// removed from the server, it is removed from the client as well, even if it has been modified
+    /// on the client. Additionally removes old `Todo`s. Returns a `SyncReport` describing the changes
+    /// made to both lists.
     ///
     /// # Example
     ///
@@ -918,7 +1492,8 @@ impl TdList {
     /// assert!(server.todos().contains(&&Todo::new_undated("Todo 2".to_string())));
     /// assert_eq!(server.todos().len(), 1);
     ///
-    /// client.get_todo_mut(0).unwrap().set_body("New Todo 1".to_string());
+    /// // Ids are not changed by synchronizing so "Todo 2" still has the id 1.
+    /// client.get_todo_mut(1).unwrap().set_body("New Todo 1".to_string());
     ///
     /// // Modifications are synchronized as well.
     /// client.sync(&mut server);
@@ -929,20 +1504,87 @@ impl TdList {
     /// assert!(server.todos().contains(&&Todo::new_undated("New Todo 1".to_string())));
     /// assert_eq!(server.todos().len(), 1);
     /// ```
-    pub fn sync(&mut self, other: &mut Self) {
+    pub fn sync(&mut self, other: &mut Self) -> SyncReport {
+        self.remove_old_todos();
+        other.remove_old_todos();
+
+        // The server keeps track of the revisions of its items, so that clients can sync only the
+        // changes and peers can tell which of them synced more recently.
+        let snapshot = if self.server { self.snapshot() } else { other.snapshot() };
+
+        let report = SyncReport {
+            todos: self.todos.sync(&mut other.todos),
+            tasks: self.tasks.sync(&mut other.tasks),
+        };
+
+        if self.server {
+            self.stamp_revisions(snapshot, other);
+        } else {
+            other.stamp_revisions(snapshot, self);
+        }
+
+        report
+    }
+
+    /// Synchronizes the list with the list of another client, for example when no server can be
+    /// reached. Both lists get the items of the other, and if an item has been modified on both lists,
+    /// the most recent modification of each field is kept. Removing an item wins over modifying it on
+    /// the other list. Returns a `SyncReport` in which the server is the other client. If either list
+    /// is a server, returns `Error::ClientOnlyOperation`.
+    ///
+    /// Unlike `sync`, the modifications and removals are kept unsynced, so both clients send them to
+    /// the server the next time they sync with it. The clients are expected to sync with the same
+    /// server. An unmodified item missing from one of the clients is only removed from the other if
+    /// the client missing it has synced with a newer revision of the server.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use mtd::{TdList, Todo};
+    ///
+    /// let mut laptop = TdList::new_client();
+    /// let mut desktop = TdList::new_client();
+    /// let mut server = TdList::new_server();
+    ///
+    /// laptop.add_todo(Todo::new_undated("Todo 1".to_string()));
+    /// laptop.sync_peer(&mut desktop).unwrap();
+    ///
+    /// assert_eq!(desktop.todos(), vec![&Todo::new_undated("Todo 1".to_string())]);
+    ///
+    /// // Both clients send the todo to the server, but it is only added once.
+    /// laptop.sync(&mut server);
+    /// desktop.sync(&mut server);
+    ///
+    /// assert_eq!(server.todos().len(), 1);
+    /// ```
+    pub fn sync_peer(&mut self, other: &mut Self) -> Result<SyncReport> {
+        if self.server || other.server {
+            return Err(Error::ClientOnlyOperation);
+        }
+
         self.remove_old_todos();
         other.remove_old_todos();
 
-        self.todos.sync(&mut other.todos);
-        self.tasks.sync(&mut other.tasks);
+        // Only the revisions of the same server tell which client has synced more recently.
+        let newer = match (self.sync_token, other.sync_token) {
+            (Some(token), Some(o_token)) => { token.cmp_revision(&o_token).unwrap_or(Ordering::Equal) }
+            _ => { Ordering::Equal }
+        };
+
+        Ok(SyncReport {
+            todos: self.todos.sync_peer(&mut other.todos, newer),
+            tasks: self.tasks.sync_peer(&mut other.tasks, newer),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use chrono::{NaiveDate, Weekday};
+    use std::time::Duration;
 
-    use crate::{Task, TdList, Todo, weekday_to_date_wtd};
+    use chrono::{NaiveDate, TimeZone, Utc, Weekday};
+
+    use crate::{Error, Recurrence, Resolution, Task, TdList, Todo, weekday_to_date_wtd};
 
     // Unit test a private function to remove the need to pass today into the Todo constructor
     #[test]
@@ -962,7 +1604,7 @@ mod tests {
 
     #[test]
     fn todo_for_date_tests() {
-        let todo = Todo::new_specific_date("Friday".to_string(), NaiveDate::from_ymd(2022, 6, 10));
+        let todo = Todo::new_for_date("Friday".to_string(), NaiveDate::from_ymd(2022, 6, 10));
 
         let today = NaiveDate::from_ymd(2022, 6, 10);
 
@@ -975,9 +1617,20 @@ mod tests {
         assert!(!todo.for_date_wtd(today.succ(), today)); // Todo is not for the following date because it is already for today
     }
 
+    #[test]
+    fn todo_for_far_future_date_not_shown_on_earlier_matching_weekdays() {
+        let todo = Todo::new_for_date("Far away".to_string(), NaiveDate::from_ymd(2022, 7, 8));
+
+        let today = NaiveDate::from_ymd(2022, 6, 10);
+
+        assert!(!todo.for_date_wtd(today, today)); // Same weekday but four weeks too early
+        assert!(!todo.for_date_wtd(NaiveDate::from_ymd(2022, 7, 1), today));
+        assert!(todo.for_date_wtd(NaiveDate::from_ymd(2022, 7, 8), today));
+    }
+
     #[test]
     fn todo_can_remove_returns_true_only_after_one_day_from_completion() {
-        let mut todo = Todo::new_specific_date("Todo".to_string(), NaiveDate::from_ymd(2022, 4, 25));
+        let mut todo = Todo::new_for_date("Todo".to_string(), NaiveDate::from_ymd(2022, 4, 25));
         todo.set_done_wtd(true, NaiveDate::from_ymd(2022, 4, 26));
 
         assert!(!todo.can_remove_wtd(NaiveDate::from_ymd(2022, 4, 26)));
@@ -1002,6 +1655,49 @@ mod tests {
         assert!(!task.weekdays().contains(&Weekday::Wed));
     }
 
+    #[test]
+    fn task_with_monthly_recurrence_done_only_for_occurrence() {
+        let mut task = Task::new_recurring("Monthly".to_string(), vec![], Recurrence::monthly_on_days(1, NaiveDate::from_ymd(2022, 1, 1), vec![15]));
+
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 15));
+
+        assert!(task.done(NaiveDate::from_ymd(2022, 6, 15)));
+        assert!(!task.done(NaiveDate::from_ymd(2022, 7, 15)));
+        // Not for the date, so it is always done.
+        assert!(task.done(NaiveDate::from_ymd(2022, 7, 16)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn task_new_recurring_panics_without_weekdays_when_weekly() {
+        Task::new_recurring("Weekly".to_string(), vec![], Recurrence::weekly(2, NaiveDate::from_ymd(2022, 1, 1)));
+    }
+
+    #[test]
+    fn task_next_date_with_large_interval() {
+        let task = Task::new_recurring("Rare".to_string(), vec![], Recurrence::yearly(1000, NaiveDate::from_ymd(2022, 6, 1)));
+
+        assert_eq!(task.next_date(NaiveDate::from_ymd(2022, 6, 2)), Some(NaiveDate::from_ymd(3022, 6, 1)));
+    }
+
+    #[test]
+    fn tdlist_undone_tasks_for_date_follows_recurrence() {
+        let mut list = TdList::new_client();
+
+        list.add_task(Task::new_recurring("Biweekly".to_string(), vec![Weekday::Thu], Recurrence::weekly(2, NaiveDate::from_ymd(2021, 3, 25))));
+
+        assert_eq!(list.undone_tasks_for_date(NaiveDate::from_ymd(2021, 4, 1)).len(), 0);
+        assert_eq!(list.undone_tasks_for_date(NaiveDate::from_ymd(2021, 4, 8)).len(), 1);
+    }
+
+    #[test]
+    fn task_without_recurrence_in_json_is_weekly() {
+        let json = r#"{"body":"Old","weekdays":["Mon"],"done_map":{},"id":0,"state":"Unchanged","sync_id":1}"#;
+        let task: Task = serde_json::from_str(json).unwrap();
+
+        assert_eq!(task, Task::new("Old".to_string(), vec![Weekday::Mon]));
+    }
+
     #[test]
     fn tdlist_add_todo_updates_ids() {
         let mut list = TdList::new_client();
@@ -1015,6 +1711,52 @@ mod tests {
         assert_eq!(list.todos()[2].id(), 2);
     }
 
+    #[test]
+    fn tdlist_ids_stay_same_after_self_sync_and_are_not_reused() {
+        let mut list = TdList::new_client();
+
+        list.add_todo(Todo::new_undated("Todo 0".to_string()));
+        list.add_todo(Todo::new_undated("Todo 1".to_string()));
+        list.add_todo(Todo::new_undated("Todo 2".to_string()));
+
+        list.remove_todo(0).unwrap();
+        list.self_sync();
+
+        assert_eq!(list.get_todo_mut(2).unwrap().body(), "Todo 2");
+        assert!(list.get_todo_mut(0).is_err());
+
+        list.remove_todo(2).unwrap();
+        list.self_sync();
+        list.add_todo(Todo::new_undated("Todo 3".to_string()));
+
+        assert_eq!(list.get_todo_mut(3).unwrap().body(), "Todo 3");
+        assert!(list.get_todo_mut(2).is_err());
+    }
+
+    #[test]
+    fn tdlist_sync_keeps_ids_and_gives_new_ids_to_copied_items() {
+        let mut client = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        client.add_todo(Todo::new_undated("Client 0".to_string()));
+        client.add_todo(Todo::new_undated("Client 1".to_string()));
+        server.add_todo(Todo::new_undated("Server 0".to_string()));
+
+        client.remove_todo(0).unwrap();
+        client.sync(&mut server);
+
+        assert_eq!(client.get_todo_mut(1).unwrap().body(), "Client 1");
+        assert_eq!(client.get_todo_mut(2).unwrap().body(), "Server 0");
+        assert_eq!(server.get_todo_mut(0).unwrap().body(), "Server 0");
+        assert_eq!(server.get_todo_mut(1).unwrap().body(), "Client 1");
+
+        server.remove_todo(0).unwrap();
+        client.sync(&mut server);
+
+        assert_eq!(client.get_todo_mut(1).unwrap().body(), "Client 1");
+        assert_eq!(server.get_todo_mut(1).unwrap().body(), "Client 1");
+    }
+
     #[test]
     fn tdlist_removed_todos_not_visible() {
         let mut list = TdList::new_client();
@@ -1081,10 +1823,10 @@ mod tests {
     fn tdlist_with_done_and_undone() -> TdList {
         let mut list = TdList::new_client();
 
-        list.add_todo(Todo::new_specific_date("Undone 1".to_string(), NaiveDate::from_ymd(2021, 4, 1)));
-        list.add_todo(Todo::new_specific_date("Undone 2".to_string(), NaiveDate::from_ymd(2021, 3, 29)));
-        list.add_todo(Todo::new_specific_date("Done 1".to_string(), NaiveDate::from_ymd(2021, 4, 1)));
-        list.add_todo(Todo::new_specific_date("Done 2".to_string(), NaiveDate::from_ymd(2021, 3, 30)));
+        list.add_todo(Todo::new_for_date("Undone 1".to_string(), NaiveDate::from_ymd(2021, 4, 1)));
+        list.add_todo(Todo::new_for_date("Undone 2".to_string(), NaiveDate::from_ymd(2021, 3, 29)));
+        list.add_todo(Todo::new_for_date("Done 1".to_string(), NaiveDate::from_ymd(2021, 4, 1)));
+        list.add_todo(Todo::new_for_date("Done 2".to_string(), NaiveDate::from_ymd(2021, 3, 30)));
 
         list.get_todo_mut(2).unwrap().set_done_wtd(true, NaiveDate::from_ymd(2021, 4, 1));
         list.get_todo_mut(3).unwrap().set_done_wtd(true, NaiveDate::from_ymd(2021, 4, 1));
@@ -1103,10 +1845,10 @@ mod tests {
 
         let returned = list.undone_todos_for_date_wtd(NaiveDate::from_ymd(2021, 4, 1), NaiveDate::from_ymd(2021, 4, 1));
 
-        assert!(returned.contains(&&list.todos()[0]));
-        assert!(returned.contains(&&list.todos()[1]));
-        assert!(!returned.contains(&&list.todos()[2]));
-        assert!(!returned.contains(&&list.todos()[3]));
+        assert!(returned.contains(&list.todos()[0]));
+        assert!(returned.contains(&list.todos()[1]));
+        assert!(!returned.contains(&list.todos()[2]));
+        assert!(!returned.contains(&list.todos()[3]));
         assert_eq!(returned.len(), 2);
     }
 
@@ -1116,10 +1858,10 @@ mod tests {
 
         let returned = list.done_todos_for_date_wtd(NaiveDate::from_ymd(2021, 4, 1), NaiveDate::from_ymd(2021, 4, 1));
 
-        assert!(!returned.contains(&&list.todos()[0]));
-        assert!(!returned.contains(&&list.todos()[1]));
-        assert!(returned.contains(&&list.todos()[2]));
-        assert!(returned.contains(&&list.todos()[3]));
+        assert!(!returned.contains(&list.todos()[0]));
+        assert!(!returned.contains(&list.todos()[1]));
+        assert!(returned.contains(&list.todos()[2]));
+        assert!(returned.contains(&list.todos()[3]));
         assert_eq!(returned.len(), 2);
     }
 
@@ -1129,8 +1871,8 @@ mod tests {
 
         let returned = list.undone_tasks_for_date(NaiveDate::from_ymd(2021, 4, 1));
 
-        assert!(returned.contains(&&list.tasks()[0]));
-        assert!(!returned.contains(&&list.tasks()[1]));
+        assert!(returned.contains(&list.tasks()[0]));
+        assert!(!returned.contains(&list.tasks()[1]));
         assert_eq!(returned.len(), 1);
     }
 
@@ -1140,8 +1882,8 @@ mod tests {
 
         let returned = list.done_tasks_for_date(NaiveDate::from_ymd(2021, 4, 1));
 
-        assert!(!returned.contains(&&list.tasks()[0]));
-        assert!(returned.contains(&&list.tasks()[1]));
+        assert!(!returned.contains(&list.tasks()[0]));
+        assert!(returned.contains(&list.tasks()[1]));
         assert_eq!(returned.len(), 1);
     }
 
@@ -1270,6 +2012,120 @@ mod tests {
         assert!(server.todos().contains(&&Todo::new_undated("New Todo 1".to_string())));
     }
 
+    #[test]
+    fn tdlist_sync_keeps_edits_to_different_fields_from_different_clients() {
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo".to_string()));
+        laptop.sync(&mut server);
+        phone.sync(&mut server);
+
+        laptop.get_todo_mut(0).unwrap().set_body("New Todo".to_string());
+        phone.get_todo_mut(0).unwrap().set_done(true);
+
+        laptop.sync(&mut server);
+        phone.sync(&mut server);
+        laptop.sync(&mut server);
+
+        for list in [&laptop, &phone, &server] {
+            assert_eq!(list.todos()[0].body(), "New Todo");
+            assert!(list.todos()[0].done());
+        }
+    }
+
+    #[test]
+    fn tdlist_sync_keeps_latest_edit_of_same_field() {
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo".to_string()));
+        laptop.sync(&mut server);
+        phone.sync(&mut server);
+
+        laptop.get_todo_mut(0).unwrap().set_body("Laptop".to_string());
+        laptop.get_todo_mut(0).unwrap().modified.body = Utc.timestamp(2000, 0);
+        phone.get_todo_mut(0).unwrap().set_body("Phone".to_string());
+        phone.get_todo_mut(0).unwrap().modified.body = Utc.timestamp(1000, 0);
+
+        // The phone's edit is older even though it is synced last.
+        laptop.sync(&mut server);
+        phone.sync(&mut server);
+
+        assert_eq!(phone.todos()[0].body(), "Laptop");
+        assert_eq!(server.todos()[0].body(), "Laptop");
+    }
+
+    #[test]
+    fn tdlist_sync_copies_task_done_state_to_server() {
+        let mut client = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        client.add_task(Task::new("Task".to_string(), vec![Weekday::Thu]));
+        client.sync(&mut server);
+
+        client.get_task_mut(0).unwrap().set_done(true, NaiveDate::from_ymd(2021, 4, 1));
+        server.get_task_mut(0).unwrap().set_body("New Task".to_string());
+        client.sync(&mut server);
+
+        assert!(server.tasks()[0].done(NaiveDate::from_ymd(2021, 4, 1)));
+        assert_eq!(client.tasks()[0].body(), "New Task");
+    }
+
+    #[test]
+    fn tdlist_sync_report_describes_added_updated_and_removed() {
+        let mut client = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        client.add_todo(Todo::new_undated("Client".to_string()));
+        server.add_todo(Todo::new_undated("Server".to_string()));
+        server.add_todo(Todo::new_undated("Removed".to_string()));
+
+        let report = client.sync(&mut server);
+
+        assert_eq!(report.todos().added_to_server().len(), 1);
+        assert_eq!(report.todos().added_to_server()[0].body(), "Client");
+        assert_eq!(report.todos().added_to_client().len(), 2);
+        assert!(report.tasks().is_empty());
+
+        server.get_todo_mut(0).unwrap().set_body("New Server".to_string());
+        server.remove_todo(1).unwrap();
+        client.remove_todo(0).unwrap();
+
+        let report = client.sync(&mut server);
+
+        assert_eq!(report.todos().updated_on_client()[0].body(), "New Server");
+        assert_eq!(report.todos().removed_from_client()[0].body(), "Removed");
+        assert_eq!(report.todos().removed_from_server()[0].body(), "Client");
+        assert!(report.todos().conflicts().is_empty());
+
+        assert!(client.sync(&mut server).is_empty());
+    }
+
+    #[test]
+    fn tdlist_sync_report_contains_conflicts_modified_on_both() {
+        let mut client = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        client.add_todo(Todo::new_undated("Todo".to_string()));
+        client.sync(&mut server);
+
+        server.get_todo_mut(0).unwrap().set_body("Server".to_string());
+        client.get_todo_mut(0).unwrap().set_body("Client".to_string());
+        client.get_todo_mut(0).unwrap().set_done(true);
+
+        let report = client.sync(&mut server);
+
+        // Only the body was modified on both.
+        assert_eq!(report.todos().conflicts().len(), 1);
+        assert_eq!(report.todos().conflicts()[0].field(), "body");
+        assert_eq!(report.todos().conflicts()[0].resolution(), Resolution::KeptClient);
+        assert_eq!(report.todos().updated_on_server().len(), 1);
+        assert_eq!(server.todos()[0].body(), "Client");
+    }
+
     #[test]
     #[should_panic]
     fn tdlist_sync_panics_with_both_server() {
@@ -1288,6 +2144,182 @@ mod tests {
         s.sync(&mut s1);
     }
 
+    #[test]
+    fn tdlist_sync_removal_wins_over_modification_of_stale_client() {
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo".to_string()));
+        laptop.sync(&mut server);
+        phone.sync(&mut server);
+
+        // The phone modifies the todo offline after the laptop has removed it.
+        laptop.remove_todo(0).unwrap();
+        laptop.sync(&mut server);
+        phone.get_todo_mut(0).unwrap().set_body("New Todo".to_string());
+
+        let report = phone.sync(&mut server);
+
+        assert_eq!(report.todos().removed_from_client()[0].body(), "New Todo");
+        assert!(report.todos().added_to_server().is_empty());
+        assert!(phone.todos().is_empty());
+        assert!(server.todos().is_empty());
+    }
+
+    #[test]
+    fn tdlist_expired_tombstones_no_longer_remove_modified_items() {
+        let mut laptop = TdList::new_client();
+        let mut phone = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_task(Task::new("Task".to_string(), vec![Weekday::Mon]));
+        laptop.sync(&mut server);
+        phone.sync(&mut server);
+
+        laptop.remove_task(0).unwrap();
+        laptop.sync(&mut server);
+        phone.get_task_mut(0).unwrap().set_body("New Task".to_string());
+
+        server.expire_tombstones(Duration::from_secs(3600));
+        assert_eq!(server.tasks.tombstones.len(), 1);
+
+        server.expire_tombstones(Duration::ZERO);
+        phone.sync(&mut server);
+
+        assert_eq!(server.tasks()[0].body(), "New Task");
+    }
+
+    #[test]
+    fn tdlist_sync_peer_shares_new_items_which_are_added_to_server_once() {
+        let mut laptop = TdList::new_client();
+        let mut desktop = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Laptop".to_string()));
+        desktop.add_task(Task::new("Desktop".to_string(), vec![Weekday::Mon]));
+
+        let report = laptop.sync_peer(&mut desktop).unwrap();
+
+        assert_eq!(report.todos().added_to_server()[0].body(), "Laptop");
+        assert_eq!(report.tasks().added_to_client()[0].body(), "Desktop");
+        assert_eq!(desktop.todos().len(), 1);
+        assert_eq!(laptop.tasks().len(), 1);
+
+        desktop.sync(&mut server);
+        laptop.sync(&mut server);
+
+        assert_eq!(server.todos().len(), 1);
+        assert_eq!(server.tasks().len(), 1);
+        assert_eq!(laptop.todos().len(), 1);
+        assert_eq!(laptop.tasks().len(), 1);
+    }
+
+    #[test]
+    fn tdlist_sync_peer_removal_is_not_undone_by_server() {
+        let mut laptop = TdList::new_client();
+        let mut desktop = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo".to_string()));
+        laptop.sync(&mut server);
+        desktop.sync(&mut server);
+
+        laptop.remove_todo(0).unwrap();
+        desktop.get_todo_mut(0).unwrap().set_body("New Todo".to_string());
+        laptop.sync_peer(&mut desktop).unwrap();
+
+        assert!(laptop.todos().is_empty());
+        assert!(desktop.todos().is_empty());
+
+        // The server learns about the removal from whichever client syncs first.
+        desktop.sync(&mut server);
+        laptop.sync(&mut server);
+
+        assert!(server.todos().is_empty());
+        assert!(laptop.todos().is_empty());
+    }
+
+    #[test]
+    fn tdlist_sync_peer_does_not_bring_back_items_removed_from_server() {
+        let mut laptop = TdList::new_client();
+        let mut desktop = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo".to_string()));
+        laptop.sync(&mut server);
+        desktop.sync(&mut server);
+
+        // The removal is synced with the server before the desktop hears about it.
+        laptop.remove_todo(0).unwrap();
+        laptop.sync(&mut server);
+
+        let report = laptop.sync_peer(&mut desktop).unwrap();
+
+        assert_eq!(report.todos().removed_from_server().len(), 1);
+        assert!(laptop.todos().is_empty());
+        assert!(desktop.todos().is_empty());
+
+        desktop.sync(&mut server);
+        assert!(server.todos().is_empty());
+    }
+
+    #[test]
+    fn tdlist_sync_peer_compares_server_revisions_instead_of_clocks() {
+        let mut laptop = TdList::new_client();
+        let mut desktop = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo".to_string()));
+        laptop.sync(&mut server);
+        desktop.sync(&mut server);
+
+        laptop.remove_todo(0).unwrap();
+        laptop.sync(&mut server);
+
+        // The clock of the desktop is ahead, but it has synced with an older revision.
+        desktop.todos.last_synced = Utc::now() + chrono::Duration::days(1);
+        laptop.sync_peer(&mut desktop).unwrap();
+
+        assert!(laptop.todos().is_empty());
+        assert!(desktop.todos().is_empty());
+    }
+
+    #[test]
+    fn tdlist_sync_peer_keeps_edits_until_synced_with_server() {
+        let mut laptop = TdList::new_client();
+        let mut desktop = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        laptop.add_todo(Todo::new_undated("Todo".to_string()));
+        laptop.sync(&mut server);
+        desktop.sync(&mut server);
+
+        laptop.get_todo_mut(0).unwrap().set_body("Laptop".to_string());
+        desktop.get_todo_mut(0).unwrap().set_done(true);
+        laptop.sync_peer(&mut desktop).unwrap();
+
+        for list in [&laptop, &desktop] {
+            assert_eq!(list.todos()[0].body(), "Laptop");
+            assert!(list.todos()[0].done());
+        }
+
+        // Either client brings both edits to the server.
+        desktop.sync(&mut server);
+
+        assert_eq!(server.todos()[0].body(), "Laptop");
+        assert!(server.todos()[0].done());
+    }
+
+    #[test]
+    fn tdlist_sync_peer_returns_err_with_server() {
+        let mut client = TdList::new_client();
+        let mut server = TdList::new_server();
+
+        assert!(matches!(client.sync_peer(&mut server).unwrap_err(), Error::ClientOnlyOperation));
+        assert!(matches!(server.sync_peer(&mut client).unwrap_err(), Error::ClientOnlyOperation));
+    }
+
     // This is like many tests merged into a one due to my laziness.
     #[test]
     fn tdlist_sync_works_with_multiple_items_and_with_tasks() {
@@ -14,18 +14,20 @@ You should have received a copy of the GNU General Public License along with thi
 see <https://www.gnu.org/licenses/>.
  */
 
-use std::{fs, io, process};
+use std::{env, fs, io, process, result};
 use std::io::Write;
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
 use chrono::{Datelike, Local, NaiveDate};
-use clap::{ArgEnum, Parser, Subcommand};
+use clap::{ArgEnum, Args, Parser, Subcommand};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use signal_hook::consts::TERM_SIGNALS;
 
-use mtd::{Config, Error, MtdNetMgr, Result, Task, TdList, Todo};
+use mtd::{backup_path, Config, DerivedKey, Error, FileLock, Frequency, ListReport, load_with_backup, MtdNetMgr, Period, Recurrence, ReportItem, Resolution, Result, save_atomic, SecretSource, Stats, StdioStream, SyncReport, Task, TdList, Todo};
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -46,6 +48,9 @@ enum Commands {
         /// Weekday to show
         #[clap(arg_enum, value_parser, long, short, group = "show_days")]
         weekday: Option<Weekday>,
+        /// Date to show (YYYY-MM-DD)
+        #[clap(value_parser, long, short, group = "show_days")]
+        date: Option<NaiveDate>,
         /// Show entire week starting from today
         #[clap(value_parser, long, group = "show_days")]
         week: bool,
@@ -58,9 +63,11 @@ enum Commands {
         /// Body of the item
         #[clap(value_parser)]
         body: String,
-        /// Weekday(s) of the item
-        #[clap(arg_enum, value_parser)]
-        weekdays: Vec<Weekday>,
+        /// Weekday(s) or date(s) (YYYY-MM-DD) of the item. Tasks use the weekday of a given date
+        #[clap(value_parser)]
+        days: Vec<Day>,
+        #[clap(flatten)]
+        recurrence: RecurrenceArgs,
     },
     /// Removes an item
     Remove {
@@ -103,16 +110,166 @@ enum Commands {
         /// Set the weekday(s) of the item
         #[clap(arg_enum, value_parser, long, short)]
         weekdays: Vec<Weekday>,
+        /// Set the date of a todo (YYYY-MM-DD)
+        #[clap(value_parser, long, short, conflicts_with = "weekdays")]
+        date: Option<NaiveDate>,
+        #[clap(flatten)]
+        recurrence: RecurrenceArgs,
+    },
+    /// Shows task streaks, completion rates and the number of done todos
+    Stats {
+        /// Number of weeks to show the statistics for, ending today
+        #[clap(value_parser = clap::value_parser!(u32).range(1..), long, short, default_value = "4")]
+        weeks: u32,
+        /// Length of the periods done todos are counted in
+        #[clap(arg_enum, value_parser, long, short, default_value = "week")]
+        period: StatsPeriod,
+        /// Print the statistics as JSON
+        #[clap(value_parser, long)]
+        json: bool,
     },
     /// Synchronizes local items with a server
-    Sync,
-    /// Runs mtd as a server
-    Server,
+    Sync {
+        /// List the items affected by the synchronization
+        #[clap(value_parser, long, short)]
+        verbose: bool,
+    },
+    /// Synchronizes local items directly with another client, for example when no server can be
+    /// reached. One client listens and the other one connects to it
+    Peer {
+        /// The address of the other client, or the address to listen on (ADDRESS:PORT or unix:PATH)
+        #[clap(value_parser)]
+        addr: String,
+        /// Wait for the other client to connect instead of connecting to it
+        #[clap(value_parser, long, short)]
+        listen: bool,
+        /// List the items affected by the synchronization
+        #[clap(value_parser, long, short)]
+        verbose: bool,
+    },
+    /// Runs mtd as a server until SIGINT or SIGTERM is received
+    Server {
+        /// Run the server in the background (Unix only)
+        #[clap(value_parser, long)]
+        daemon: bool,
+        /// Write the process id of the server to a file
+        #[clap(value_parser, long)]
+        pidfile: Option<PathBuf>,
+        /// Write the output of a daemonized server to a file instead of discarding it
+        #[clap(value_parser, long, requires = "daemon")]
+        log_file: Option<PathBuf>,
+    },
+    /// Handles a single sync session over stdin and stdout and exits. Clients can run this through
+    /// SSH with a socket address like 'command:ssh HOST mtd serve-stdio'
+    ServeStdio,
+    /// Encrypts the items saved before data encryption was enabled in the config
+    EncryptData,
     /// Re-initializes mtd
     /// (WARNING! This will completely delete all saved items!)
     ReInit,
 }
 
+// Options for tasks that don't simply repeat every week.
+#[derive(Args, Default)]
+struct RecurrenceArgs {
+    /// How often a task repeats
+    #[clap(arg_enum, value_parser, long)]
+    repeat: Option<Repeat>,
+    /// Number of days, weeks, months or years between the occurrences of a task
+    #[clap(value_parser = clap::value_parser!(u32).range(1..), long)]
+    interval: Option<u32>,
+    /// Date from which the occurrences of a task are counted (YYYY-MM-DD, defaults to today)
+    #[clap(value_parser, long)]
+    start: Option<NaiveDate>,
+    /// Day(s) of the month of a monthly or yearly task. Negative days count from the end of the month
+    #[clap(value_parser, long, allow_hyphen_values = true)]
+    month_day: Vec<i32>,
+    /// Nth weekday(s) of the month of a monthly or yearly task, for example 1:mon or -1:fri
+    #[clap(value_parser, long, allow_hyphen_values = true)]
+    nth: Vec<NthWeekday>,
+}
+
+impl RecurrenceArgs {
+    /// Returns `None` if no recurrence options were given.
+    fn to_recurrence(&self) -> Option<Recurrence> {
+        if self.repeat.is_none() && self.interval.is_none() && self.start.is_none() &&
+            self.month_day.is_empty() && self.nth.is_empty() {
+            return None;
+        }
+
+        let frequency = match self.repeat {
+            Some(repeat) => { repeat.into() }
+            None if !self.month_day.is_empty() || !self.nth.is_empty() => { Frequency::Monthly }
+            None => { Frequency::Weekly }
+        };
+
+        Some(Recurrence::new(
+            frequency,
+            self.interval.unwrap_or(1),
+            self.start.unwrap_or_else(|| Local::today().naive_local()),
+            self.month_day.clone(),
+            self.nth.iter().map(|nth| (nth.0, nth.1.into())).collect(),
+        ))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+enum Repeat {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+impl From<Repeat> for Frequency {
+    fn from(repeat: Repeat) -> Self {
+        match repeat {
+            Repeat::Daily => { Frequency::Daily }
+            Repeat::Weekly => { Frequency::Weekly }
+            Repeat::Monthly => { Frequency::Monthly }
+            Repeat::Yearly => { Frequency::Yearly }
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+enum StatsPeriod {
+    Day,
+    Week,
+    Month,
+}
+
+impl From<StatsPeriod> for Period {
+    fn from(period: StatsPeriod) -> Self {
+        match period {
+            StatsPeriod::Day => { Period::Day }
+            StatsPeriod::Week => { Period::Week }
+            StatsPeriod::Month => { Period::Month }
+        }
+    }
+}
+
+// Nth weekday of a month given as N:WEEKDAY.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct NthWeekday(i8, Weekday);
+
+impl FromStr for NthWeekday {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let err = || format!("'{}' is not a valid nth weekday (for example 1:mon or -1:fri).", s);
+
+        let (n, wd) = s.split_once(':').ok_or_else(err)?;
+        let n: i8 = n.parse().map_err(|_| err())?;
+        if n == 0 || !(-5..=5).contains(&n) {
+            return Err(err());
+        }
+        let wd = <Weekday as ArgEnum>::from_str(wd, true).map_err(|_| err())?;
+
+        Ok(NthWeekday(n, wd))
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
 enum ItemType {
     Todo,
@@ -120,7 +277,7 @@ enum ItemType {
 }
 
 // Define custom weekday for clap to parse weekdays.
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ArgEnum)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, ArgEnum)]
 enum Weekday {
     Mon,
     Tue,
@@ -131,9 +288,9 @@ enum Weekday {
     Sun,
 }
 
-impl Into<chrono::Weekday> for Weekday {
-    fn into(self) -> chrono::Weekday {
-        match self {
+impl From<Weekday> for chrono::Weekday {
+    fn from(wd: Weekday) -> Self {
+        match wd {
             Weekday::Mon => { chrono::Weekday::Mon }
             Weekday::Tue => { chrono::Weekday::Tue }
             Weekday::Wed => { chrono::Weekday::Wed }
@@ -145,6 +302,69 @@ impl Into<chrono::Weekday> for Weekday {
     }
 }
 
+// A day given on the command line. Either a weekday meaning the next such weekday or an explicit
+// ISO 8601 date.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Day {
+    Weekday(Weekday),
+    Date(NaiveDate),
+}
+
+impl Day {
+    fn to_date(self) -> NaiveDate {
+        match self {
+            Day::Weekday(wd) => { mtd::weekday_to_date(wd.into()) }
+            Day::Date(date) => { date }
+        }
+    }
+
+    fn weekday(self) -> chrono::Weekday {
+        match self {
+            Day::Weekday(wd) => { wd.into() }
+            Day::Date(date) => { date.weekday() }
+        }
+    }
+}
+
+impl FromStr for Day {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(Day::Date(date));
+        }
+        <Weekday as ArgEnum>::from_str(s, true)
+            .map(Day::Weekday)
+            .map_err(|_| format!("'{}' is neither a weekday nor a date (YYYY-MM-DD).", s))
+    }
+}
+
+/// Formats a key salt as hexadecimal digits.
+fn salt_to_hex(salt: &[u8; 16]) -> String {
+    salt.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Parses a key salt formatted with `salt_to_hex`.
+fn parse_salt(hex: &str) -> Option<[u8; 16]> {
+    if hex.len() != 32 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut salt = [0; 16];
+    for (i, byte) in salt.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(salt)
+}
+
+// Formats a completion rate as a rounded percentage, or '-' if there is nothing to count.
+fn format_rate(rate: Option<f64>) -> String {
+    match rate {
+        Some(rate) => { format!("{:.0}%", rate * 100.0) }
+        None => { "-".to_string() }
+    }
+}
+
 fn main() {
     if let Err(e) = MtdApp::run() {
         eprintln!("{}", e);
@@ -160,29 +380,18 @@ struct MtdApp {
 }
 
 impl MtdApp {
-    /// Initializes a new MtdApp. Reads/creates config and saved items.
-    fn init(config_path: &PathBuf) -> Result<Self> {
-        let conf;
-
-        if config_path.exists() {
-            conf = Config::new_from_json(&fs::read_to_string(config_path)?)?;
-        } else {
-            conf = MtdApp::create_new_config(config_path)?;
-        }
+    /// Initializes a new MtdApp with a config. Reads/creates saved items.
+    fn init(mut conf: Config) -> Result<Self> {
+        MtdApp::read_data_password(&mut conf)?;
 
         let list;
 
         // It is possible that a save_location has not been defined which needs to be checked before
         // checking if the path even exists.
         if let Some(list_path) = conf.save_location() {
-            if list_path.exists() {
-                list = TdList::new_from_json(
-                    &fs::read_to_string(
-                        list_path
-                    )?
-                )?;
-            } else {
-                list = MtdApp::create_new_list(&conf)?;
+            match MtdApp::load(list_path, |data| TdList::new_from_json(&conf.decode_data(data)?))? {
+                Some(l) => { list = l; }
+                None => { list = MtdApp::create_new_list(&conf)?; }
             }
         } else {
             list = MtdApp::create_new_list(&conf)?;
@@ -194,6 +403,43 @@ impl MtdApp {
         })
     }
 
+    /// Reads the password for encrypting the saved items if the config enables the encryption.
+    fn read_data_password(conf: &mut Config) -> Result<()> {
+        if let Some(source) = conf.data_encryption() {
+            let password = source.read(|| rpassword::prompt_password("Input data encryption password: "))?;
+            conf.set_data_password(password);
+        }
+        Ok(())
+    }
+
+    /// Reads or creates the config.
+    fn load_config(config_path: &Path) -> Result<Config> {
+        let parse = |data: &[u8]| Config::new_from_json(&String::from_utf8_lossy(data));
+        match MtdApp::load(config_path, parse)? {
+            Some(conf) => { Ok(conf) }
+            None => { MtdApp::create_new_config(config_path) }
+        }
+    }
+
+    /// Loads a saved file, falling back to the last good copy if the file is unreadable.
+    fn load<T>(path: &Path, parse: impl Fn(&[u8]) -> Result<T>) -> Result<Option<T>> {
+        let loaded = match load_with_backup(path, parse)? {
+            Some(loaded) => { loaded }
+            None => { return Ok(None); }
+        };
+
+        if let Some(e) = loaded.main_error() {
+            eprintln!(
+                "Warning! Cannot read '{}': {}\nUsing the last good copy '{}' instead. Changes made after it was saved are lost.",
+                path.display(),
+                e,
+                backup_path(path).display()
+            );
+        }
+
+        Ok(Some(loaded.into_value()))
+    }
+
     /// Creates a new TdList as a server or a client depending on user input.
     fn create_new_list(config: &Config) -> Result<TdList> {
         let mut buffer = String::new();
@@ -236,7 +482,7 @@ impl MtdApp {
     }
 
     /// Initializes a new config and writes it to a file.
-    fn create_new_config(config_path: &PathBuf) -> Result<Config> {
+    fn create_new_config(config_path: &Path) -> Result<Config> {
         println!("Creating a new config.");
 
         let stdin = io::stdin();
@@ -258,7 +504,10 @@ impl MtdApp {
         }
 
         let local_only = &local_only_inp_buf == "y";
-        let mut encryption_passwd;
+        let encryption_passwd;
+        let mut password_source = None;
+        let mut derived_key = None;
+        let mut end_to_end = None;
         let mut socket_addr = String::new();
 
         if local_only {
@@ -272,36 +521,23 @@ impl MtdApp {
                 .collect();
         } else {
             loop {
-                print!("Input server socket address (ADDRESS:PORT): ");
+                print!("Input server socket address (ADDRESS:PORT, unix:PATH, command:COMMAND or folder:PATH): ");
                 stdout.flush()?;
                 socket_addr.clear();
                 stdin.read_line(&mut socket_addr)?;
                 socket_addr = socket_addr.trim().to_string();
 
-                if socket_addr.to_socket_addrs().is_err() {
+                // Unix domain socket paths, commands and folders are only checked when syncing.
+                let other_transport = ["unix:", "command:", "folder:"].iter().any(|prefix| socket_addr.starts_with(prefix));
+                if !other_transport && socket_addr.to_socket_addrs().is_err() {
                     eprintln!("Cannot parse '{}' to socket address.", socket_addr);
                     continue;
                 }
                 break;
             }
 
-            println!("Note! Encryption password is stored in cleartext but obfuscated locally.");
-
-            let mut encryption_passwd_again;
-
-            loop {
-                encryption_passwd = rpassword::prompt_password("Input encryption password: ")?;
-                encryption_passwd_again = rpassword::prompt_password("Input encryption password again: ")?;
-
-                if encryption_passwd != encryption_passwd_again {
-                    eprintln!("Passwords do not match.");
-                    continue;
-                } else if encryption_passwd.is_empty() {
-                    eprintln!("Password cannot be empty.");
-                    continue;
-                }
-                break;
-            }
+            (encryption_passwd, password_source, derived_key) = MtdApp::ask_password_storage()?;
+            end_to_end = MtdApp::ask_end_to_end()?;
         }
 
         let mut save_location_buf = String::new();
@@ -313,71 +549,284 @@ impl MtdApp {
             stdin.read_line(&mut save_location_buf)?;
             save_location_buf = save_location_buf.trim().to_string();
 
-            if save_location_buf.parse::<PathBuf>().is_err() && &save_location_buf != "" {
+            if save_location_buf.parse::<PathBuf>().is_err() && !save_location_buf.is_empty() {
                 eprintln!("Cannot parse '{}' to path.", save_location_buf);
                 continue;
             }
             break;
         }
 
-        let save_path;
-
-        if &save_location_buf == "" {
-            save_path = MtdApp::default_save_path()?;
+        let save_path = if save_location_buf.is_empty() {
+            MtdApp::default_save_path()?
         } else {
-            save_path = save_location_buf.parse().unwrap();
-        }
+            save_location_buf.parse().unwrap()
+        };
 
-        let conf = Config::new(
+        let mut conf = Config::new(
             socket_addr.parse().unwrap(),
             encryption_passwd.into_bytes(),
             Duration::from_secs(30),
             Some(save_path),
             local_only,
         );
+        conf.set_password_source(password_source);
+        conf.set_derived_key(derived_key);
+        conf.set_end_to_end(end_to_end);
+        conf.set_data_encryption(MtdApp::ask_data_encryption()?);
 
         if let Some(conf_dir) = config_path.parent() {
             fs::create_dir_all(conf_dir)?;
         }
-        fs::write(&config_path, conf.to_json()?)?;
+        save_atomic(config_path, conf.to_json()?.as_bytes())?;
 
         Ok(conf)
     }
 
+    /// Asks whether the saved items should be encrypted and where the password is read from.
+    fn ask_data_encryption() -> Result<Option<SecretSource>> {
+        if MtdApp::ask_choice("Encrypt saved items (y/n)? ", &["y", "n"])? == "n" {
+            return Ok(None);
+        }
+        Ok(Some(MtdApp::ask_secret_source()?))
+    }
+
+    /// Asks whether synced items should be encrypted so that the server cannot read them and where
+    /// the password is read from.
+    fn ask_end_to_end() -> Result<Option<SecretSource>> {
+        if MtdApp::ask_choice("Encrypt synced items end-to-end so that the server cannot read them (y/n)? ", &["y", "n"])? == "n" {
+            return Ok(None);
+        }
+        Ok(Some(MtdApp::ask_secret_source()?))
+    }
+
+    /// Asks where a password is read from.
+    fn ask_secret_source() -> Result<SecretSource> {
+        match MtdApp::ask_choice("Read the password from a prompt, an environment variable or a command (p/e/c)? ", &["p", "e", "c"])?.as_str() {
+            "p" => { Ok(SecretSource::Prompt) }
+            "e" => { Ok(SecretSource::Env(MtdApp::ask_non_empty("Input environment variable name: ")?)) }
+            _ => { Ok(SecretSource::Command(MtdApp::ask_non_empty("Input password command: ")?)) }
+        }
+    }
+
+    /// Asks where the encryption password for syncing is stored. Returns the password to store in the
+    /// config, the source of the password and the key to use instead of the password.
+    fn ask_password_storage() -> Result<(String, Option<SecretSource>, Option<DerivedKey>)> {
+        let question = "Store the encryption password in the (c)onfig, a secret (f)ile, the MTD_PASSWORD (e)nvironment \
+            variable, the output of a co(m)mand or store only a derived (k)ey (c/f/e/m/k)? ";
+
+        match MtdApp::ask_choice(question, &["c", "f", "e", "m", "k"])?.as_str() {
+            "c" => {
+                println!("Note! Encryption password is stored in cleartext but obfuscated locally.");
+                Ok((MtdApp::ask_new_password()?, None, None))
+            }
+            "f" => {
+                let path = MtdApp::ask_non_empty("Input secret file path (the file should only be readable by you): ")?;
+                Ok((String::new(), Some(SecretSource::File(path.into())), None))
+            }
+            "e" => {
+                Ok((String::new(), Some(SecretSource::Env("MTD_PASSWORD".to_string())), None))
+            }
+            "m" => {
+                let command = MtdApp::ask_non_empty("Input password command: ")?;
+                Ok((String::new(), Some(SecretSource::Command(command)), None))
+            }
+            _ => {
+                let password = MtdApp::ask_new_password()?;
+                let key = loop {
+                    let salt = MtdApp::ask("Input the key salt used by the server and other clients (Leave empty to generate a new one): ")?;
+                    if salt.is_empty() {
+                        break DerivedKey::derive_with_random_salt(password.as_bytes())?;
+                    }
+                    match parse_salt(&salt) {
+                        Some(salt) => { break DerivedKey::derive(password.as_bytes(), salt)?; }
+                        None => { eprintln!("The salt should be 32 hexadecimal digits."); }
+                    }
+                };
+                println!("Key salt: {}", salt_to_hex(&key.salt()));
+                println!("Use the same salt and password when creating the configs of the server and other clients.");
+                Ok((String::new(), None, Some(key)))
+            }
+        }
+    }
+
+    /// Asks a new password twice.
+    fn ask_new_password() -> Result<String> {
+        loop {
+            let encryption_passwd = rpassword::prompt_password("Input encryption password: ")?;
+            let encryption_passwd_again = rpassword::prompt_password("Input encryption password again: ")?;
+
+            if encryption_passwd != encryption_passwd_again {
+                eprintln!("Passwords do not match.");
+                continue;
+            } else if encryption_passwd.is_empty() {
+                eprintln!("Password cannot be empty.");
+                continue;
+            }
+            return Ok(encryption_passwd);
+        }
+    }
+
+    /// Asks a question until one of the given options is answered. Returns the lowercase answer.
+    fn ask_choice(question: &str, options: &[&str]) -> Result<String> {
+        loop {
+            let answer = MtdApp::ask(question)?.to_lowercase();
+            if options.contains(&answer.as_str()) {
+                return Ok(answer);
+            }
+            eprintln!("Invalid option.");
+        }
+    }
+
+    /// Asks a question until the answer isn't empty.
+    fn ask_non_empty(question: &str) -> Result<String> {
+        loop {
+            let answer = MtdApp::ask(question)?;
+            if !answer.is_empty() {
+                return Ok(answer);
+            }
+            eprintln!("Input cannot be empty.");
+        }
+    }
+
+    /// Asks a question and returns the trimmed answer.
+    fn ask(question: &str) -> Result<String> {
+        let mut stdout = io::stdout();
+        let mut buffer = String::new();
+
+        print!("{}", question);
+        stdout.flush()?;
+        io::stdin().read_line(&mut buffer)?;
+
+        Ok(buffer.trim().to_string())
+    }
+
     /// Runs the mtd cli app.
     fn run() -> Result<()> {
         let cli = CliArgs::parse();
         let config_path = cli.config_file.unwrap_or(MtdApp::default_config_path()?);
 
-        let app;
-
         // Re-init is checked here because it should run without reading previous values.
         if let Commands::ReInit = &cli.command {
-            app = MtdApp::re_init(&config_path)?;
-        } else {
-            app = MtdApp::init(&config_path)?.handle_command(cli.command)?;
+            let app = MtdApp::re_init(&config_path)?;
+            let _lock = MtdApp::lock(&app.conf)?;
+            return app.save();
+        }
+
+        // The session uses stdin and stdout, so the config options cannot be asked.
+        if let Commands::ServeStdio = &cli.command {
+            if !config_path.exists() {
+                let msg = format!("No config found at '{}'. Create the server config by running mtd interactively first.", config_path.display());
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+            }
+        }
+
+        let conf = MtdApp::load_config(&config_path)?;
+
+        // Unencrypted items cannot be loaded once data encryption is enabled, so they are encrypted
+        // without initializing the app.
+        if let Commands::EncryptData = &cli.command {
+            return MtdApp::encrypt_data(conf);
+        }
+
+        // Creating a new list would ask whether it is a server or a client.
+        if let Commands::ServeStdio = &cli.command {
+            let msg = match conf.save_location() {
+                Some(path) if path.exists() || backup_path(path).exists() => { None }
+                Some(path) => { Some(format!("No saved items found at '{}'. Initialize the server by running mtd interactively first.", path.display())) }
+                None => { Some("The config doesn't define a save location for the server items.".to_string()) }
+            };
+            if let Some(msg) = msg {
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+            }
+        }
+
+        // The list is locked from loading until saving so that other mtd processes cannot overwrite
+        // the changes. A server runs until stopped, so it only locks the list while syncing.
+        let mut lock = match &cli.command {
+            Commands::Server { .. } => { None }
+            _ => { MtdApp::lock(&conf)? }
+        };
+
+        let server = matches!(cli.command, Commands::Server { .. });
+        let app = MtdApp::init(conf)?.handle_command(cli.command, lock.as_ref())?;
+
+        if lock.is_none() {
+            lock = MtdApp::lock(&app.conf)?;
+        }
+        // A server saves its items after every sync. Saving them again would overwrite the changes
+        // other processes have saved since, so only a new list is saved.
+        if !server || app.conf.save_location().is_some_and(|path| !path.exists()) {
+            app.save()?;
         }
+        drop(lock);
+
+        Ok(())
+    }
 
-        if let Some(path) = app.conf.save_location() {
-            if !path.exists() {
-                if let Some(parent) = path.parent() {
-                    fs::create_dir_all(parent)?;
+    /// Encrypts the items saved before data encryption was enabled, and the replica of a shared
+    /// folder this device syncs with.
+    fn encrypt_data(mut conf: Config) -> Result<()> {
+        if conf.data_encryption().is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The config doesn't enable data encryption.").into());
+        }
+        MtdApp::read_data_password(&mut conf)?;
+
+        let _lock = MtdApp::lock(&conf)?;
+        let path = match conf.save_location() {
+            Some(path) => { path }
+            None => { return Ok(()); }
+        };
+        conf.encrypt_saved_data(path)?;
+
+        if let Some(folder) = conf.socket_addr().strip_prefix("folder:") {
+            if let Some(list) = MtdApp::load(path, |data| TdList::new_from_json(&conf.decode_data(data)?))? {
+                list.encrypt_folder_replica(Path::new(folder), &conf)?;
+            }
+        }
+
+        println!("Saved items are encrypted.");
+        Ok(())
+    }
+
+    /// Locks the save location of the list so that other mtd processes have to wait before using it.
+    fn lock(conf: &Config) -> Result<Option<FileLock>> {
+        match conf.save_location() {
+            Some(path) => {
+                if !path.exists() {
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
                 }
+                Ok(Some(FileLock::acquire(path, conf.timeout())?))
             }
-            fs::write(path, app.list.to_json()?)?;
+            None => { Ok(None) }
         }
+    }
 
+    /// Writes the list to the save location if one is defined.
+    fn save(&self) -> Result<()> {
+        if let Some(path) = self.conf.save_location() {
+            save_atomic(path, &self.conf.encode_data(&self.list.to_json()?)?)?;
+        }
         Ok(())
     }
 
-    // Needs to take ownership because syncing needs ownership
-    fn handle_command(mut self, command: Commands) -> Result<Self> {
+    // Needs to take ownership because syncing needs ownership. The lock of the save location is given
+    // if it is held.
+    fn handle_command(mut self, command: Commands, lock: Option<&FileLock>) -> Result<Self> {
         match command {
-            Commands::Show { item_type, weekday, week } => {
-                self.show(item_type, weekday, week);
+            Commands::Show { item_type, weekday, date, week } => {
+                self.show(item_type, weekday, date, week);
             }
-            Commands::Add { item_type, weekdays, body } => {
-                self.add(item_type, weekdays, body);
+            Commands::Add { item_type, days, body, recurrence } => {
+                match recurrence.to_recurrence() {
+                    Some(r) if item_type == ItemType::Task => {
+                        self.add_recurring_task(days, body, r);
+                    }
+                    _ => {
+                        self.add(item_type, days, body);
+                    }
+                }
             }
             Commands::Remove { item_type, id } => {
                 self.remove(item_type, id)?;
@@ -388,17 +837,31 @@ impl MtdApp {
             Commands::Undo { item_type, id } => {
                 self.modify_done_state(item_type, id, false)?;
             }
-            Commands::Set { item_type, id, body, weekdays } => {
-                self.set(item_type, id, body, weekdays)?;
+            Commands::Set { item_type, id, body, weekdays, date, recurrence } => {
+                self.set(item_type, id, body, weekdays, date)?;
+                if let (ItemType::Task, Some(r)) = (item_type, recurrence.to_recurrence()) {
+                    self.list.get_task_mut(id)?.set_recurrence(r);
+                }
+            }
+            Commands::Stats { weeks, period, json } => {
+                self.stats(weeks, period.into(), json)?;
+            }
+            Commands::Sync { verbose } => {
+                let report = self.sync()?;
+                MtdApp::print_sync_report(&report, "Server", verbose);
             }
-            Commands::Sync {} => {
-                self.sync()?;
+            Commands::Peer { addr, listen, verbose } => {
+                let report = self.peer(&addr, listen)?;
+                MtdApp::print_sync_report(&report, "Peer", verbose);
             }
-            Commands::Server {} => {
-                self.server()?;
+            Commands::Server { daemon, pidfile, log_file } => {
+                self.run_server(daemon, pidfile.as_deref(), log_file.as_deref())?;
             }
-            // Re-init is handled earlier
-            Commands::ReInit {} => {}
+            Commands::ServeStdio => {
+                self.serve_stdio(lock)?;
+            }
+            // Re-init and encrypt-data are handled earlier
+            Commands::EncryptData | Commands::ReInit => {}
         }
 
         if self.conf.local_only() {
@@ -408,7 +871,7 @@ impl MtdApp {
         Ok(self)
     }
 
-    fn show(&self, item_type: Option<ItemType>, weekday_opt: Option<Weekday>, week: bool) {
+    fn show(&self, item_type: Option<ItemType>, weekday_opt: Option<Weekday>, date_opt: Option<NaiveDate>, week: bool) {
         // If item type is None, show everything.
         let show_todos = item_type.is_none() || item_type.unwrap() == ItemType::Todo;
         let show_tasks = item_type.is_none() || item_type.unwrap() == ItemType::Task;
@@ -428,23 +891,28 @@ impl MtdApp {
                     break;
                 }
             }
+        } else if let Some(date) = date_opt {
+            self.print_date(date, show_todos, show_tasks);
         } else {
-            let weekday: chrono::Weekday;
-
             // If cli arg weekday is unspecified show today's weekday.
-            if let Some(wd) = weekday_opt {
-                weekday = wd.into();
-            } else {
-                weekday = Local::today().weekday();
-            }
+            let weekday = match weekday_opt {
+                Some(wd) => { wd.into() }
+                None => { Local::today().weekday() }
+            };
 
             self.print_date(mtd::weekday_to_date(weekday), show_todos, show_tasks);
         }
     }
 
     fn print_date(&self, date: NaiveDate, show_todos: bool, show_tasks: bool) {
-        // Print weekday in yellow
-        println!("\x1B[33m{}:\x1B[39m", date.weekday().to_string().to_uppercase());
+        // Print weekday in yellow. Dates outside the upcoming week are ambiguous with only a weekday
+        // so print the full date for them as well.
+        let days_from_today = (date - Local::today().naive_local()).num_days();
+        if (0..7).contains(&days_from_today) {
+            println!("\x1B[33m{}:\x1B[39m", date.weekday().to_string().to_uppercase());
+        } else {
+            println!("\x1B[33m{} {}:\x1B[39m", date.weekday().to_string().to_uppercase(), date);
+        }
         if show_todos {
             let undone_todos = self.list.undone_todos_for_date(date);
             let done_todos = self.list.done_todos_for_date(date);
@@ -485,29 +953,87 @@ impl MtdApp {
         }
     }
 
-    fn add(&mut self, item_type: ItemType, weekdays: Vec<Weekday>, body: String) {
-        let mut chrono_weekdays: Vec<chrono::Weekday> = Vec::new();
-        for wd in weekdays {
-            chrono_weekdays.push(wd.into());
+    fn stats(&self, weeks: u32, period: Period, json: bool) -> Result<()> {
+        let today = Local::today().naive_local();
+        let from = today - chrono::Duration::weeks(weeks.into()) + chrono::Duration::days(1);
+        let stats = self.list.stats(from, today, period);
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            MtdApp::print_stats(&stats);
+        }
+        Ok(())
+    }
+
+    fn print_stats(stats: &Stats) {
+        // Print headers as green like in show.
+        println!("\x1B[32mTasks from {} to {}:\x1B[39m", stats.from(), stats.to());
+        if stats.tasks().is_empty() {
+            println!("\tNo tasks.");
+        } else {
+            println!("\t{:>5} {:>5} {:>7} {:>7}  Task", "Done", "Rate", "Streak", "Longest");
+            for task in stats.tasks() {
+                let occurrences = task.occurrences();
+                println!("\t{:>5} {:>5} {:>7} {:>7}  {}. {}",
+                         format!("{}/{}", occurrences.done(), occurrences.total() - occurrences.skipped()),
+                         format_rate(task.completion_rate()),
+                         task.current_streak(),
+                         task.longest_streak(),
+                         task.id(),
+                         task.body());
+            }
+        }
+
+        println!("\x1B[32mTasks by weekday:\x1B[39m");
+        for weekday in stats.weekdays() {
+            let occurrences = weekday.occurrences();
+            println!("\t{} {:>5} {:>5}",
+                     weekday.weekday().to_string().to_uppercase(),
+                     format!("{}/{}", occurrences.done(), occurrences.total() - occurrences.skipped()),
+                     format_rate(weekday.completion_rate()));
+        }
+
+        println!("\x1B[32mDone todos:\x1B[39m");
+        for period in stats.todos() {
+            let dates = if period.start() == period.end() {
+                period.start().to_string()
+            } else {
+                format!("{} - {}", period.start(), period.end())
+            };
+            println!("\t{:<23} {:>3}", dates, period.todos_done());
         }
+    }
 
-        // If no weekdays are specified, add today's weekday.
-        if chrono_weekdays.is_empty() {
-            chrono_weekdays.push(Local::today().weekday());
+    fn add(&mut self, item_type: ItemType, mut days: Vec<Day>, body: String) {
+        // If no days are specified, add today.
+        if days.is_empty() {
+            days.push(Day::Date(Local::today().naive_local()));
         }
 
         match item_type {
             ItemType::Todo => {
-                for day in chrono_weekdays {
-                    self.list.add_todo(Todo::new_dated(body.clone(), day));
+                for day in days {
+                    self.list.add_todo(Todo::new_for_date(body.clone(), day.to_date()));
                 }
             }
             ItemType::Task => {
-                self.list.add_task(Task::new(body, chrono_weekdays));
+                self.list.add_task(Task::new(body, days.into_iter().map(Day::weekday).collect()));
             }
         }
     }
 
+    fn add_recurring_task(&mut self, days: Vec<Day>, body: String, recurrence: Recurrence) {
+        let mut weekdays: Vec<chrono::Weekday> = days.into_iter().map(Day::weekday).collect();
+
+        // Weekly tasks without weekdays would never occur so use the weekday of the start date.
+        if weekdays.is_empty() && recurrence.frequency() == Frequency::Weekly {
+            weekdays.push(recurrence.start().weekday());
+        }
+
+        self.list.add_task(Task::new_recurring(body, weekdays, recurrence));
+    }
+
     fn remove(&mut self, item_type: ItemType, id: u64) -> Result<()> {
         match item_type {
             ItemType::Todo => {
@@ -527,17 +1053,15 @@ impl MtdApp {
             }
             ItemType::Task => {
                 let task = self.list.get_task_mut(id)?;
-                let mut next_date_for_task = Local::today().naive_local();
-                while !task.for_date(next_date_for_task) {
-                    next_date_for_task = next_date_for_task.succ();
+                if let Some(next_date_for_task) = task.next_date(Local::today().naive_local()) {
+                    task.set_done(to_done, next_date_for_task);
                 }
-                task.set_done(to_done, next_date_for_task);
             }
         }
         Ok(())
     }
 
-    fn set(&mut self, item_type: ItemType, id: u64, body: Option<String>, weekdays: Vec<Weekday>) -> Result<()> {
+    fn set(&mut self, item_type: ItemType, id: u64, body: Option<String>, weekdays: Vec<Weekday>, date: Option<NaiveDate>) -> Result<()> {
         let mut chrono_weekdays: Vec<chrono::Weekday> = Vec::new();
         for wd in weekdays {
             chrono_weekdays.push(wd.into());
@@ -549,7 +1073,9 @@ impl MtdApp {
                 if let Some(b) = body {
                     todo.set_body(b);
                 }
-                if chrono_weekdays.len() >= 1 {
+                if let Some(d) = date {
+                    todo.set_date(d);
+                } else if !chrono_weekdays.is_empty() {
                     todo.set_weekday(chrono_weekdays[0]);
                 }
             }
@@ -558,7 +1084,9 @@ impl MtdApp {
                 if let Some(b) = body {
                     task.set_body(b);
                 }
-                if chrono_weekdays.len() >= 1 {
+                if let Some(d) = date {
+                    task.set_weekdays(vec![d.weekday()]);
+                } else if !chrono_weekdays.is_empty() {
                     task.set_weekdays(chrono_weekdays);
                 }
             }
@@ -567,23 +1095,207 @@ impl MtdApp {
         Ok(())
     }
 
-    fn sync(&mut self) -> Result<()> {
+    fn sync(&mut self) -> Result<SyncReport> {
+        self.sync_with(|net_mgr| net_mgr.client_sync())
+    }
+
+    // Runs a sync with the net manager of the list, so tests can sync over other transports.
+    fn sync_with(&mut self, sync: impl FnOnce(&mut MtdNetMgr) -> Result<SyncReport>) -> Result<SyncReport> {
+        self.conf.read_password(|| rpassword::prompt_password("Input encryption password: "))?;
+        self.conf.read_end_to_end_password(|| rpassword::prompt_password("Input end-to-end encryption password: "))?;
+        let conf = &self.conf;
+
+        let mut net_mgr = MtdNetMgr::new(&mut self.list, conf);
+
+        sync(&mut net_mgr)
+    }
+
+    fn peer(&mut self, addr: &str, listen: bool) -> Result<SyncReport> {
+        if listen {
+            self.peer_with(|net_mgr| {
+                println!("Waiting for a peer to connect to {}...", addr);
+                net_mgr.peer_listen(addr)
+            })
+        } else {
+            self.peer_with(|net_mgr| net_mgr.peer_sync(addr))
+        }
+    }
+
+    // Runs a peer sync with the net manager of the list, so tests can sync over other transports.
+    fn peer_with(&mut self, sync: impl FnOnce(&mut MtdNetMgr) -> Result<SyncReport>) -> Result<SyncReport> {
+        self.conf.read_password(|| rpassword::prompt_password("Input encryption password: "))?;
         let conf = &self.conf;
 
         let mut net_mgr = MtdNetMgr::new(&mut self.list, conf);
 
-        net_mgr.client_sync()
+        sync(&mut net_mgr)
+    }
+
+    // Remote is the name of the other side of the synchronization.
+    fn print_sync_report(report: &SyncReport, remote: &str, verbose: bool) {
+        if report.is_empty() {
+            println!("Everything is up to date.");
+            return;
+        }
+
+        MtdApp::print_list_report("Todos", report.todos(), remote, verbose);
+        MtdApp::print_list_report("Tasks", report.tasks(), remote, verbose);
+    }
+
+    fn print_list_report(name: &str, report: &ListReport, remote: &str, verbose: bool) {
+        if report.is_empty() {
+            return;
+        }
+
+        // Print header as green
+        println!("\x1B[32m{}:\x1B[39m", name);
+
+        let sides = [
+            ("Local", report.added_to_client(), report.updated_on_client(), report.removed_from_client()),
+            (remote, report.added_to_server(), report.updated_on_server(), report.removed_from_server()),
+        ];
+
+        for (side, added, updated, removed) in sides {
+            println!("\t{}: {} added, {} updated, {} removed", side, added.len(), updated.len(), removed.len());
+            if verbose {
+                MtdApp::print_report_items('+', added);
+                MtdApp::print_report_items('~', updated);
+                MtdApp::print_report_items('-', removed);
+            }
+        }
+
+        if !report.conflicts().is_empty() {
+            println!("\tConflicts resolved: {}", report.conflicts().len());
+            if verbose {
+                for conflict in report.conflicts() {
+                    let kept = match conflict.resolution() {
+                        Resolution::KeptClient => { "local".to_string() }
+                        Resolution::KeptServer => { remote.to_lowercase() }
+                    };
+                    println!("\t\t{} ({}): kept the {} modification", conflict.item().body(), conflict.field(), kept);
+                }
+            }
+        }
+    }
+
+    fn print_report_items(prefix: char, items: &[ReportItem]) {
+        for item in items {
+            println!("\t\t{} {}", prefix, item.body());
+        }
+    }
+
+    fn run_server(&mut self, daemon: bool, pidfile: Option<&Path>, log_file: Option<&Path>) -> Result<()> {
+        // The password is read before detaching, since a daemon cannot be prompted.
+        self.conf.read_password(|| rpassword::prompt_password("Input encryption password: "))?;
+        // A daemon changes its working directory, so a relative pidfile is resolved before that.
+        let pidfile = match pidfile {
+            Some(path) => { Some(env::current_dir()?.join(path)) }
+            None => { None }
+        };
+        if daemon {
+            MtdApp::daemonize(log_file)?;
+        }
+        if let Some(path) = &pidfile {
+            fs::write(path, format!("{}\n", process::id()))?;
+        }
+
+        let result = self.server();
+
+        // Failing to remove the pidfile must not hide how the server stopped.
+        if let Some(path) = &pidfile {
+            if let Err(e) = fs::remove_file(path) {
+                eprintln!("Cannot remove pidfile '{}': {}", path.display(), e);
+            }
+        }
+
+        result
+    }
+
+    fn serve_stdio(&mut self, lock: Option<&FileLock>) -> Result<()> {
+        self.conf.read_password(|| rpassword::prompt_password("Input encryption password: "))?;
+        let conf = &self.conf;
+
+        MtdNetMgr::new(&mut self.list, conf).serve_stream(StdioStream::new(), lock)
     }
 
     fn server(&mut self) -> Result<()> {
+        self.server_with(|net_mgr| net_mgr.server_listening_loop())
+    }
+
+    // Runs the server loop with the net manager of the list, so tests can serve over other
+    // transports.
+    fn server_with(&mut self, listen: impl FnOnce(&mut MtdNetMgr) -> Result<()>) -> Result<()> {
         let conf = &self.conf;
 
-        let mut net_mgr = MtdNetMgr::new(&mut self.list, &conf);
+        let mut net_mgr = MtdNetMgr::new(&mut self.list, conf);
+
+        // The first signal lets the server finish the current syncs and exit normally. The list has
+        // already been saved after every sync. A second signal exits immediately.
+        let shutdown_flag = net_mgr.shutdown_handle().flag();
+        for &signal in TERM_SIGNALS {
+            signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown_flag.clone())?;
+            signal_hook::flag::register(signal, shutdown_flag.clone())?;
+        }
+
+        listen(&mut net_mgr)?;
+        println!("Server stopped.");
+
+        Ok(())
+    }
+
+    /// Detaches the process from the terminal and continues running in the background. Output is
+    /// written to the log file if one is given and discarded otherwise.
+    #[cfg(unix)]
+    fn daemonize(log_file: Option<&Path>) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        // Files are opened before forking so that errors are still shown in the terminal.
+        let log = match log_file {
+            Some(path) => { fs::OpenOptions::new().create(true).append(true).open(path)? }
+            None => { fs::OpenOptions::new().write(true).open("/dev/null")? }
+        };
+        let dev_null = fs::File::open("/dev/null")?;
+
+        // Forking twice makes sure that the daemon can never acquire a controlling terminal. This is
+        // done before any other threads are started.
+        unsafe {
+            match libc::fork() {
+                -1 => { return Err(io::Error::last_os_error().into()); }
+                0 => {}
+                _ => { process::exit(0); }
+            }
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            match libc::fork() {
+                -1 => { return Err(io::Error::last_os_error().into()); }
+                0 => {}
+                _ => { process::exit(0); }
+            }
+
+            // The daemon must not keep the directory it was started in busy, and must not inherit a
+            // umask that would make the saved items readable by others.
+            if libc::chdir(c"/".as_ptr()) == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+            libc::umask(0o077);
+
+            if libc::dup2(dev_null.as_raw_fd(), libc::STDIN_FILENO) == -1 ||
+                libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1 ||
+                libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+        }
+
+        Ok(())
+    }
 
-        net_mgr.server_listening_loop()
+    #[cfg(not(unix))]
+    fn daemonize(_log_file: Option<&Path>) -> Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Daemon mode is only supported on Unix.").into())
     }
 
-    fn re_init(config_path: &PathBuf) -> Result<Self> {
+    fn re_init(config_path: &Path) -> Result<Self> {
         let stdin = io::stdin();
         let mut stdout = io::stdout();
 
@@ -611,7 +1323,8 @@ impl MtdApp {
             // return Ok(MtdApp::new(config_path)?);
         }
 
-        let config = MtdApp::create_new_config(&config_path)?;
+        let mut config = MtdApp::create_new_config(config_path)?;
+        MtdApp::read_data_password(&mut config)?;
 
         Ok(Self {
             list: MtdApp::create_new_list(&config)?,
@@ -622,14 +1335,15 @@ impl MtdApp {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::mpsc;
     use std::thread;
     use std::time::Duration;
 
-    use chrono::{Datelike, Local};
+    use chrono::{Datelike, Local, NaiveDate};
 
-    use mtd::{Config, Task, TdList, Todo};
+    use mtd::{Config, MemoryTransport, Task, TdList, Todo};
 
-    use crate::{Commands, ItemType, MtdApp, Weekday};
+    use crate::{Commands, Day, format_rate, ItemType, MtdApp, NthWeekday, RecurrenceArgs, Repeat, Weekday};
 
     fn create_client_app() -> MtdApp {
         MtdApp {
@@ -648,14 +1362,14 @@ mod tests {
     #[test]
     fn add_adds_todo_successfully() {
         let mut client = create_client_app();
-        client.add(ItemType::Todo, vec![Weekday::Wed], "Todo".to_string());
+        client.add(ItemType::Todo, vec![Day::Weekday(Weekday::Wed)], "Todo".to_string());
         assert_eq!(client.list.todos()[0], &Todo::new_dated("Todo".to_string(), chrono::Weekday::Wed));
     }
 
     #[test]
     fn add_adds_task_successfully() {
         let mut client = create_client_app();
-        client.add(ItemType::Task, vec![Weekday::Wed, Weekday::Fri, Weekday::Sun], "Task".to_string());
+        client.add(ItemType::Task, vec![Day::Weekday(Weekday::Wed), Day::Weekday(Weekday::Fri), Day::Weekday(Weekday::Sun)], "Task".to_string());
         assert_eq!(client.list.tasks()[0], &Task::new("Task".to_string(), vec![chrono::Weekday::Wed, chrono::Weekday::Fri, chrono::Weekday::Sun]))
     }
 
@@ -669,12 +1383,56 @@ mod tests {
     #[test]
     fn add_adds_todo_to_multiple_weekdays() {
         let mut client = create_client_app();
-        client.add(ItemType::Todo, vec![Weekday::Wed, Weekday::Fri, Weekday::Sun], "Todo".to_string());
+        client.add(ItemType::Todo, vec![Day::Weekday(Weekday::Wed), Day::Weekday(Weekday::Fri), Day::Weekday(Weekday::Sun)], "Todo".to_string());
         assert_eq!(client.list.todos()[0], &Todo::new_dated("Todo".to_string(), chrono::Weekday::Wed));
         assert_eq!(client.list.todos()[1], &Todo::new_dated("Todo".to_string(), chrono::Weekday::Fri));
         assert_eq!(client.list.todos()[2], &Todo::new_dated("Todo".to_string(), chrono::Weekday::Sun));
     }
 
+    #[test]
+    fn add_adds_todo_for_far_future_date() {
+        let mut client = create_client_app();
+        let date = NaiveDate::from_ymd(2035, 12, 24);
+        client.add(ItemType::Todo, vec![Day::Date(date)], "Todo".to_string());
+        assert_eq!(client.list.todos()[0], &Todo::new_for_date("Todo".to_string(), date));
+    }
+
+    #[test]
+    fn day_parses_weekdays_and_iso_dates() {
+        assert_eq!("fri".parse::<Day>().unwrap(), Day::Weekday(Weekday::Fri));
+        assert_eq!("2030-02-01".parse::<Day>().unwrap(), Day::Date(NaiveDate::from_ymd(2030, 2, 1)));
+        assert!("next week".parse::<Day>().is_err());
+    }
+
+    #[test]
+    fn format_rate_rounds_to_percent() {
+        assert_eq!(format_rate(Some(2.0 / 3.0)), "67%");
+        assert_eq!(format_rate(Some(1.0)), "100%");
+        assert_eq!(format_rate(None), "-");
+    }
+
+    #[test]
+    fn add_recurring_task_uses_recurrence_args() {
+        let mut client = create_client_app();
+        let args = RecurrenceArgs {
+            repeat: Some(Repeat::Monthly),
+            start: Some(NaiveDate::from_ymd(2022, 1, 1)),
+            nth: vec![NthWeekday(1, Weekday::Mon)],
+            ..RecurrenceArgs::default()
+        };
+        client.add_recurring_task(vec![], "Task".to_string(), args.to_recurrence().unwrap());
+
+        assert!(client.list.tasks()[0].for_date(NaiveDate::from_ymd(2022, 7, 4)));
+        assert!(!client.list.tasks()[0].for_date(NaiveDate::from_ymd(2022, 7, 11)));
+    }
+
+    #[test]
+    fn nth_weekday_parses_negative_and_rejects_zero() {
+        assert_eq!("-1:fri".parse::<NthWeekday>().unwrap(), NthWeekday(-1, Weekday::Fri));
+        assert!("0:fri".parse::<NthWeekday>().is_err());
+        assert!("2fri".parse::<NthWeekday>().is_err());
+    }
+
     #[test]
     fn remove_removes_todo_successfully() {
         let mut client = create_client_app();
@@ -711,15 +1469,23 @@ mod tests {
     fn set_sets_todo_values_to_new() {
         let mut client = create_client_app();
         client.list.add_todo(Todo::new_dated("Todo".to_string(), chrono::Weekday::Sun));
-        client.set(ItemType::Todo, 0, Some("New Todo".to_string()), vec![Weekday::Wed]).unwrap();
+        client.set(ItemType::Todo, 0, Some("New Todo".to_string()), vec![Weekday::Wed], None).unwrap();
         assert_eq!(client.list.todos()[0], &Todo::new_dated("New Todo".to_string(), chrono::Weekday::Wed));
     }
 
+    #[test]
+    fn set_sets_todo_date_to_new() {
+        let mut client = create_client_app();
+        client.list.add_todo(Todo::new_dated("Todo".to_string(), chrono::Weekday::Sun));
+        client.set(ItemType::Todo, 0, None, vec![], Some(NaiveDate::from_ymd(2035, 1, 1))).unwrap();
+        assert_eq!(client.list.todos()[0].date(), NaiveDate::from_ymd(2035, 1, 1));
+    }
+
     #[test]
     fn set_sets_task_values_to_new() {
         let mut client = create_client_app();
         client.list.add_task(Task::new("Task".to_string(), vec![chrono::Weekday::Sun]));
-        client.set(ItemType::Task, 0, Some("New Task".to_string()), vec![Weekday::Thu, Weekday::Fri]).unwrap();
+        client.set(ItemType::Task, 0, Some("New Task".to_string()), vec![Weekday::Thu, Weekday::Fri], None).unwrap();
         assert_eq!(client.list.tasks()[0], &Task::new("New Task".to_string(), vec![chrono::Weekday::Thu, chrono::Weekday::Fri]))
     }
 
@@ -727,7 +1493,7 @@ mod tests {
     fn set_doesnt_modify_weekday_without_explicit_set() {
         let mut client = create_client_app();
         client.list.add_todo(Todo::new_dated("Todo".to_string(), chrono::Weekday::Sun));
-        client.set(ItemType::Todo, 0, Some("New Todo".to_string()), vec![]).unwrap();
+        client.set(ItemType::Todo, 0, Some("New Todo".to_string()), vec![], None).unwrap();
         assert_eq!(client.list.todos()[0], &Todo::new_dated("New Todo".to_string(), chrono::Weekday::Sun));
     }
 
@@ -735,10 +1501,32 @@ mod tests {
     fn set_doesnt_modify_body_without_explicit_set() {
         let mut client = create_client_app();
         client.list.add_task(Task::new("Task".to_string(), vec![chrono::Weekday::Sun]));
-        client.set(ItemType::Task, 0, None, vec![Weekday::Thu, Weekday::Fri]).unwrap();
+        client.set(ItemType::Task, 0, None, vec![Weekday::Thu, Weekday::Fri], None).unwrap();
         assert_eq!(client.list.tasks()[0], &Task::new("Task".to_string(), vec![chrono::Weekday::Thu, chrono::Weekday::Fri]))
     }
 
+    #[test]
+    fn peer_syncing_works() {
+        let transport = MemoryTransport::new();
+        let listener_transport = transport.clone();
+
+        let listener = thread::spawn(move || {
+            let mut desktop = create_client_app();
+            desktop.list.add_todo(Todo::new_undated("Todo".to_string()));
+            desktop.peer_with(|net_mgr| net_mgr.peer_listen_over(&listener_transport)).unwrap();
+            desktop
+        });
+
+        let mut laptop = create_client_app();
+        laptop.list.add_task(Task::new("Task".to_string(), vec![chrono::Weekday::Mon]));
+        laptop.peer_with(|net_mgr| net_mgr.peer_sync_over(&transport)).unwrap();
+
+        let desktop = listener.join().unwrap();
+
+        assert_eq!(laptop.list.todos().len(), 1);
+        assert_eq!(desktop.list.tasks().len(), 1);
+    }
+
     #[test]
     fn sync_as_server_fails() {
         assert!(create_server_app().sync().is_err());
@@ -751,17 +1539,25 @@ mod tests {
 
     #[test]
     fn syncing_works() {
-        thread::spawn(|| {
+        let transport = MemoryTransport::new();
+        let server_transport = transport.clone();
+        let (tx, rx) = mpsc::channel();
+
+        let server = thread::spawn(move || {
             let mut server = create_server_app();
             server.list.add_todo(Todo::new_undated("Todo".to_string()));
-            server.server().unwrap();
+            server.server_with(|net_mgr| {
+                tx.send(net_mgr.shutdown_handle()).unwrap();
+                net_mgr.server_listening_loop_over(&server_transport)
+            }).unwrap();
         });
-
-        // Give server time to init
-        thread::sleep(Duration::from_millis(500));
+        let handle = rx.recv().unwrap();
 
         let mut client = create_client_app();
-        client.sync().unwrap();
+        client.sync_with(|net_mgr| net_mgr.client_sync_over(&transport)).unwrap();
+
+        handle.shutdown();
+        server.join().unwrap();
 
         assert_eq!(client.list.todos().len(), 1);
         assert!(client.list.todos().contains(&&Todo::new_undated("Todo".to_string())));
@@ -784,8 +1580,28 @@ mod tests {
         // Do assert here to first check that the save format hasn't changed and will contain the todo in cleartext.
         assert!(app.list.to_json().unwrap().contains("This string doesn't remain if the todo is actually removed."));
 
-        let app = app.handle_command(Commands::Remove { item_type: ItemType::Todo, id: 0 }).unwrap();
+        let app = app.handle_command(Commands::Remove { item_type: ItemType::Todo, id: 0 }, None).unwrap();
 
         assert!(!app.list.to_json().unwrap().contains("This string doesn't remain if the todo is actually removed."));
     }
+
+    #[test]
+    fn local_only_remove_doesnt_change_ids_of_other_items() {
+        let mut app = MtdApp {
+            list: TdList::new_client(),
+            conf: Config::new(
+                "127.0.0.1:55995".to_string(),
+                "pw".as_bytes().to_vec(),
+                Duration::from_secs(30),
+                None,
+                true,
+            ),
+        };
+        app.list.add_todo(Todo::new_undated("Todo 0".to_string()));
+        app.list.add_todo(Todo::new_undated("Todo 1".to_string()));
+
+        let mut app = app.handle_command(Commands::Remove { item_type: ItemType::Todo, id: 0 }, None).unwrap();
+
+        assert_eq!(app.list.get_todo_mut(1).unwrap().body(), "Todo 1");
+    }
 }
\ No newline at end of file
@@ -17,17 +17,50 @@ see <https://www.gnu.org/licenses/>.
 //! A Module defining networking functions for MTD such as syncing with a remote server or running a
 //! server. Data transmitted over the network is encrypted.
 
-use std::{fs, io};
+use std::{fs, io, thread};
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 
 use rand::random;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::{Error, Result, TdList};
-use crate::network::crypt::{decrypt, encrypt};
+use subtle::ConstantTimeEq;
+
+use crate::{backup_path, CommandTransport, DerivedKey, Error, FileLock, Listener, load_with_backup, Result, save_atomic, schema, SealedList, SecretSource, storage, Stream, SyncReport, TcpTransport, TD_LIST_SCHEMA_VERSION, TdList, Transport};
+#[cfg(unix)]
+use crate::UnixTransport;
+use crate::delta::{DeltaRequest, DeltaResponse};
+use crate::network::crypt::{derive_key, session_keys, SessionCipher, transcript_hash};
+
+/// The version of the wire protocol used for synchronization. Both the client and the server need to
+/// use the same version.
+pub const PROTOCOL_VERSION: u16 = 4;
+
+/// Bytes that start both handshake hello messages.
+const HELLO_MAGIC: &[u8; 4] = b"MTD\0";
+
+/// How often `server_listening_loop` checks whether it should shut down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The maximum number of connections a server handles at the same time. Further connections wait
+/// to be accepted until one of the current connections has finished.
+const MAX_CONNECTIONS: usize = 32;
+
+/// The prefix of socket addresses that are paths of Unix domain sockets.
+const UNIX_SOCKET_PREFIX: &str = "unix:";
+
+/// The prefix of socket addresses that are commands to sync through.
+const COMMAND_PREFIX: &str = "command:";
+
+/// The prefix of socket addresses that are shared folders to sync with.
+const FOLDER_PREFIX: &str = "folder:";
+
+/// The maximum size of the unencrypted handshake messages.
+const MAX_HANDSHAKE_FRAME_SIZE: u32 = 64;
 
 /// A config specifying how a `MtdNetMgr` should function. Defining a `save_location` is optional.
 /// If it is `None` any `TdList` won't be saved.
@@ -38,12 +71,53 @@ pub struct Config {
     timeout: Duration,
     save_location: Option<PathBuf>,
     local_only: bool,
+    #[serde(default)]
+    limits: Limits,
+    #[serde(default)]
+    password_source: Option<SecretSource>,
+    #[serde(default)]
+    derived_key: Option<DerivedKey>,
+    // A password read from the password source is only kept in memory.
+    #[serde(skip)]
+    sourced_password: Option<Vec<u8>>,
+    #[serde(default)]
+    data_encryption: Option<SecretSource>,
+    // The data encryption password is only kept in memory after it has been read from its source.
+    #[serde(skip)]
+    data_password: Option<Vec<u8>>,
+    #[serde(default)]
+    end_to_end: Option<SecretSource>,
+    // The end-to-end encryption password is only kept in memory after it has been read.
+    #[serde(skip)]
+    end_to_end_password: Option<Vec<u8>>,
+    #[serde(default = "default_tombstone_expiry")]
+    tombstone_expiry: Duration,
+}
+
+/// Removed items are remembered for about half a year by default.
+fn default_tombstone_expiry() -> Duration {
+    Duration::from_secs(180 * 24 * 60 * 60)
 }
 
 impl Config {
     /// Creates a new `Config` with explicit values.
     pub fn new(socket_addr: String, encryption_password: Vec<u8>, timeout: Duration, save_location: Option<PathBuf>, local_only: bool) -> Self {
-        Self { socket_addr, encryption_password, timeout, save_location, local_only }
+        Self {
+            socket_addr,
+            encryption_password,
+            timeout,
+            save_location,
+            local_only,
+            limits: Limits::default(),
+            password_source: None,
+            derived_key: None,
+            sourced_password: None,
+            data_encryption: None,
+            data_password: None,
+            end_to_end: None,
+            end_to_end_password: None,
+            tombstone_expiry: default_tombstone_expiry(),
+        }
     }
     /// Creates a new `Config` with default values.
     pub fn new_default(encryption_password: Vec<u8>, socket_addr: String, save_location: Option<PathBuf>) -> Self {
@@ -53,23 +127,86 @@ impl Config {
             timeout: Duration::from_secs(30),
             save_location,
             local_only: false,
+            limits: Limits::default(),
+            password_source: None,
+            derived_key: None,
+            sourced_password: None,
+            data_encryption: None,
+            data_password: None,
+            end_to_end: None,
+            end_to_end_password: None,
+            tombstone_expiry: default_tombstone_expiry(),
         }
     }
     /// Creates a ´Config` from a JSON string.
     pub fn new_from_json(json: &str) -> Result<Self> {
-        Ok(serde_json::from_str(json)?)
+        schema::config_from_json(json)
     }
     /// Creates a JSON string from the `Config`.
     pub fn to_json(&self) -> Result<String> {
-        Ok(serde_json::to_string_pretty(self)?)
+        schema::config_to_json(self)
     }
     /// Returns the `Config`'s socket address.
     pub fn socket_addr(&self) -> &str {
         &self.socket_addr
     }
-    /// Returns the `Config`'s encryption password.
+    /// Returns the `Config`'s encryption password. If the `Config` has a password source, this is
+    /// the password read with `read_password`.
     pub fn encryption_password(&self) -> &Vec<u8> {
-        &self.encryption_password
+        self.sourced_password.as_ref().unwrap_or(&self.encryption_password)
+    }
+    /// Returns the source the encryption password is read from, or `None` if it is stored in the
+    /// `Config`.
+    pub fn password_source(&self) -> Option<&SecretSource> {
+        self.password_source.as_ref()
+    }
+    /// Sets the source the encryption password is read from. `None` uses the password stored in the
+    /// `Config`.
+    pub fn set_password_source(&mut self, source: Option<SecretSource>) {
+        self.password_source = source;
+        self.sourced_password = None;
+    }
+    /// Reads the encryption password from the password source if the `Config` has one. The password
+    /// is never saved. `prompt` is called for asking the password from the user if the source is
+    /// `SecretSource::Prompt`.
+    pub fn read_password(&mut self, prompt: impl FnOnce() -> io::Result<String>) -> Result<()> {
+        if let Some(source) = &self.password_source {
+            self.sourced_password = Some(source.read(prompt)?);
+        }
+        Ok(())
+    }
+    /// Returns the key used instead of the encryption password, if any.
+    pub fn derived_key(&self) -> Option<&DerivedKey> {
+        self.derived_key.as_ref()
+    }
+    /// Sets a key to use instead of the encryption password. The key is used even if the `Config`
+    /// also has a password.
+    pub fn set_derived_key(&mut self, key: Option<DerivedKey>) {
+        self.derived_key = key;
+    }
+    /// Returns the salt and the key a server uses for all connections.
+    fn server_key(&self) -> Result<([u8; 16], [u8; 32])> {
+        match &self.derived_key {
+            Some(key) => { Ok((key.salt(), *key.key())) }
+            None => {
+                let salt: [u8; 16] = random();
+                Ok((salt, derive_key(self.checked_password()?, &salt)?))
+            }
+        }
+    }
+    /// Returns the key a client uses with the salt sent by the server.
+    fn client_key(&self, salt: &[u8]) -> Result<[u8; 32]> {
+        match &self.derived_key {
+            Some(key) if key.salt() == salt => { Ok(*key.key()) }
+            Some(_) => { Err(Error::KeySaltMismatch) }
+            None => { derive_key(self.checked_password()?, salt) }
+        }
+    }
+    fn checked_password(&self) -> Result<&[u8]> {
+        if self.password_source.is_some() && self.sourced_password.is_none() {
+            return Err(Error::SecretUnavailable("The encryption password has not been read.".to_string()));
+        }
+        Ok(self.encryption_password())
     }
     /// Returns the `Config`'s timeout duration.
     pub fn timeout(&self) -> Duration {
@@ -79,57 +216,308 @@ impl Config {
     pub fn save_location(&self) -> Option<&PathBuf> {
         match &self.save_location {
             None => { None }
-            Some(p) => { Some(&p) }
+            Some(p) => { Some(p) }
         }
     }
     /// Returns `true` if mtd should run only locally.
     pub fn local_only(&self) -> bool {
         self.local_only
     }
+    /// Returns the `Config`'s limits for data received over the network.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+    /// Sets the `Config`'s limits for data received over the network.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    /// Returns how long a server or a shared folder remembers removed items. See
+    /// `TdList::expire_tombstones`.
+    pub fn tombstone_expiry(&self) -> Duration {
+        self.tombstone_expiry
+    }
+    /// Sets how long a server or a shared folder remembers removed items.
+    pub fn set_tombstone_expiry(&mut self, tombstone_expiry: Duration) {
+        self.tombstone_expiry = tombstone_expiry;
+    }
+    /// Returns the source of the password used for encrypting the saved `TdList`, or `None` if the
+    /// saved `TdList` isn't encrypted.
+    pub fn data_encryption(&self) -> Option<&SecretSource> {
+        self.data_encryption.as_ref()
+    }
+    /// Sets the source of the password used for encrypting the saved `TdList`. `None` disables the
+    /// encryption.
+    pub fn set_data_encryption(&mut self, source: Option<SecretSource>) {
+        self.data_encryption = source;
+    }
+    /// Sets the password read from the `data_encryption` source. The password is never saved.
+    pub fn set_data_password(&mut self, password: Vec<u8>) {
+        self.data_password = Some(password);
+    }
+    /// Returns the source of the password used for end-to-end encrypting items synced with a server,
+    /// or `None` if the server can read the items.
+    pub fn end_to_end(&self) -> Option<&SecretSource> {
+        self.end_to_end.as_ref()
+    }
+    /// Sets the source of the password used for end-to-end encrypting items synced with a server.
+    /// `None` disables the end-to-end encryption. Only clients use the password.
+    pub fn set_end_to_end(&mut self, source: Option<SecretSource>) {
+        self.end_to_end = source;
+        self.end_to_end_password = None;
+    }
+    /// Reads the end-to-end encryption password from its source if the `Config` has one. The
+    /// password is never saved. `prompt` is called for asking the password from the user if the
+    /// source is `SecretSource::Prompt`.
+    pub fn read_end_to_end_password(&mut self, prompt: impl FnOnce() -> io::Result<String>) -> Result<()> {
+        if let Some(source) = &self.end_to_end {
+            self.end_to_end_password = Some(source.read(prompt)?);
+        }
+        Ok(())
+    }
+    fn checked_end_to_end_password(&self) -> Result<&[u8]> {
+        self.end_to_end_password.as_deref()
+            .ok_or_else(|| Error::SecretUnavailable("The end-to-end encryption password has not been read.".to_string()))
+    }
+    /// Converts a serialized `TdList` to the bytes that should be saved, encrypting it if
+    /// `data_encryption` is set.
+    pub fn encode_data(&self, json: &str) -> Result<Vec<u8>> {
+        match (&self.data_encryption, &self.data_password) {
+            (None, _) => { Ok(json.as_bytes().to_vec()) }
+            (Some(_), Some(password)) => { storage::encrypt_contents(json.as_bytes(), password) }
+            (Some(_), None) => { Err(Error::SecretUnavailable("The data encryption password has not been read.".to_string())) }
+        }
+    }
+    /// Converts saved bytes back to a serialized `TdList`, decrypting them if they are encrypted.
+    /// Returns `Error::UnencryptedData` if `data_encryption` is set but the data isn't encrypted, so
+    /// that data replaced by someone without the password is never loaded. Data saved before the
+    /// encryption was enabled has to be encrypted once with `encrypt_saved_data`.
+    pub fn decode_data(&self, data: &[u8]) -> Result<String> {
+        let plaintext = if storage::is_encrypted(data) {
+            match &self.data_password {
+                Some(password) => { storage::decrypt_contents(data, password)? }
+                None => { return Err(Error::SecretUnavailable("The saved data is encrypted but no data encryption password is configured.".to_string())); }
+            }
+        } else if self.data_encryption.is_some() {
+            return Err(Error::UnencryptedData);
+        } else {
+            data.to_vec()
+        };
+
+        String::from_utf8(plaintext).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e).into())
+    }
+    /// Encrypts a file written with `save_atomic` before `data_encryption` was enabled. The
+    /// unencrypted backup of the file is removed. Files that are already encrypted are kept as they
+    /// are. Does nothing if `data_encryption` isn't set.
+    pub fn encrypt_saved_data(&self, path: &Path) -> Result<()> {
+        if self.data_encryption.is_none() {
+            return Ok(());
+        }
+
+        match fs::read(path) {
+            Ok(data) if !storage::is_encrypted(&data) => {
+                let json = String::from_utf8(data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                save_atomic(path, &self.encode_data(&json)?)?;
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => { return Err(e.into()); }
+        }
+
+        // The backup is the unencrypted version written before.
+        let backup = backup_path(path);
+        match fs::read(&backup) {
+            Ok(data) if !storage::is_encrypted(&data) => { fs::remove_file(&backup)?; }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => { return Err(e.into()); }
+        }
+
+        Ok(())
+    }
+}
+
+/// Limits for data received over the network. Messages exceeding the limits are rejected before
+/// their items are parsed or merged with the local `TdList`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Limits {
+    max_frame_size: u32,
+    max_items: usize,
+    max_body_len: usize,
+    #[serde(default = "default_max_record_len")]
+    max_record_len: usize,
+}
+
+/// End-to-end encrypted items also contain the history of tasks, so records may be considerably
+/// larger than a body.
+fn default_max_record_len() -> usize {
+    1024 * 1024
+}
+
+impl Limits {
+    /// Creates new `Limits`. `max_frame_size` is the maximum size of a single message in bytes,
+    /// `max_items` the maximum number of `Todo`s and `Task`s combined, `max_body_len` the maximum
+    /// length of an item's body in bytes and `max_record_len` the maximum size of a single end-to-end
+    /// encrypted item in bytes.
+    pub fn new(max_frame_size: u32, max_items: usize, max_body_len: usize, max_record_len: usize) -> Self {
+        Self { max_frame_size, max_items, max_body_len, max_record_len }
+    }
+    /// Returns the maximum size of a single message in bytes.
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+    /// Returns the maximum number of `Todo`s and `Task`s combined.
+    pub fn max_items(&self) -> usize {
+        self.max_items
+    }
+    /// Returns the maximum length of an item's body in bytes.
+    pub fn max_body_len(&self) -> usize {
+        self.max_body_len
+    }
+    /// Returns the maximum size of a single end-to-end encrypted item in bytes.
+    pub fn max_record_len(&self) -> usize {
+        self.max_record_len
+    }
+
+    /// Checks that a serialized `TdList` received over the network is within the limits. The list
+    /// is checked before it is parsed, so oversized lists are never built.
+    pub(crate) fn check_list(&self, list: &Value) -> Result<()> {
+        let todos = list_items(list, "todos");
+        let tasks = list_items(list, "tasks");
+
+        let items = todos.len() + tasks.len();
+        if items > self.max_items {
+            return Err(Error::TooManyItems(items, self.max_items));
+        }
+
+        let bodies = todos.iter().chain(tasks).filter_map(|item| item.get("body").and_then(Value::as_str));
+        for body in bodies {
+            if body.len() > self.max_body_len {
+                return Err(Error::BodyTooLong(body.len(), self.max_body_len));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that serialized end-to-end encrypted items received over the network are within the
+    /// limits before they are parsed. The bodies of the items cannot be checked by the server, so
+    /// the size of each encrypted item is checked instead.
+    pub(crate) fn check_sealed(&self, sealed: &Value) -> Result<()> {
+        let records = |kind| sealed.get(kind).and_then(Value::as_array).map_or(&[][..], Vec::as_slice);
+
+        let items = records("todos").len() + records("tasks").len();
+        if items > self.max_items {
+            return Err(Error::TooManyItems(items, self.max_items));
+        }
+
+        let ciphertexts = records("todos").iter().chain(records("tasks")).filter_map(|r| r.get("ciphertext").and_then(Value::as_array));
+        for ciphertext in ciphertexts {
+            if ciphertext.len() > self.max_record_len {
+                return Err(Error::RecordTooLarge(ciphertext.len(), self.max_record_len));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Gets the serialized items of a list of the given kind, or nothing if the list has no items.
+fn list_items<'a>(list: &'a Value, kind: &str) -> &'a [Value] {
+    list.get(kind)
+        .and_then(|l| l.get("items"))
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 * 1024 * 1024,
+            max_items: 100_000,
+            max_body_len: 64 * 1024,
+            max_record_len: default_max_record_len(),
+        }
+    }
 }
 
 /// A struct used for synchronizing `TdList`s between a client and a server over the network. All
-/// transmitted data is encrypted using AES GCM. `MtdNetMgr` can act both as a client and as a server.
-/// After synchronization data is written to the disk both on the server and the client if the config
-/// specifies a `save_location`.
+/// transmitted data is encrypted using AES GCM. The key is derived from the password with Argon2
+/// once per connection on the client and once per `server_listening_loop` on the server, and
+/// separate session keys for both directions are derived from it with HKDF. `MtdNetMgr` can act
+/// both as a client and as a server. Clients only send the items they have modified since their
+/// last sync, and the server merges them and responds with the items changed on it since then. If
+/// the client's `Config` enables end-to-end encryption, the items are additionally encrypted with a
+/// password the server doesn't know, and the server only stores them while the client merges them.
+/// After a synchronization the server writes its `TdList` to the disk if its `Config` specifies a
+/// `save_location`. The client only updates its `TdList` in memory and leaves saving it to the
+/// caller.
 ///
 /// # Example
 ///
 /// ```
-/// use std::net::{IpAddr, Ipv4Addr};
 /// use std::thread;
-/// use std::time::Duration;
-/// use mtd::{Config, MtdNetMgr, TdList, Todo};
+/// use mtd::{Config, MemoryTransport, MtdNetMgr, TdList, Todo};
 ///
 /// let password = b"Very secure password.";
-/// let addr = "127.0.0.1:55995".to_string();
-/// let addr1 = addr.clone();
+/// // Clients and servers usually connect over TCP, but an in-memory transport works within a
+/// // single process.
+/// let transport = MemoryTransport::new();
+/// let server_transport = transport.clone();
 ///
 /// // Create a new thread to act as a server.
 /// thread::spawn(move || {
 ///     let mut server_list = TdList::new_server();
 ///     server_list.add_todo(Todo::new_undated("Todo 1".to_string()));
 ///
-///     let conf = Config::new_default(password.to_vec(), addr, None);
+///     let conf = Config::new_default(password.to_vec(), String::new(), None);
 ///     let mut server_mgr = MtdNetMgr::new(&mut server_list, &conf);
 ///
-///     server_mgr.server_listening_loop().unwrap();
+///     server_mgr.server_listening_loop_over(&server_transport).unwrap();
 /// });
 ///
-/// // Give the server some time to bind to a port etc.
-/// thread::sleep(Duration::from_millis(500));
+/// let mut client_list = TdList::new_client();
 ///
-/// let mut client_list = TdList::new_client();///
-///
-/// let conf = Config::new_default(password.to_vec(), addr1, None);
+/// let conf = Config::new_default(password.to_vec(), String::new(), None);
 /// let mut client_mgr = MtdNetMgr::new(&mut client_list, &conf);
-/// client_mgr.client_sync().unwrap();
+/// client_mgr.client_sync_over(&transport).unwrap();
 ///
 /// assert!(client_list.todos().contains(&&Todo::new_undated("Todo 1".to_string())));
 /// ```
 pub struct MtdNetMgr<'a> {
     td_list: &'a mut TdList,
     config: &'a Config,
+    shutdown: ShutdownHandle,
+}
+
+/// A handle for stopping a running `server_listening_loop`, for example from another thread or a
+/// signal handler. Cloned handles control the same loop.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Creates a new `ShutdownHandle`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests the server to shut down. The server stops accepting new connections and returns
+    /// after the connections being handled have finished.
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if a shutdown has been requested.
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// Returns the flag which is set to `true` when a shutdown is requested. Setting the flag is
+    /// equivalent to calling `shutdown`, which is useful for signal handlers.
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.flag.clone()
+    }
 }
 
 impl<'a> MtdNetMgr<'a> {
@@ -137,12 +525,53 @@ impl<'a> MtdNetMgr<'a> {
     // the value of td_list which isn't easy without ownership.
     /// Creates a new `MtdNetMgr`.
     pub fn new(td_list: &'a mut TdList, config: &'a Config) -> Self {
-        Self { td_list, config }
+        Self { td_list, config, shutdown: ShutdownHandle::new() }
     }
 
-    /// Connects to a server and synchronizes the local `TdList` with a server. Writes the local
-    /// `TdList` if the initialization `Config` defined a `save_location`.
-    pub fn client_sync(&mut self) -> Result<()> {
+    /// Returns a handle that can be used for stopping `server_listening_loop`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Connects to a server and synchronizes the local `TdList` with a server. The local `TdList` is
+    /// not written to the disk, saving it is left to the caller. Returns a `SyncReport` describing
+    /// the changes made to the local and the server `TdList`.
+    ///
+    /// Connects over TCP, or over a Unix domain socket if the socket address of the `Config` starts
+    /// with `unix:`. If the socket address starts with `command:`, the rest of it is run as a shell
+    /// command, such as `ssh host mtd serve-stdio`, and the sync is done over its standard input and
+    /// output. If it starts with `folder:`, the list is synced with the folder at the rest of the
+    /// address using `TdList::sync_folder` instead of a server.
+    ///
+    /// If the sync fails, the local `TdList` is left as it was before the sync, so its unsynced
+    /// changes are sent again on the next sync.
+    pub fn client_sync(&mut self) -> Result<SyncReport> {
+        let addr = self.config.socket_addr();
+
+        if let Some(folder) = addr.strip_prefix(FOLDER_PREFIX) {
+            if self.config.local_only {
+                return Err(Error::OnlineOnlyOperation);
+            }
+            let config = self.config;
+            return self.transaction(|mgr| mgr.td_list.sync_folder(Path::new(folder), config));
+        }
+
+        if let Some(command) = addr.strip_prefix(COMMAND_PREFIX) {
+            return self.client_sync_over(&CommandTransport::new(command.to_string()));
+        }
+
+        match addr.strip_prefix(UNIX_SOCKET_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => { self.client_sync_over(&UnixTransport::new(path.into())) }
+            #[cfg(not(unix))]
+            Some(_) => { Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform.").into()) }
+            None => { self.client_sync_over(&TcpTransport::new(addr.to_string())) }
+        }
+    }
+
+    /// Connects to a server using the given `Transport` and synchronizes the local `TdList` with
+    /// it. See `client_sync`.
+    pub fn client_sync_over<T: Transport>(&mut self, transport: &T) -> Result<SyncReport> {
         if self.config.local_only {
             return Err(Error::OnlineOnlyOperation);
         }
@@ -150,60 +579,240 @@ impl<'a> MtdNetMgr<'a> {
             return Err(Error::ClientOnlyOperation);
         }
 
-        let mut stream = TcpStream::connect(self.config.socket_addr())?;
+        self.transaction(|mgr| {
+            let mut stream = transport.connect()?;
+            stream.set_timeout(mgr.config.timeout())?;
 
-        stream.set_read_timeout(Some(self.config.timeout()))?;
-        stream.set_write_timeout(Some(self.config.timeout()))?;
+            let mut session = mgr.client_handshake(&mut stream)?;
 
-        // Send random data to the server to verify that the server is authentic.
-        let random_auth_data: [u8; 8] = random();
-        self.write_encrypted(&mut stream, &random_auth_data)?;
+            if mgr.config.end_to_end().is_some() {
+                mgr.sync_sealed(&mut session, &mut stream)
+            } else {
+                mgr.sync_delta(&mut session, &mut stream)
+            }
+        })
+    }
 
-        // Server responds with a session id and the previous random data.
-        let msg = self.read_decrypted(&mut stream)?;
-        if msg.len() < 16 {
-            return Err(Error::AuthFailed);
+    /// Runs a sync which modifies the local `TdList`, and restores the list as it was before the sync
+    /// if the sync fails. The changes are only kept after the server has acknowledged them.
+    ///
+    /// Syncing again after the server has saved the changes but the acknowledgement was lost is
+    /// safe: items the server already has are merged instead of being added again, and removing an
+    /// item twice removes it once.
+    fn transaction(&mut self, sync: impl FnOnce(&mut Self) -> Result<SyncReport>) -> Result<SyncReport> {
+        let snapshot = self.td_list.clone();
+
+        let result = sync(self);
+        if result.is_err() {
+            *self.td_list = snapshot;
         }
 
-        // set session id
-        let sid: [u8; 8] = (&msg[..8]).try_into().unwrap();
-        let auth_data: &[u8] = &msg[8..];
+        result
+    }
 
-        // Check random data
-        if auth_data != random_auth_data {
-            return Err(Error::AuthFailed);
+    /// Synchronizes with the items the server can read. Only the items modified since the last sync
+    /// are sent, and the server merges them and responds with the items changed since the last sync.
+    fn sync_delta<S: Read + Write>(&mut self, session: &mut Session, stream: &mut S) -> Result<SyncReport> {
+        // Send delta command to server. The server can only decrypt it if we know the password.
+        session.send(stream, b"delta")?;
+
+        // The server responds with the newest schema version it supports. It cannot read our changes
+        // if its version is older.
+        let msg = session.receive(stream)?;
+        let server_version: u32 = serde_json::from_slice(&msg)?;
+        if server_version < TD_LIST_SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchemaVersion(server_version, TD_LIST_SCHEMA_VERSION));
         }
 
-        // Send read command to server to verify our authenticity.
-        self.write_encrypted(&mut stream, &[&sid, b"read".as_slice()].concat())?;
+        // The items were last synced end-to-end encrypted, so the server may not have them.
+        if self.td_list.end_to_end_salt.take().is_some() {
+            self.td_list.mark_unsynced();
+            self.td_list.sync_token = None;
+        }
 
-        // Server sends its TdList, sync with that list
-        let msg = self.read_check_decrypted(&mut stream, &sid)?;
-        let mut server = TdList::new_from_json(&String::from_utf8_lossy(&msg))?;
+        self.td_list.remove_old_todos();
+        session.send(stream, serde_json::to_string(&DeltaRequest::new(self.td_list)?)?.as_bytes())?;
 
-        self.td_list.sync(&mut server);
+        // The server responds with the items changed since our last sync after it has saved our
+        // changes. We can only decrypt it if the server knows the password.
+        let msg = session.receive(stream)?;
+        let response: DeltaResponse = serde_json::from_slice(&msg)?;
+        let list = response.list(self.config.limits())?;
 
-        // send the synced list back to the server
-        self.write_encrypted(&mut stream, &[&sid, server.to_json()?.as_bytes()].concat())?;
+        Ok(self.td_list.apply_delta(response, list))
+    }
 
-        // Verify that the server actually got its list.
-        let msg = self.read_check_decrypted(&mut stream, &sid)?;
+    /// Synchronizes with the end-to-end encrypted items. The server cannot read the items, so they
+    /// are merged by the client and encrypted again before sending them back.
+    fn sync_sealed<S: Read + Write>(&mut self, session: &mut Session, stream: &mut S) -> Result<SyncReport> {
+        let password = self.config.checked_end_to_end_password()?;
 
-        if msg == b"ok" {
-            Ok(())
-        } else {
-            Err(Error::Unknown)
+        session.send(stream, b"read sealed")?;
+
+        let msg = session.receive(stream)?;
+        let sealed: Value = serde_json::from_slice(&msg)?;
+        self.config.limits().check_sealed(&sealed)?;
+        let sealed: SealedList = serde_json::from_value(sealed)?;
+
+        let (mut server, key) = sealed.open(password, self.config.limits())?;
+
+        // The items were last synced with readable items or other encrypted items, for example when
+        // end-to-end encryption was just enabled, so they may not have been sealed yet.
+        if self.td_list.end_to_end_salt != Some(key.salt()) {
+            self.td_list.mark_unsynced();
+            self.td_list.end_to_end_salt = Some(key.salt());
+            self.td_list.sync_token = None;
+        }
+
+        let report = self.td_list.sync(&mut server);
+
+        session.send(stream, serde_json::to_string(&SealedList::seal(&server, &key)?)?.as_bytes())?;
+
+        // Verify that the server actually got its items.
+        let msg = session.receive(stream)?;
+
+        match msg.as_slice() {
+            b"ok" => { Ok(report) }
+            b"modified" => { Err(Error::ConcurrentSync) }
+            _ => { Err(Error::Unknown) }
+        }
+    }
+
+    /// Connects to another client waiting in `peer_listen` and synchronizes the local `TdList` with
+    /// the list of the other client using `TdList::sync_peer`, for example when no server can be
+    /// reached. Both clients need to use the same password. Returns a `SyncReport` in which the
+    /// server is the other client.
+    ///
+    /// Connects over TCP, or over a Unix domain socket if the address starts with `unix:`.
+    pub fn peer_sync(&mut self, addr: &str) -> Result<SyncReport> {
+        match addr.strip_prefix(UNIX_SOCKET_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => { self.peer_sync_over(&UnixTransport::new(path.into())) }
+            #[cfg(not(unix))]
+            Some(_) => { Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform.").into()) }
+            None => { self.peer_sync_over(&TcpTransport::new(addr.to_string())) }
+        }
+    }
+
+    /// Connects to another client using the given `Transport` and synchronizes with it. See
+    /// `peer_sync`.
+    pub fn peer_sync_over<T: Transport>(&mut self, transport: &T) -> Result<SyncReport> {
+        self.check_peer()?;
+
+        let mut stream = transport.connect()?;
+        stream.set_timeout(self.config.timeout())?;
+
+        let mut session = self.client_handshake(&mut stream)?;
+        session.send(&mut stream, b"peer")?;
+
+        // The lists are sent one at a time, so neither client blocks on sending a large list while
+        // the other one is sending too.
+        session.send(&mut stream, self.td_list.to_json()?.as_bytes())?;
+        let msg = session.receive(&mut stream)?;
+
+        self.sync_with_peer_list(&msg)
+    }
+
+    /// Waits for another client to connect with `peer_sync` and synchronizes the local `TdList` with
+    /// the list of the other client. Returns after a single synchronization, or with an error if a
+    /// shutdown is requested with a `ShutdownHandle` before the other client connects. Returns a
+    /// `SyncReport` in which the server is the other client.
+    ///
+    /// Listens on TCP, or on a Unix domain socket if the address starts with `unix:`.
+    pub fn peer_listen(&mut self, addr: &str) -> Result<SyncReport> {
+        match addr.strip_prefix(UNIX_SOCKET_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => { self.peer_listen_over(&UnixTransport::new(path.into())) }
+            #[cfg(not(unix))]
+            Some(_) => { Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform.").into()) }
+            None => { self.peer_listen_over(&TcpTransport::new(addr.to_string())) }
+        }
+    }
+
+    /// Waits for another client to connect using the given `Transport` and synchronizes with it. See
+    /// `peer_listen`.
+    pub fn peer_listen_over<T: Transport>(&mut self, transport: &T) -> Result<SyncReport> {
+        self.check_peer()?;
+
+        let listener = transport.listen()?;
+        let (salt, key) = self.config.server_key()?;
+
+        let mut stream = loop {
+            if self.shutdown.is_shutdown() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, "Stopped waiting for a peer.").into());
+            }
+            match listener.accept() {
+                Ok(stream) => { break stream; }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => { thread::sleep(SHUTDOWN_POLL_INTERVAL); }
+                Err(e) => { return Err(e.into()); }
+            }
+        };
+        stream.set_timeout(self.config.timeout())?;
+
+        let mut session = MtdNetMgr::server_handshake(self.config, &mut stream, &salt, &key)?;
+
+        // A client trying to sync with a server doesn't expect the items of a client.
+        if session.receive(&mut stream)? != b"peer" {
+            return Err(Error::ClientOnlyOperation);
         }
+
+        let msg = session.receive(&mut stream)?;
+        session.send(&mut stream, self.td_list.to_json()?.as_bytes())?;
+
+        self.sync_with_peer_list(&msg)
+    }
+
+    fn check_peer(&self) -> Result<()> {
+        if self.config.local_only {
+            return Err(Error::OnlineOnlyOperation);
+        }
+        if self.td_list.server {
+            return Err(Error::ClientOnlyOperation);
+        }
+        Ok(())
+    }
+
+    /// Merges the list received from the other client with the local `TdList`. Both clients merge
+    /// the same lists, so neither needs to send the result back. If the connection breaks after only
+    /// one of the clients has merged the lists, the other one gets the same items the next time they
+    /// sync.
+    fn sync_with_peer_list(&mut self, msg: &[u8]) -> Result<SyncReport> {
+        // Lists of an older schema version are migrated, and the other client cannot read our list
+        // if its version is older.
+        let peer: Value = serde_json::from_slice(msg)?;
+        self.config.limits().check_list(&peer)?;
+        let mut peer = TdList::new_from_json(&peer.to_string())?;
+
+        self.td_list.sync_peer(&mut peer)
     }
 
-    /// Creates a loop which handles incoming sync connections. Note that each connection is handled in
-    /// the same thread sequentially so only one connection can be processed at a time. Writes the local
-    /// `TdList` if the initialization `Config` defined a `save_location`.
+    /// Creates a loop which handles incoming sync connections. Each connection is handled in its own
+    /// thread, so a slow client doesn't hold up other clients. At most 32 connections are handled at
+    /// the same time. The `TdList` is only locked while merging the changes of a client, never while
+    /// waiting for the network, so concurrent syncs never overwrite each other's changes. Writes the
+    /// local `TdList` if the initialization `Config` defined a `save_location`. The list is reloaded
+    /// from the save location before merging, so changes other processes saved are kept.
+    ///
+    /// The loop runs until a shutdown is requested with a `ShutdownHandle`. It then finishes handling
+    /// the current connections and returns `Ok`.
     ///
-    /// # Panics
+    /// The encryption key is derived from the password once when the loop starts, so handling a
+    /// connection doesn't require running Argon2.
     ///
-    /// If the `TdList` is a client list.
+    /// Listens on TCP, or on a Unix domain socket if the socket address of the `Config` starts with
+    /// `unix:`.
     pub fn server_listening_loop(&mut self) -> Result<()> {
+        match self.config.socket_addr().strip_prefix(UNIX_SOCKET_PREFIX) {
+            #[cfg(unix)]
+            Some(path) => { self.server_listening_loop_over(&UnixTransport::new(path.into())) }
+            #[cfg(not(unix))]
+            Some(_) => { Err(io::Error::new(io::ErrorKind::Unsupported, "Unix domain sockets are not supported on this platform.").into()) }
+            None => { self.server_listening_loop_over(&TcpTransport::new(self.config.socket_addr().to_string())) }
+        }
+    }
+
+    /// Handles incoming sync connections of the given `Transport`. See `server_listening_loop`.
+    pub fn server_listening_loop_over<T: Transport>(&mut self, transport: &T) -> Result<()> {
         if self.config.local_only {
             return Err(Error::OnlineOnlyOperation);
         }
@@ -211,110 +820,371 @@ impl<'a> MtdNetMgr<'a> {
             return Err(Error::ServerOnlyOperation);
         }
 
-        let tcp = TcpListener::bind(self.config.socket_addr())?;
+        // The listener is polled so that shutdown requests are noticed without a new connection.
+        let listener = transport.listen()?;
+
+        let (salt, key) = self.config.server_key()?;
 
-        for stream in tcp.incoming() {
-            match self.handle_stream(stream) {
-                Err(e) => {
-                    eprintln!("Error occurred: {}", e)
+        let config = self.config;
+        let shutdown = &self.shutdown;
+        let td_list = Mutex::new(&mut *self.td_list);
+        let connections = AtomicUsize::new(0);
+
+        // Leaving the scope waits for the connections being handled to finish.
+        thread::scope(|scope| {
+            while !shutdown.is_shutdown() {
+                // Connections beyond the limit wait in the backlog of the listener.
+                if connections.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                    continue;
                 }
-                Ok(_) => {}
+
+                let stream = match listener.accept() {
+                    Ok(stream) => { stream }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("Error occurred: {}", e);
+                        continue;
+                    }
+                };
+
+                let td_list = &td_list;
+                let salt = &salt;
+                let key = &key;
+                let connections = &connections;
+                connections.fetch_add(1, Ordering::SeqCst);
+                scope.spawn(move || {
+                    if let Err(e) = MtdNetMgr::handle_stream(config, td_list, stream, salt, key, None) {
+                        eprintln!("Error occurred: {}", e)
+                    }
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                });
             }
-        }
+        });
 
         Ok(())
     }
 
-    fn handle_stream(&mut self, stream: io::Result<TcpStream>) -> Result<()> {
-        let mut stream = stream?;
+    /// Handles a single sync session over the given stream, for example a `StdioStream` of a server
+    /// started by a client's `CommandTransport`. Writes the local `TdList` if the initialization
+    /// `Config` defined a `save_location`.
+    ///
+    /// Several sessions can be served by different processes at the same time, so the caller should
+    /// hold the `FileLock` of the save location from loading the `TdList` until the session has
+    /// finished, and pass it as `file_lock`. Otherwise, the list is only locked while saving it.
+    pub fn serve_stream<S: Stream>(&mut self, stream: S, file_lock: Option<&FileLock>) -> Result<()> {
+        if self.config.local_only {
+            return Err(Error::OnlineOnlyOperation);
+        }
+        if !self.td_list.server {
+            return Err(Error::ServerOnlyOperation);
+        }
 
-        stream.set_read_timeout(Some(self.config.timeout()))?;
-        stream.set_write_timeout(Some(self.config.timeout()))?;
+        let (salt, key) = self.config.server_key()?;
 
-        // Random session id for the sync exchange.
-        let sid: [u8; 8] = random();
+        MtdNetMgr::handle_stream(self.config, &Mutex::new(&mut *self.td_list), stream, &salt, &key, file_lock)
+    }
 
-        // First the client sends some random data in an encrypted form to the server.
-        let random_auth_data = self.read_decrypted(&mut stream)?;
-        // The server sends the data back with a new session id attached.
-        self.write_encrypted(&mut stream, &[&sid, random_auth_data.as_slice()].concat())?;
+    fn handle_stream<S: Stream>(config: &Config, td_list: &Mutex<&mut TdList>, mut stream: S, salt: &[u8; 16], key: &[u8; 32], file_lock: Option<&FileLock>) -> Result<()> {
+        stream.set_timeout(config.timeout())?;
 
-        // Client sends a command to the server.
-        let msg = self.read_check_decrypted(&mut stream, &sid)?;
+        let mut session = MtdNetMgr::server_handshake(config, &mut stream, salt, key)?;
 
-        // Verify that the request is a read request. This just verifies that the client has the right
-        // encryption password.
-        if msg == b"read" {
-            // Respond with the server TdList
-            self.write_encrypted(&mut stream, &[&sid, self.td_list.to_json()?.as_bytes()].concat())?;
-        } else {
-            println!("Client from {} didn't try to read server items. Stopping connection. This is probably a bad sign.", stream.peer_addr()?);
-            return Ok(());
+        // Client sends a command to the server.
+        let msg = session.receive(&mut stream)?;
+
+        // Verify that the request is a sync request. Decrypting the request already verified that
+        // the client has the right encryption password.
+        match msg.as_slice() {
+            b"delta" => { MtdNetMgr::handle_delta(config, td_list, &mut session, &mut stream, file_lock) }
+            b"read sealed" => { MtdNetMgr::handle_sealed(config, td_list, &mut session, &mut stream, file_lock) }
+            _ => {
+                eprintln!("Client from {} didn't try to sync with server items. Stopping connection. This is probably a bad sign.", stream.peer());
+                Ok(())
+            }
         }
+    }
+
+    /// Merges the changes of a client into the server `TdList` and responds with the items changed
+    /// since the client last synced.
+    fn handle_delta<S: Read + Write>(config: &Config, td_list: &Mutex<&mut TdList>, session: &mut Session, stream: &mut S, file_lock: Option<&FileLock>) -> Result<()> {
+        session.send(stream, &serde_json::to_vec(&TD_LIST_SCHEMA_VERSION)?)?;
+
+        // Client sends the items it has modified since it last synced.
+        let msg = session.receive(stream)?;
+        let request: DeltaRequest = serde_json::from_slice(&msg)?;
+        let changes = request.changes(config.limits())?;
+
+        // Other connections must not merge their changes at the same time. Otherwise, the revisions
+        // of the changes could be mixed up.
+        let mut td_list = td_list.lock().unwrap_or_else(|e| e.into_inner());
+        let _file_lock = MtdNetMgr::reload_server_list(config, &mut td_list, file_lock)?;
+
+        let response = td_list.merge_delta(changes, request.token())?;
+        td_list.expire_tombstones(config.tombstone_expiry());
+        MtdNetMgr::save_server_list(config, &td_list)?;
 
-        // Client sends a response with a new synced TdList for the server.
-        let msg = self.read_check_decrypted(&mut stream, &sid)?;
-        let json_string = String::from_utf8_lossy(&msg).to_string();
-        let new_td_list = TdList::new_from_json(&json_string)?;
+        drop(td_list);
 
-        self.td_list.todos = new_td_list.todos;
-        self.td_list.tasks = new_td_list.tasks;
+        // The response also verifies to the client that the server has saved its changes.
+        session.send(stream, serde_json::to_string(&response)?.as_bytes())?;
 
-        if let Some(path) = self.config.save_location() {
-            fs::write(path, &json_string)?;
+        Ok(())
+    }
+
+    /// Sends the end-to-end encrypted items to a client, which merges them itself, and replaces them
+    /// with the merged items.
+    ///
+    /// The server cannot read the items, so it cannot tell a merge from a client that leaves items
+    /// out. Any client knowing the server's password can therefore replace or remove all the
+    /// encrypted items, except that removed items are kept removed by the server's tombstones.
+    fn handle_sealed<S: Read + Write>(config: &Config, td_list: &Mutex<&mut TdList>, session: &mut Session, stream: &mut S, file_lock: Option<&FileLock>) -> Result<()> {
+        // The list isn't locked while the client merges the items, so a slow client doesn't hold up
+        // the others.
+        let sealed = td_list.lock().unwrap_or_else(|e| e.into_inner()).sealed.clone();
+
+        session.send(stream, serde_json::to_string(&sealed)?.as_bytes())?;
+
+        // Client sends a response with new encrypted items for the server.
+        let msg = session.receive(stream)?;
+        let new_sealed: Value = serde_json::from_slice(&msg)?;
+        config.limits().check_sealed(&new_sealed)?;
+        let mut new_sealed: SealedList = serde_json::from_value(new_sealed)?;
+
+        let mut td_list = td_list.lock().unwrap_or_else(|e| e.into_inner());
+        let _file_lock = MtdNetMgr::reload_server_list(config, &mut td_list, file_lock)?;
+
+        // Another client may have replaced the items while this client merged them. Replacing them
+        // again would lose the changes of the other client, so this client has to sync again.
+        if !td_list.sealed.same_records(&sealed) {
+            drop(td_list);
+            session.send(stream, b"modified")?;
+            return Ok(());
         }
 
+        // The client may not know about all the removed items, so the server keeps its own
+        // tombstones and removes the items they belong to.
+        new_sealed.keep_tombstones(&td_list.sealed);
+        td_list.sealed = new_sealed;
+        td_list.expire_tombstones(config.tombstone_expiry());
+        MtdNetMgr::save_server_list(config, &td_list)?;
+
+        drop(td_list);
+
         // Send ok to the client to verify that everything went right.
-        self.write_encrypted(&mut stream, &[&sid, b"ok".as_slice()].concat())?;
+        session.send(stream, b"ok")?;
 
         Ok(())
     }
 
-    /// Encrypts and writes a message to a `TcpStream`.
-    fn write_encrypted(&self, stream: &mut TcpStream, content: &[u8]) -> Result<()> {
-        let enc = encrypt(content, &self.config.encryption_password())?;
-        let len = enc.len() as u32;
-        let len_header = len.to_le_bytes();
-        stream.write(&len_header)?;
-        stream.write(&enc)?;
+    /// Locks the save location of the server `TdList` unless the lock is already held, and reloads
+    /// the list from it. Other processes, such as a command line client or a `serve_stream` session
+    /// using the same save location, may have saved changes since the list was loaded, and saving
+    /// the list without them would lose the changes. The returned lock must be held until the list
+    /// has been saved.
+    ///
+    /// A caller holding the lock since loading the list already has all the changes, so the list is
+    /// not reloaded then.
+    fn reload_server_list(config: &Config, td_list: &mut TdList, file_lock: Option<&FileLock>) -> Result<Option<FileLock>> {
+        let path = match (config.save_location(), file_lock) {
+            (Some(path), None) => { path }
+            _ => { return Ok(None); }
+        };
+
+        let lock = FileLock::acquire(path, config.timeout())?;
+        if let Some(loaded) = load_with_backup(path, |data| TdList::new_from_json(&config.decode_data(data)?))? {
+            *td_list = loaded.into_value();
+        }
+
+        Ok(Some(lock))
+    }
+
+    /// Writes the server `TdList` if the `Config` defines a `save_location`. The save location must
+    /// be locked, see `reload_server_list`.
+    fn save_server_list(config: &Config, td_list: &TdList) -> Result<()> {
+        if let Some(path) = config.save_location() {
+            save_atomic(path, &config.encode_data(&td_list.to_json()?)?)?;
+        }
         Ok(())
     }
 
-    /// Reads a message from a `TcpStream` and decrypts it.
-    fn read_decrypted(&self, stream: &mut TcpStream) -> Result<Vec<u8>> {
-        let mut msg_len_header = [0u8; 4];
-        stream.read_exact(&mut msg_len_header)?;
-        let len = u32::from_le_bytes(msg_len_header);
-        let mut encrypted_msg = vec![0u8; len as usize];
-        stream.read_exact(&mut encrypted_msg)?;
-        decrypt(&encrypted_msg, &self.config.encryption_password())
+    /// Performs the client side of the handshake. The client sends its protocol version and a random
+    /// nonce, and the server responds with its protocol version, the salt used for deriving the key
+    /// from the password and a nonce of its own. Both sides then derive the session keys and proofs
+    /// of knowing the password from the key and a hash of the hellos, so the keys and proofs are
+    /// bound to this exact session.
+    ///
+    /// The client proves its knowledge of the password first. The server responds with its own proof,
+    /// or with an empty message if the client's proof was incorrect.
+    fn client_handshake<S: Read + Write>(&self, stream: &mut S) -> Result<Session> {
+        let client_nonce: [u8; 16] = random();
+        let client_hello = [HELLO_MAGIC.as_slice(), &PROTOCOL_VERSION.to_le_bytes(), &client_nonce].concat();
+        write_frame(stream, &client_hello)?;
+
+        let server_hello = read_frame(stream, MAX_HANDSHAKE_FRAME_SIZE)?;
+        let salt = &check_hello(&server_hello, 32)?[..16];
+
+        let key = self.config.client_key(salt)?;
+        let keys = session_keys(&key, &transcript_hash(&client_hello, &server_hello))?;
+
+        write_frame(stream, &keys.client_proof)?;
+
+        let server_proof = read_frame(stream, MAX_HANDSHAKE_FRAME_SIZE)?;
+        if server_proof.is_empty() {
+            return Err(Error::WrongPassword);
+        }
+        // A server with a different password would have rejected our proof, so an incorrect proof
+        // means that the server isn't the one it claims to be, for example a replayed session.
+        if !bool::from(server_proof.ct_eq(&keys.server_proof)) {
+            return Err(Error::TamperedMessage);
+        }
+
+        Ok(Session::new(&keys.client_key, &keys.server_key, self.config.limits().max_frame_size()))
     }
 
-    /// Reads a message from a `TcpStream` and decrypts it. Checks the message's session id and returns
-    /// the message without a session id.
-    fn read_check_decrypted(&self, stream: &mut TcpStream, correct_sid: &[u8; 8]) -> Result<Vec<u8>> {
-        MtdNetMgr::check_sid(correct_sid, &self.read_decrypted(stream)?).map(|l| l.to_vec())
+    /// Performs the server side of the handshake. See `client_handshake`.
+    fn server_handshake<S: Read + Write>(config: &Config, stream: &mut S, salt: &[u8; 16], key: &[u8; 32]) -> Result<Session> {
+        let client_hello = read_frame(stream, MAX_HANDSHAKE_FRAME_SIZE)?;
+
+        let server_nonce: [u8; 16] = random();
+        let server_hello = [HELLO_MAGIC.as_slice(), &PROTOCOL_VERSION.to_le_bytes(), salt, &server_nonce].concat();
+        // The hello is sent even if the client's version isn't supported so that the client can
+        // report the version mismatch.
+        write_frame(stream, &server_hello)?;
+
+        check_hello(&client_hello, 16)?;
+        let keys = session_keys(key, &transcript_hash(&client_hello, &server_hello))?;
+
+        let client_proof = read_frame(stream, MAX_HANDSHAKE_FRAME_SIZE)?;
+        if !bool::from(client_proof.ct_eq(&keys.client_proof)) {
+            // Tell the client that its password was wrong.
+            write_frame(stream, &[])?;
+            return Err(Error::WrongPassword);
+        }
+
+        write_frame(stream, &keys.server_proof)?;
+
+        Ok(Session::new(&keys.server_key, &keys.client_key, config.limits().max_frame_size()))
     }
+}
 
-    /// Checks if a message contains a valid session id. Returns the message without the session id
-    /// if the session id is correct. Otherwise returns an Err.
-    fn check_sid<'b>(correct_sid: &[u8; 8], msg_with_sid: &'b [u8]) -> Result<&'b [u8]> {
-        if msg_with_sid.len() >= 8 && &msg_with_sid[..8] == correct_sid {
-            Ok(&msg_with_sid[8..])
-        } else {
-            Err(Error::AuthFailed)
+/// The encryption state of a single connection. Both directions use their own key and message
+/// counter.
+struct Session {
+    sending: SessionCipher,
+    receiving: SessionCipher,
+    max_frame_size: u32,
+}
+
+impl Session {
+    fn new(sending_key: &[u8; 32], receiving_key: &[u8; 32], max_frame_size: u32) -> Self {
+        Self {
+            sending: SessionCipher::new(sending_key),
+            receiving: SessionCipher::new(receiving_key),
+            max_frame_size,
         }
     }
+
+    /// Encrypts and writes a message to a stream.
+    fn send<S: Write>(&mut self, stream: &mut S, msg: &[u8]) -> Result<()> {
+        write_frame(stream, &self.sending.seal(msg)?)
+    }
+
+    /// Reads a message from a stream and decrypts it. Both ends have proven that they know the
+    /// password during the handshake, so a message that cannot be decrypted has been modified,
+    /// replayed or reordered.
+    fn receive<S: Read>(&mut self, stream: &mut S) -> Result<Vec<u8>> {
+        let ciphertext = read_frame(stream, self.max_frame_size)?;
+        self.receiving.open(&ciphertext).map_err(|_| Error::TamperedMessage)
+    }
+}
+
+/// Checks that a handshake hello message starts with the magic bytes and a supported protocol
+/// version. Returns the rest of the message if it has the expected length.
+fn check_hello(msg: &[u8], rest_len: usize) -> Result<&[u8]> {
+    if msg.len() < HELLO_MAGIC.len() + 2 || &msg[..HELLO_MAGIC.len()] != HELLO_MAGIC {
+        // Versions before the handshake was introduced start directly with encrypted data.
+        return Err(Error::UnsupportedProtocolVersion(0));
+    }
+
+    let version = u16::from_le_bytes([msg[HELLO_MAGIC.len()], msg[HELLO_MAGIC.len() + 1]]);
+    if version != PROTOCOL_VERSION {
+        return Err(Error::UnsupportedProtocolVersion(version));
+    }
+
+    let rest = &msg[HELLO_MAGIC.len() + 2..];
+    if rest.len() != rest_len {
+        return Err(Error::AuthFailed);
+    }
+
+    Ok(rest)
+}
+
+/// Writes a length prefixed message to a stream.
+fn write_frame<S: Write>(stream: &mut S, content: &[u8]) -> Result<()> {
+    let len = content.len() as u32;
+    stream.write_all(&len.to_le_bytes())?;
+    stream.write_all(content)?;
+    // Buffered streams such as the standard output must not hold back the message.
+    stream.flush()?;
+    Ok(())
+}
+
+/// Reads a length prefixed message from a stream. Returns an error without reading the message if
+/// it is larger than `max_len`.
+fn read_frame<S: Read>(stream: &mut S, max_len: u32) -> Result<Vec<u8>> {
+    let mut msg_len_header = [0u8; 4];
+    stream.read_exact(&mut msg_len_header)?;
+    let len = u32::from_le_bytes(msg_len_header);
+    if len > max_len {
+        return Err(Error::FrameTooLarge(len, max_len));
+    }
+    let mut msg = vec![0u8; len as usize];
+    stream.read_exact(&mut msg)?;
+    Ok(msg)
 }
 
 #[cfg(test)]
 mod network_tests {
     use std::{env, fs, thread};
-    use std::path::Path;
+    use std::io::Cursor;
+    use std::sync::mpsc;
     use std::time::Duration;
 
-    use crate::{Config, Error, TdList, Todo};
-    use crate::network::MtdNetMgr;
+    use chrono::Weekday;
+
+    use crate::{backup_path, Config, DerivedKey, Error, FileLock, ItemState, Limits, Listener, MemoryTransport, save_atomic, SealedList, SecretSource, ShutdownHandle, Task, TD_LIST_SCHEMA_VERSION, TdList, Todo, Transport};
+    use crate::network::{HELLO_MAGIC, MtdNetMgr, PROTOCOL_VERSION, read_frame, write_frame};
+
+    // Runs a server over a new MemoryTransport until it is shut down. Returns the transport for the
+    // clients, the handle for shutting the server down and the thread returning the server list.
+    fn memory_server(mut server: TdList, conf: Config) -> (MemoryTransport, ShutdownHandle, thread::JoinHandle<TdList>) {
+        let transport = MemoryTransport::new();
+        let server_transport = transport.clone();
+        let (tx, rx) = mpsc::channel();
+
+        let server = thread::spawn(move || {
+            let mut server_mgr = MtdNetMgr::new(&mut server, &conf);
+            tx.send(server_mgr.shutdown_handle()).unwrap();
+            server_mgr.server_listening_loop_over(&server_transport).unwrap();
+            server
+        });
+
+        (transport, rx.recv().unwrap(), server)
+    }
+
+    // Waits for a connection to the listener.
+    fn accept<L: Listener>(listener: &L) -> L::Stream {
+        loop {
+            match listener.accept() {
+                Ok(stream) => { return stream; }
+                Err(_) => { thread::sleep(Duration::from_millis(10)); }
+            }
+        }
+    }
 
     #[test]
     fn mtd_net_mgr_returns_err_if_server_listener_ran_with_client_td_list() {
@@ -325,10 +1195,7 @@ mod network_tests {
             None,
             false,
         );
-        match MtdNetMgr::new(&mut TdList::new_client(), &conf).server_listening_loop().unwrap_err() {
-            Error::ServerOnlyOperation => assert!(true),
-            _ => assert!(false)
-        }
+        assert!(matches!(MtdNetMgr::new(&mut TdList::new_client(), &conf).server_listening_loop().unwrap_err(), Error::ServerOnlyOperation));
     }
 
     #[test]
@@ -340,10 +1207,7 @@ mod network_tests {
             None,
             false,
         );
-        match MtdNetMgr::new(&mut TdList::new_server(), &conf).client_sync().unwrap_err() {
-            Error::ClientOnlyOperation => assert!(true),
-            _ => assert!(false)
-        }
+        assert!(matches!(MtdNetMgr::new(&mut TdList::new_server(), &conf).client_sync().unwrap_err(), Error::ClientOnlyOperation));
     }
 
     #[test]
@@ -355,10 +1219,7 @@ mod network_tests {
             None,
             true,
         );
-        match MtdNetMgr::new(&mut TdList::new_client(), &conf).client_sync().unwrap_err() {
-            Error::OnlineOnlyOperation => assert!(true),
-            _ => assert!(false)
-        }
+        assert!(matches!(MtdNetMgr::new(&mut TdList::new_client(), &conf).client_sync().unwrap_err(), Error::OnlineOnlyOperation));
     }
 
     #[test]
@@ -370,10 +1231,441 @@ mod network_tests {
             None,
             true,
         );
-        match MtdNetMgr::new(&mut TdList::new_server(), &conf).server_listening_loop().unwrap_err() {
-            Error::OnlineOnlyOperation => assert!(true),
-            _ => assert!(false)
+        assert!(matches!(MtdNetMgr::new(&mut TdList::new_server(), &conf).server_listening_loop().unwrap_err(), Error::OnlineOnlyOperation));
+    }
+
+    #[test]
+    fn mtd_net_mgr_client_sync_returns_err_if_server_uses_other_protocol_version() {
+        let transport = MemoryTransport::new();
+        let listener = transport.listen().unwrap();
+
+        thread::spawn(move || {
+            let mut stream = accept(&listener);
+            read_frame(&mut stream, 64).unwrap();
+            write_frame(&mut stream, &[HELLO_MAGIC.as_slice(), &1u16.to_le_bytes(), &[0; 32]].concat()).unwrap();
+        });
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+
+        assert!(matches!(MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap_err(), Error::UnsupportedProtocolVersion(1)));
+    }
+
+    #[test]
+    fn mtd_net_mgr_client_sync_returns_err_if_passwords_differ() {
+        let (transport, handle, server) = memory_server(TdList::new_server(), Config::new_default(b"hunter42".to_vec(), String::new(), None));
+
+        let conf = Config::new_default(b"hunter43".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+
+        assert!(matches!(MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap_err(), Error::WrongPassword));
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mtd_net_mgr_client_sync_returns_err_if_server_proof_is_incorrect() {
+        let transport = MemoryTransport::new();
+        let listener = transport.listen().unwrap();
+
+        thread::spawn(move || {
+            let mut stream = accept(&listener);
+            read_frame(&mut stream, 64).unwrap();
+            write_frame(&mut stream, &[HELLO_MAGIC.as_slice(), &PROTOCOL_VERSION.to_le_bytes(), &[0; 32]].concat()).unwrap();
+            read_frame(&mut stream, 64).unwrap();
+            // A proof recorded from another session is as good as random data.
+            write_frame(&mut stream, &[7; 32]).unwrap();
+        });
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+
+        assert!(matches!(MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap_err(), Error::TamperedMessage));
+    }
+
+    #[test]
+    fn config_encodes_data_encrypted_only_if_data_encryption_is_set() {
+        let json = TdList::new_client().to_json().unwrap();
+        let mut conf = Config::new_default(b"hunter42".to_vec(), "127.0.0.1:55995".to_string(), None);
+
+        assert_eq!(conf.encode_data(&json).unwrap(), json.as_bytes());
+
+        conf.set_data_encryption(Some(SecretSource::Prompt));
+        assert!(matches!(conf.encode_data(&json).unwrap_err(), Error::SecretUnavailable(_)));
+
+        conf.set_data_password(b"data password".to_vec());
+        let encoded = conf.encode_data(&json).unwrap();
+        assert_ne!(encoded, json.as_bytes());
+        assert_eq!(conf.decode_data(&encoded).unwrap(), json);
+        assert!(matches!(conf.decode_data(json.as_bytes()).unwrap_err(), Error::UnencryptedData));
+
+        // The password must never be saved to the config.
+        assert!(Config::new_from_json(&conf.to_json().unwrap()).unwrap().decode_data(&encoded).is_err());
+    }
+
+    #[test]
+    fn config_encrypts_saved_data_and_removes_unencrypted_backup() {
+        let path = env::temp_dir().join(format!("mtd-encrypt-saved-data-test-{}.json", std::process::id()));
+        let json = TdList::new_client().to_json().unwrap();
+        save_atomic(&path, json.as_bytes()).unwrap();
+        save_atomic(&path, json.as_bytes()).unwrap();
+
+        let mut conf = Config::new_default(b"hunter42".to_vec(), "127.0.0.1:55995".to_string(), Some(path.clone()));
+        conf.set_data_encryption(Some(SecretSource::Prompt));
+        conf.set_data_password(b"data password".to_vec());
+        conf.encrypt_saved_data(&path).unwrap();
+
+        assert_eq!(conf.decode_data(&fs::read(&path).unwrap()).unwrap(), json);
+        assert!(!backup_path(&path).exists());
+
+        // Encrypted files are kept as they are.
+        let encrypted = fs::read(&path).unwrap();
+        conf.encrypt_saved_data(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), encrypted);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn mtd_net_mgr_syncs_with_derived_key_and_sourced_password() {
+        let mut server = TdList::new_server();
+        server.add_todo(Todo::new_undated("Todo 1".to_string()));
+        let mut server_conf = Config::new_default(Vec::new(), String::new(), None);
+        server_conf.set_derived_key(Some(DerivedKey::derive(b"hunter42", [3; 16]).unwrap()));
+        let (transport, handle, server) = memory_server(server, server_conf);
+
+        let mut conf = Config::new_default(Vec::new(), String::new(), None);
+        conf.set_password_source(Some(SecretSource::Prompt));
+        let mut client = TdList::new_client();
+
+        assert!(matches!(MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap_err(), Error::SecretUnavailable(_)));
+
+        conf.read_password(|| Ok("hunter42".to_string())).unwrap();
+        MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap();
+        assert!(client.todos().contains(&&Todo::new_undated("Todo 1".to_string())));
+
+        // A client with a key derived with another salt cannot sync.
+        conf.set_derived_key(Some(DerivedKey::derive(b"hunter42", [4; 16]).unwrap()));
+        assert!(matches!(MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap_err(), Error::KeySaltMismatch));
+
+        // The password is never saved.
+        assert!(Config::new_from_json(&conf.to_json().unwrap()).unwrap().encryption_password().is_empty());
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mtd_net_mgr_end_to_end_sync_hides_items_from_server() {
+        let mut server = TdList::new_server();
+        server.add_todo(Todo::new_undated("Readable todo".to_string()));
+        let (transport, handle, server) = memory_server(server, Config::new_default(b"SecurePw".to_vec(), String::new(), None));
+
+        let mut conf = Config::new_default(b"SecurePw".to_vec(), String::new(), None);
+        conf.set_end_to_end(Some(SecretSource::Prompt));
+
+        // Items synced before enabling end-to-end encryption are encrypted instead of removed.
+        let mut client1 = TdList::new_client();
+        client1.add_todo(Todo::new_undated("Secret todo".to_string()));
+        client1.self_sync();
+        assert!(matches!(MtdNetMgr::new(&mut client1, &conf).client_sync_over(&transport).unwrap_err(), Error::SecretUnavailable(_)));
+
+        conf.read_end_to_end_password(|| Ok("E2e password".to_string())).unwrap();
+        MtdNetMgr::new(&mut client1, &conf).client_sync_over(&transport).unwrap();
+
+        let mut client2 = TdList::new_client();
+        MtdNetMgr::new(&mut client2, &conf).client_sync_over(&transport).unwrap();
+        assert_eq!(client2.todos(), vec![&Todo::new_undated("Secret todo".to_string())]);
+
+        let mut wrong_conf = conf.clone();
+        wrong_conf.read_end_to_end_password(|| Ok("Wrong password".to_string())).unwrap();
+        assert!(matches!(MtdNetMgr::new(&mut TdList::new_client(), &wrong_conf).client_sync_over(&transport).unwrap_err(), Error::DecryptingFailed));
+
+        handle.shutdown();
+        let server = server.join().unwrap();
+
+        // The server keeps its readable items separate and cannot read the end-to-end encrypted ones.
+        assert_eq!(server.todos(), vec![&Todo::new_undated("Readable todo".to_string())]);
+        assert!(!server.to_json().unwrap().contains("Secret todo"));
+        assert!(!client2.todos().contains(&&Todo::new_undated("Readable todo".to_string())));
+    }
+
+    #[test]
+    fn read_frame_returns_err_if_frame_exceeds_limit() {
+        let mut stream = Cursor::new(u32::MAX.to_le_bytes().to_vec());
+        assert!(matches!(read_frame(&mut stream, 1024).unwrap_err(), Error::FrameTooLarge(u32::MAX, 1024)));
+    }
+
+    #[test]
+    fn limits_check_list_returns_err_if_too_many_items() {
+        let mut list = TdList::new_server();
+        list.add_todo(Todo::new_undated("Todo 1".to_string()));
+        list.add_task(Task::new("Task 1".to_string(), vec![Weekday::Mon]));
+
+        let list = serde_json::from_str(&list.to_json().unwrap()).unwrap();
+
+        assert!(Limits::new(1024, 2, 1024, 1024).check_list(&list).is_ok());
+        assert!(matches!(Limits::new(1024, 1, 1024, 1024).check_list(&list).unwrap_err(), Error::TooManyItems(2, 1)));
+    }
+
+    #[test]
+    fn limits_check_list_returns_err_if_body_too_long() {
+        let mut list = TdList::new_server();
+        list.add_task(Task::new("Task 1".to_string(), vec![Weekday::Mon]));
+
+        let list = serde_json::from_str(&list.to_json().unwrap()).unwrap();
+
+        assert!(Limits::new(1024, 10, 6, 1024).check_list(&list).is_ok());
+        assert!(matches!(Limits::new(1024, 10, 5, 1024).check_list(&list).unwrap_err(), Error::BodyTooLong(6, 5)));
+    }
+
+    #[test]
+    fn limits_check_sealed_returns_err_if_too_many_records() {
+        let sealed = serde_json::json!({ "todos": [{ "sync_id": 1, "ciphertext": [] }], "tasks": [{ "sync_id": 2, "ciphertext": [] }] });
+
+        assert!(Limits::new(1024, 2, 1024, 1024).check_sealed(&sealed).is_ok());
+        assert!(matches!(Limits::new(1024, 1, 1024, 1024).check_sealed(&sealed).unwrap_err(), Error::TooManyItems(2, 1)));
+    }
+
+    #[test]
+    fn limits_check_sealed_returns_err_if_record_too_large() {
+        let sealed = serde_json::json!({ "todos": [{ "sync_id": 1, "ciphertext": [0, 0, 0, 0] }], "tasks": [] });
+
+        assert!(Limits::new(1024, 10, 1024, 4).check_sealed(&sealed).is_ok());
+        assert!(matches!(Limits::new(1024, 10, 1024, 3).check_sealed(&sealed).unwrap_err(), Error::RecordTooLarge(4, 3)));
+    }
+
+    #[test]
+    fn mtd_net_mgr_server_handles_clients_concurrently() {
+        let (transport, handle, server) = memory_server(TdList::new_server(), Config::new_default(b"hunter42".to_vec(), String::new(), None));
+
+        // A stalled client that never sends anything shouldn't prevent others from syncing.
+        let stalled = transport.connect().unwrap();
+
+        let syncs: Vec<_> = (0..4).map(|i| {
+            let transport = transport.clone();
+            thread::spawn(move || {
+                let conf = Config::new(String::new(), b"hunter42".to_vec(), Duration::from_secs(5), None, false);
+                let mut client = TdList::new_client();
+                client.add_todo(Todo::new_undated(format!("Todo {}", i)));
+                MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap();
+            })
+        }).collect();
+
+        for sync in syncs {
+            sync.join().unwrap();
+        }
+
+        let conf = Config::new(String::new(), b"hunter42".to_vec(), Duration::from_secs(5), None, false);
+        let mut client = TdList::new_client();
+        MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap();
+
+        assert_eq!(client.todos().len(), 4);
+        for i in 0..4 {
+            assert!(client.todos().contains(&&Todo::new_undated(format!("Todo {}", i))));
         }
+
+        drop(stalled);
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mtd_net_mgr_server_listening_loop_returns_after_shutdown() {
+        let (transport, handle, server) = memory_server(TdList::new_server(), Config::new_default(b"hunter42".to_vec(), String::new(), None));
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo 1".to_string()));
+        MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap();
+
+        handle.shutdown();
+        let server = server.join().unwrap();
+
+        assert!(server.todos().contains(&&Todo::new_undated("Todo 1".to_string())));
+    }
+
+    #[test]
+    fn mtd_net_mgr_syncs_over_memory_transport() {
+        let transport = MemoryTransport::new();
+        let server_transport = transport.clone();
+        let (tx, rx) = mpsc::channel();
+
+        let server = thread::spawn(move || {
+            let mut server = TdList::new_server();
+            server.add_todo(Todo::new_undated("Todo 1".to_string()));
+            let server_conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+            let mut server_mgr = MtdNetMgr::new(&mut server, &server_conf);
+            tx.send(server_mgr.shutdown_handle()).unwrap();
+            server_mgr.server_listening_loop_over(&server_transport).unwrap();
+            server
+        });
+
+        let handle = rx.recv().unwrap();
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo 2".to_string()));
+        MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap();
+
+        let wrong_conf = Config::new_default(b"hunter43".to_vec(), String::new(), None);
+        assert!(matches!(MtdNetMgr::new(&mut TdList::new_client(), &wrong_conf).client_sync_over(&transport).unwrap_err(), Error::WrongPassword));
+
+        handle.shutdown();
+        let server = server.join().unwrap();
+
+        assert_eq!(client.todos().len(), 2);
+        assert_eq!(server.todos().len(), 2);
+    }
+
+    #[test]
+    fn mtd_net_mgr_client_sync_restores_list_if_server_does_not_acknowledge() {
+        let transport = MemoryTransport::new();
+        let server_transport = transport.clone();
+
+        // A server which reads the changes of two clients and disconnects without acknowledging them.
+        let server = thread::spawn(move || {
+            let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+            let listener = server_transport.listen().unwrap();
+            let (salt, key) = conf.server_key().unwrap();
+
+            for _ in 0..2 {
+                let mut stream = accept(&listener);
+                let mut session = MtdNetMgr::server_handshake(&conf, &mut stream, &salt, &key).unwrap();
+
+                if session.receive(&mut stream).unwrap() == b"delta" {
+                    session.send(&mut stream, &serde_json::to_vec(&TD_LIST_SCHEMA_VERSION).unwrap()).unwrap();
+                } else {
+                    session.send(&mut stream, serde_json::to_string(&SealedList::default()).unwrap().as_bytes()).unwrap();
+                }
+                session.receive(&mut stream).unwrap();
+            }
+        });
+
+        let mut conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo".to_string()));
+        assert!(MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).is_err());
+
+        assert_eq!(client.todos.items[0].state, ItemState::New);
+        assert!(client.sync_token.is_none());
+
+        conf.set_end_to_end(Some(SecretSource::Prompt));
+        conf.read_end_to_end_password(|| Ok("E2e password".to_string())).unwrap();
+        assert!(MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).is_err());
+
+        assert_eq!(client.todos.items[0].state, ItemState::New);
+        assert!(client.end_to_end_salt.is_none());
+
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mtd_net_mgr_peer_sync_merges_both_clients() {
+        let transport = MemoryTransport::new();
+        let listener_transport = transport.clone();
+
+        let listener = thread::spawn(move || {
+            let mut desktop = TdList::new_client();
+            desktop.add_todo(Todo::new_undated("Desktop".to_string()));
+            let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+            let report = MtdNetMgr::new(&mut desktop, &conf).peer_listen_over(&listener_transport).unwrap();
+            (desktop, report)
+        });
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut laptop = TdList::new_client();
+        laptop.add_todo(Todo::new_undated("Laptop".to_string()));
+        let report = MtdNetMgr::new(&mut laptop, &conf).peer_sync_over(&transport).unwrap();
+
+        let (desktop, desktop_report) = listener.join().unwrap();
+
+        assert_eq!(laptop.todos().len(), 2);
+        assert_eq!(desktop.todos().len(), 2);
+        assert_eq!(report.todos().added_to_client()[0].body(), "Desktop");
+        assert_eq!(desktop_report.todos().added_to_client()[0].body(), "Laptop");
+    }
+
+    #[test]
+    fn mtd_net_mgr_peer_sync_returns_err_with_server() {
+        let transport = MemoryTransport::new();
+        let server_transport = transport.clone();
+        let (tx, rx) = mpsc::channel();
+
+        let server = thread::spawn(move || {
+            let mut server = TdList::new_server();
+            let server_conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+            let mut server_mgr = MtdNetMgr::new(&mut server, &server_conf);
+            tx.send(server_mgr.shutdown_handle()).unwrap();
+            server_mgr.server_listening_loop_over(&server_transport).unwrap();
+        });
+
+        let handle = rx.recv().unwrap();
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo".to_string()));
+
+        assert!(MtdNetMgr::new(&mut client, &conf).peer_sync_over(&transport).is_err());
+        assert!(matches!(MtdNetMgr::new(&mut TdList::new_server(), &conf).peer_sync_over(&transport).unwrap_err(), Error::ClientOnlyOperation));
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn mtd_net_mgr_serve_stream_handles_one_session() {
+        let transport = MemoryTransport::new();
+        let server_transport = transport.clone();
+
+        let server = thread::spawn(move || {
+            let listener = server_transport.listen().unwrap();
+            let stream = accept(&listener);
+
+            let mut server = TdList::new_server();
+            let server_conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+            MtdNetMgr::new(&mut server, &server_conf).serve_stream(stream, None).unwrap();
+            server
+        });
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo 1".to_string()));
+        MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap();
+
+        // The server returns after the session without being shut down.
+        let server = server.join().unwrap();
+        assert_eq!(server.todos(), vec![&Todo::new_undated("Todo 1".to_string())]);
+    }
+
+    #[test]
+    fn mtd_net_mgr_serve_stream_saves_with_held_file_lock() {
+        let transport = MemoryTransport::new();
+        let server_transport = transport.clone();
+        let path = env::temp_dir().join("mtd-serve-stream-locked-test.json");
+        let _ = fs::remove_file(&path);
+        let server_path = path.clone();
+
+        let server = thread::spawn(move || {
+            let listener = server_transport.listen().unwrap();
+            let stream = accept(&listener);
+
+            // Acquiring the lock again would wait until the timeout and fail.
+            let server_conf = Config::new(String::new(), b"hunter42".to_vec(), Duration::from_secs(1), Some(server_path.clone()), false);
+            let lock = FileLock::acquire(&server_path, server_conf.timeout()).unwrap();
+            MtdNetMgr::new(&mut TdList::new_server(), &server_conf).serve_stream(stream, Some(&lock)).unwrap();
+        });
+
+        let conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo 1".to_string()));
+        MtdNetMgr::new(&mut client, &conf).client_sync_over(&transport).unwrap();
+
+        server.join().unwrap();
+        let server = TdList::new_from_json(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(server.todos(), vec![&Todo::new_undated("Todo 1".to_string())]);
     }
 
     // This test tests more than one thing, but I believe it to be rather useful. Running more than
@@ -393,56 +1685,94 @@ mod network_tests {
 
         client.add_todo(Todo::new_undated("Todo 3".to_string()));
 
-        let client_conf = Config::new("127.0.0.1:55997".to_string(), b"hunter42".to_vec(), Duration::from_secs(30), None, false);
-        let mut client_mgr = MtdNetMgr::new(&mut client, &client_conf);
+        let server_path = env::temp_dir().join(format!("mtd-server-write-test-{}.json", std::process::id()));
+        let server_conf = Config::new_default(b"hunter42".to_vec(), String::new(), Some(server_path.clone()));
+        let (transport, handle, server) = memory_server(server, server_conf);
 
-        thread::spawn(move || {
-            let server_path = env::temp_dir().join(Path::new("mtd-server-write-test-file"));
-            let server_conf = Config::new("127.0.0.1:55997".to_string(), b"hunter42".to_vec(), Duration::from_secs(30), Some(server_path.clone()), false);
-            let mut server_mgr = MtdNetMgr::new(&mut server, &server_conf);
-            server_mgr.server_listening_loop().unwrap();
-        });
+        let client_conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        MtdNetMgr::new(&mut client, &client_conf).client_sync_over(&transport).unwrap();
 
-        thread::sleep(Duration::from_millis(500));
-
-        client_mgr.client_sync().unwrap();
+        handle.shutdown();
+        server.join().unwrap();
 
         assert_eq!(client.todos().len(), 3);
         assert!(client.todos().contains(&&Todo::new_undated("New Todo 1".to_string())));
         assert!(client.todos().contains(&&Todo::new_undated("Todo 2".to_string())));
         assert!(client.todos().contains(&&Todo::new_undated("Todo 3".to_string())));
 
-        let server_path = env::temp_dir().join(Path::new("mtd-server-write-test-file"));
-        let server = TdList::new_from_json(&fs::read_to_string(server_path).unwrap()).unwrap();
+        let server = TdList::new_from_json(&fs::read_to_string(&server_path).unwrap()).unwrap();
 
         assert_eq!(server.todos().len(), 3);
         assert!(server.todos().contains(&&Todo::new_undated("New Todo 1".to_string())));
         assert!(server.todos().contains(&&Todo::new_undated("Todo 2".to_string())));
         assert!(server.todos().contains(&&Todo::new_undated("Todo 3".to_string())));
+
+        let _ = fs::remove_file(&server_path);
+        let _ = fs::remove_file(backup_path(&server_path));
+    }
+
+    #[test]
+    fn mtd_net_mgr_server_keeps_changes_saved_by_other_processes() {
+        let server_path = env::temp_dir().join(format!("mtd-server-reload-test-{}.json", std::process::id()));
+        let _ = fs::remove_file(&server_path);
+        let _ = fs::remove_file(backup_path(&server_path));
+
+        let server_conf = Config::new_default(b"hunter42".to_vec(), String::new(), Some(server_path.clone()));
+        let (transport, handle, server) = memory_server(TdList::new_server(), server_conf.clone());
+
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo 1".to_string()));
+        let client_conf = Config::new_default(b"hunter42".to_vec(), String::new(), None);
+        MtdNetMgr::new(&mut client, &client_conf).client_sync_over(&transport).unwrap();
+
+        // Another process, such as the command line client, edits the saved items between the syncs.
+        {
+            let _lock = FileLock::acquire(&server_path, server_conf.timeout()).unwrap();
+            let mut saved = TdList::new_from_json(&fs::read_to_string(&server_path).unwrap()).unwrap();
+            saved.add_todo(Todo::new_undated("Todo 2".to_string()));
+            save_atomic(&server_path, saved.to_json().unwrap().as_bytes()).unwrap();
+        }
+
+        MtdNetMgr::new(&mut client, &client_conf).client_sync_over(&transport).unwrap();
+
+        handle.shutdown();
+        server.join().unwrap();
+
+        assert_eq!(client.todos().len(), 2);
+        assert!(client.todos().contains(&&Todo::new_undated("Todo 2".to_string())));
+
+        let saved = TdList::new_from_json(&fs::read_to_string(&server_path).unwrap()).unwrap();
+        assert_eq!(saved.todos().len(), 2);
+        assert!(saved.todos().contains(&&Todo::new_undated("Todo 1".to_string())));
+        assert!(saved.todos().contains(&&Todo::new_undated("Todo 2".to_string())));
+
+        let _ = fs::remove_file(&server_path);
+        let _ = fs::remove_file(backup_path(&server_path));
     }
 }
 
 /// Module containing functionality for encrypting/decrypting messages used for secure network
-/// communication. Data is encrypted with AES-GCM. The encryption key is generated from a password
-/// using Argon2. For network communications, session ids should be used in addition to encrypting
-/// data.
-mod crypt {
+/// communication. Data is encrypted with AES-GCM. A key is generated from a password using Argon2
+/// once per session, and separate keys for both directions as well as proofs of knowing the key are
+/// derived from it and a hash of the handshake using HKDF. Messages are numbered with a counter
+/// used as the nonce, so each message can only be decrypted once and in the order it was sent.
+///
+/// Saved data is encrypted with a key derived from a password and a random salt for every write.
+/// End-to-end encrypted items are encrypted with a key derived once per sync and a random nonce.
+pub(crate) mod crypt {
     use aes_gcm::{Aes256Gcm, Nonce};
-    use aes_gcm::aead::{Aead, KeyInit};
+    use aes_gcm::aead::{Aead, KeyInit, Payload};
     use argon2::Argon2;
+    use hkdf::Hkdf;
     use rand::random;
+    use sha2::{Digest, Sha256};
 
     use crate::network::Error;
 
     /// Encrypts a given byte array with the given password.
     pub fn encrypt(msg: &[u8], passwd: &[u8]) -> Result<Vec<u8>, Error> {
         let key_salt: [u8; 16] = random();
-        let argon2 = Argon2::default();
-
-        let mut secret_passwd_hash: [u8; 32] = [0; 32];
-        argon2.hash_password_into(passwd, &key_salt, &mut secret_passwd_hash).map_err(|_| Error::EncryptingFailed)?;
-
-        let cipher = Aes256Gcm::new_from_slice(&secret_passwd_hash).map_err(|_| Error::EncryptingFailed)?;
+        let cipher = Aes256Gcm::new(&derive_key(passwd, &key_salt)?.into());
 
         // Random 96-bits for nonce.
         let nonce_bits: [u8; 12] = random();
@@ -461,43 +1791,156 @@ mod crypt {
 
     /// Decrypts a given ciphertext with the given password.
     pub fn decrypt(ciphertext: &[u8], passwd: &[u8]) -> Result<Vec<u8>, Error> {
-        let key_salt = &ciphertext[0..16];
-        let argon2 = Argon2::default();
+        if ciphertext.len() < 28 {
+            return Err(Error::DecryptingFailed);
+        }
+
+        let cipher = Aes256Gcm::new(&derive_key(passwd, &ciphertext[0..16]).map_err(|_| Error::DecryptingFailed)?.into());
+        let nonce = Nonce::from_slice(&ciphertext[16..28]);
+
+        cipher.decrypt(nonce, &ciphertext[28..]).map_err(|_| Error::DecryptingFailed)
+    }
+
+    /// Derives a key from the given password and salt using Argon2.
+    pub fn derive_key(passwd: &[u8], salt: &[u8]) -> Result<[u8; 32], Error> {
+        let mut key = [0; 32];
+        Argon2::default().hash_password_into(passwd, salt, &mut key).map_err(|_| Error::EncryptingFailed)?;
+        Ok(key)
+    }
+
+    /// Encrypts a given byte array with a key. `aad` is authenticated but not encrypted, so the
+    /// ciphertext can only be decrypted with the same `aad`.
+    pub fn encrypt_with_key(msg: &[u8], key: &[u8; 32], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        let cipher = Aes256Gcm::new(key.into());
+
+        let nonce_bits: [u8; 12] = random();
+        let nonce = Nonce::from_slice(nonce_bits.as_slice());
+
+        let ciphertext = cipher.encrypt(nonce, Payload { msg, aad }).map_err(|_| Error::EncryptingFailed)?;
+
+        Ok([nonce_bits.as_slice(), &ciphertext].concat())
+    }
 
-        let mut secret_passwd_hash: [u8; 32] = [0; 32];
-        argon2.hash_password_into(passwd, key_salt, &mut secret_passwd_hash).map_err(|_| Error::DecryptingFailed)?;
+    /// Decrypts a ciphertext encrypted with `encrypt_with_key`.
+    pub fn decrypt_with_key(ciphertext: &[u8], key: &[u8; 32], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        if ciphertext.len() < 12 {
+            return Err(Error::DecryptingFailed);
+        }
+
+        let cipher = Aes256Gcm::new(key.into());
+        let nonce = Nonce::from_slice(&ciphertext[..12]);
+
+        cipher.decrypt(nonce, Payload { msg: &ciphertext[12..], aad }).map_err(|_| Error::DecryptingFailed)
+    }
+
+    /// Hashes the hello messages of a handshake.
+    pub fn transcript_hash(client_hello: &[u8], server_hello: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update((client_hello.len() as u32).to_le_bytes());
+        hasher.update(client_hello);
+        hasher.update((server_hello.len() as u32).to_le_bytes());
+        hasher.update(server_hello);
+        hasher.finalize().into()
+    }
+
+    /// The keys and proofs of a single session.
+    pub struct SessionKeys {
+        /// The key for messages sent by the client.
+        pub client_key: [u8; 32],
+        /// The key for messages sent by the server.
+        pub server_key: [u8; 32],
+        /// The value the client sends to prove that it knows the key.
+        pub client_proof: [u8; 32],
+        /// The value the server sends to prove that it knows the key.
+        pub server_proof: [u8; 32],
+    }
 
-        let cipher = Aes256Gcm::new_from_slice(&secret_passwd_hash).map_err(|_| Error::DecryptingFailed)?;
+    /// Derives the session keys and proofs for a single connection from a key and the hash of the
+    /// handshake transcript.
+    pub fn session_keys(key: &[u8; 32], transcript_hash: &[u8; 32]) -> Result<SessionKeys, Error> {
+        let hkdf = Hkdf::<Sha256>::new(Some(transcript_hash), key);
+
+        let mut keys = SessionKeys {
+            client_key: [0; 32],
+            server_key: [0; 32],
+            client_proof: [0; 32],
+            server_proof: [0; 32],
+        };
+        hkdf.expand(b"mtd client to server", &mut keys.client_key).map_err(|_| Error::EncryptingFailed)?;
+        hkdf.expand(b"mtd server to client", &mut keys.server_key).map_err(|_| Error::EncryptingFailed)?;
+        hkdf.expand(b"mtd client proof", &mut keys.client_proof).map_err(|_| Error::EncryptingFailed)?;
+        hkdf.expand(b"mtd server proof", &mut keys.server_proof).map_err(|_| Error::EncryptingFailed)?;
+
+        Ok(keys)
+    }
+
+    /// Encrypts or decrypts the messages sent in one direction of a session.
+    pub struct SessionCipher {
+        cipher: Aes256Gcm,
+        counter: u64,
+    }
+
+    impl SessionCipher {
+        /// Creates a new `SessionCipher` using the given session key.
+        pub fn new(key: &[u8; 32]) -> Self {
+            Self { cipher: Aes256Gcm::new(key.into()), counter: 0 }
+        }
+
+        /// Encrypts the next message.
+        pub fn seal(&mut self, msg: &[u8]) -> Result<Vec<u8>, Error> {
+            let nonce = self.next_nonce().ok_or(Error::EncryptingFailed)?;
+            self.cipher.encrypt(Nonce::from_slice(&nonce), msg).map_err(|_| Error::EncryptingFailed)
+        }
 
-        let nonce_bits = &ciphertext[16..28];
-        let nonce = Nonce::from_slice(nonce_bits);
+        /// Decrypts the next message.
+        pub fn open(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+            let nonce = self.next_nonce().ok_or(Error::DecryptingFailed)?;
+            self.cipher.decrypt(Nonce::from_slice(&nonce), ciphertext).map_err(|_| Error::DecryptingFailed)
+        }
 
-        Ok(cipher.decrypt(nonce, &ciphertext[28..]).map_err(|_| Error::DecryptingFailed)?)
+        /// Returns the 96-bit nonce for the next message. Returns `None` if the counter has run out.
+        fn next_nonce(&mut self) -> Option<[u8; 12]> {
+            let mut nonce = [0; 12];
+            nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+            self.counter = self.counter.checked_add(1)?;
+            Some(nonce)
+        }
     }
 
     #[cfg(test)]
     mod tests {
-        use crate::network::crypt::{decrypt, encrypt};
+        use crate::network::crypt::{decrypt, decrypt_with_key, derive_key, encrypt, encrypt_with_key, session_keys, SessionCipher, SessionKeys, transcript_hash};
+
+        fn session(passwd: &[u8], client_hello: &[u8]) -> SessionKeys {
+            let key = derive_key(passwd, b"Some salt for tests").unwrap();
+            session_keys(&key, &transcript_hash(client_hello, b"server hello")).unwrap()
+        }
+
+        fn keys() -> ([u8; 32], [u8; 32]) {
+            let keys = session(b"Very secure passwd", b"client hello");
+            (keys.client_key, keys.server_key)
+        }
 
         #[test]
         fn decrypting_encrypted_returns_original() {
             let msg = b"A message to keep secure.";
-            let ps = b"Very secure passwd";
+            let (key, _) = keys();
 
-            let ct = encrypt(msg, ps).unwrap();
+            let ct = SessionCipher::new(&key).seal(msg).unwrap();
 
-            assert_eq!(decrypt(&ct, ps).unwrap(), msg);
+            assert_eq!(SessionCipher::new(&key).open(&ct).unwrap(), msg);
         }
 
         #[test]
-        fn encrypting_same_msg_with_same_password_returns_different_ciphertext() {
+        fn encrypting_same_msg_with_same_key_returns_different_ciphertext() {
             let msg = b"A message to keep secure.";
-            let ps = b"Very secure passwd";
+            let (key, _) = keys();
+            let mut cipher = SessionCipher::new(&key);
 
             let mut ciphertexts = Vec::new();
 
             for _ in 1..3 {
-                let ct = encrypt(msg, ps).unwrap();
+                let ct = cipher.seal(msg).unwrap();
                 assert!(!ciphertexts.contains(&ct));
                 ciphertexts.push(ct);
             }
@@ -506,24 +1949,93 @@ mod crypt {
         #[test]
         fn decrypting_with_incorrect_passwd_fails() {
             let msg = b"A message to keep secure.";
-            let ps = b"Very secure passwd";
+            let (key, _) = keys();
+            let wrong_key = session(b"Incorrect passwd", b"client hello").client_key;
 
-            let ct = encrypt(msg, ps).unwrap();
+            let ct = SessionCipher::new(&key).seal(msg).unwrap();
 
-            assert!(decrypt(&ct, b"Incorrect passwd").is_err());
+            assert!(SessionCipher::new(&wrong_key).open(&ct).is_err());
+        }
+
+        #[test]
+        fn decrypting_with_key_of_other_direction_fails() {
+            let msg = b"A message to keep secure.";
+            let (client_key, server_key) = keys();
+
+            let ct = SessionCipher::new(&client_key).seal(msg).unwrap();
+
+            assert!(SessionCipher::new(&server_key).open(&ct).is_err());
         }
 
         #[test]
         fn decrypting_with_invalid_ciphertext_fails() {
             let msg = b"A message to keep secure.";
-            let ps = b"Very secure passwd";
+            let (key, _) = keys();
 
-            let mut ct = encrypt(msg, ps).unwrap();
+            let mut ct = SessionCipher::new(&key).seal(msg).unwrap();
             ct.push(14);
             ct.push(36);
             ct.push(122);
 
-            assert!(decrypt(&ct, ps).is_err());
+            assert!(SessionCipher::new(&key).open(&ct).is_err());
+        }
+
+        #[test]
+        fn decrypting_messages_out_of_order_fails() {
+            let (key, _) = keys();
+            let mut sender = SessionCipher::new(&key);
+            let mut receiver = SessionCipher::new(&key);
+
+            let first = sender.seal(b"First").unwrap();
+            let second = sender.seal(b"Second").unwrap();
+
+            assert!(receiver.open(&second).is_err());
+            assert!(SessionCipher::new(&key).open(&first).is_ok());
+        }
+
+        #[test]
+        fn decrypting_password_encrypted_returns_original() {
+            let msg = b"A message to keep secure.";
+            let ps = b"Very secure passwd";
+
+            let ct = encrypt(msg, ps).unwrap();
+
+            assert_eq!(decrypt(&ct, ps).unwrap(), msg);
+            assert!(decrypt(&ct, b"Incorrect passwd").is_err());
+            assert!(decrypt(&ct[..20], ps).is_err());
+        }
+
+        #[test]
+        fn decrypting_key_encrypted_requires_same_aad() {
+            let msg = b"A message to keep secure.";
+            let (key, other_key) = keys();
+
+            let ct = encrypt_with_key(msg, &key, b"aad").unwrap();
+
+            assert_eq!(decrypt_with_key(&ct, &key, b"aad").unwrap(), msg);
+            assert!(decrypt_with_key(&ct, &key, b"other aad").is_err());
+            assert!(decrypt_with_key(&ct, &other_key, b"aad").is_err());
+        }
+
+        #[test]
+        fn session_keys_differ_between_sessions() {
+            let keys = session(b"Very secure passwd", b"client hello");
+            let other_keys = session(b"Very secure passwd", b"other client hello");
+
+            assert_ne!(keys.client_key, keys.server_key);
+            assert_ne!(keys.client_key, other_keys.client_key);
+            assert_ne!(keys.client_proof, other_keys.client_proof);
+            assert_ne!(keys.server_proof, other_keys.server_proof);
+        }
+
+        #[test]
+        fn proofs_depend_on_passwd() {
+            let keys = session(b"Very secure passwd", b"client hello");
+            let wrong_keys = session(b"Incorrect passwd", b"client hello");
+
+            assert_ne!(keys.client_proof, keys.server_proof);
+            assert_ne!(keys.client_proof, wrong_keys.client_proof);
+            assert_ne!(keys.server_proof, wrong_keys.server_proof);
         }
     }
 }
@@ -0,0 +1,399 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining recurrence rules for `Task`s. The rules are a small subset of the iCalendar
+//! RRULE: a frequency, an interval and optional by-month-day and nth-weekday-of-month parts. The
+//! by-weekday part of a rule is the weekdays list of the `Task` itself.
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error;
+
+/// The number of periods of the interval searched for the next occurrence of a `Recurrence`. The
+/// months and leap years visited by any interval repeat within it.
+const MAX_PERIODS: i64 = 48;
+
+/// How often a `Recurrence` repeats. The interval of the `Recurrence` is counted in these units.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub enum Frequency {
+    /// Repeats every `interval` days.
+    Daily,
+    /// Repeats every `interval` weeks on the weekdays of the `Task`.
+    Weekly,
+    /// Repeats every `interval` months.
+    Monthly,
+    /// Repeats every `interval` years on the month of the start date.
+    Yearly,
+}
+
+/// A rule describing on which dates a `Task` should be done.
+///
+/// # Example
+///
+/// ```
+/// use chrono::{NaiveDate, Weekday};
+/// use mtd::{Recurrence, Task};
+///
+/// // Every other week on Monday starting from 2022-06-13.
+/// let biweekly = Recurrence::weekly(2, NaiveDate::from_ymd(2022, 6, 13));
+/// let task = Task::new_recurring("Water plants".to_string(), vec![Weekday::Mon], biweekly);
+///
+/// assert!(task.for_date(NaiveDate::from_ymd(2022, 6, 13)));
+/// assert!(!task.for_date(NaiveDate::from_ymd(2022, 6, 20)));
+/// assert!(task.for_date(NaiveDate::from_ymd(2022, 6, 27)));
+///
+/// // On the first Monday of each month.
+/// let first_monday = Recurrence::monthly_on_nth_weekdays(1, NaiveDate::from_ymd(2022, 6, 1), vec![(1, Weekday::Mon)]);
+/// let task = Task::new_recurring("Team meeting".to_string(), vec![], first_monday);
+///
+/// assert!(task.for_date(NaiveDate::from_ymd(2022, 7, 4)));
+/// assert!(!task.for_date(NaiveDate::from_ymd(2022, 7, 11)));
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct Recurrence {
+    frequency: Frequency,
+    // Checked when deserializing like in new, since a zero interval would divide by zero.
+    #[serde(deserialize_with = "deserialize_interval")]
+    interval: u32,
+    start: NaiveDate,
+    month_days: Vec<i32>,
+    nth_weekdays: Vec<(i8, Weekday)>,
+}
+
+impl Recurrence {
+    /// Creates a new `Recurrence` that repeats every `interval` days from `start`. If the `Task` has
+    /// weekdays, only the days matching them are included.
+    ///
+    /// # Panics
+    ///
+    /// If the given interval is zero.
+    pub fn daily(interval: u32, start: NaiveDate) -> Self {
+        Self::new(Frequency::Daily, interval, start, Vec::new(), Vec::new())
+    }
+
+    /// Creates a new `Recurrence` that repeats every `interval` weeks from the week of `start` on
+    /// the weekdays of the `Task`.
+    ///
+    /// # Panics
+    ///
+    /// If the given interval is zero.
+    pub fn weekly(interval: u32, start: NaiveDate) -> Self {
+        Self::new(Frequency::Weekly, interval, start, Vec::new(), Vec::new())
+    }
+
+    /// Creates a new `Recurrence` that repeats every `interval` months from the month of `start` on
+    /// the given days of the month. Negative days count from the end of the month, -1 being the last
+    /// day. If no days are given, the day of `start` is used.
+    ///
+    /// # Panics
+    ///
+    /// If the given interval is zero.
+    pub fn monthly_on_days(interval: u32, start: NaiveDate, month_days: Vec<i32>) -> Self {
+        Self::new(Frequency::Monthly, interval, start, month_days, Vec::new())
+    }
+
+    /// Creates a new `Recurrence` that repeats every `interval` months from the month of `start` on
+    /// the given nth weekdays of the month. For example `(1, Weekday::Mon)` is the first Monday and
+    /// `(-1, Weekday::Fri)` the last Friday of a month.
+    ///
+    /// # Panics
+    ///
+    /// If the given interval is zero.
+    pub fn monthly_on_nth_weekdays(interval: u32, start: NaiveDate, nth_weekdays: Vec<(i8, Weekday)>) -> Self {
+        Self::new(Frequency::Monthly, interval, start, Vec::new(), nth_weekdays)
+    }
+
+    /// Creates a new `Recurrence` that repeats every `interval` years on the month and day of
+    /// `start`.
+    ///
+    /// # Panics
+    ///
+    /// If the given interval is zero.
+    pub fn yearly(interval: u32, start: NaiveDate) -> Self {
+        Self::new(Frequency::Yearly, interval, start, Vec::new(), Vec::new())
+    }
+
+    /// Creates a new `Recurrence` with explicit values. Month days and nth weekdays are only used
+    /// with monthly and yearly frequencies.
+    ///
+    /// # Panics
+    ///
+    /// If the given interval is zero.
+    pub fn new(frequency: Frequency, interval: u32, start: NaiveDate, month_days: Vec<i32>, nth_weekdays: Vec<(i8, Weekday)>) -> Self {
+        if interval == 0 {
+            panic!("Cannot create a recurrence with an interval of zero.")
+        }
+        Self { frequency, interval, start, month_days, nth_weekdays }
+    }
+
+    /// Gets the `frequency` of the `Recurrence`.
+    pub fn frequency(&self) -> Frequency {
+        self.frequency
+    }
+
+    /// Gets the `interval` of the `Recurrence`.
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+
+    /// Gets the date from which the `Recurrence` starts. There are no occurrences before this date.
+    pub fn start(&self) -> NaiveDate {
+        self.start
+    }
+
+    /// Gets the days of the month the `Recurrence` occurs on.
+    pub fn month_days(&self) -> &Vec<i32> {
+        &self.month_days
+    }
+
+    /// Gets the nth weekdays of the month the `Recurrence` occurs on.
+    pub fn nth_weekdays(&self) -> &Vec<(i8, Weekday)> {
+        &self.nth_weekdays
+    }
+
+    /// Returns `true` if the `Recurrence` occurs on the given date. The weekdays are the by-weekday
+    /// part of the rule.
+    pub(crate) fn occurs_on(&self, date: NaiveDate, weekdays: &[Weekday]) -> bool {
+        if date < self.start {
+            return false;
+        }
+
+        let interval = self.interval as i64;
+
+        match self.frequency {
+            Frequency::Daily => {
+                (date - self.start).num_days() % interval == 0 &&
+                    (weekdays.is_empty() || weekdays.contains(&date.weekday()))
+            }
+            Frequency::Weekly => {
+                let weeks = (week_start(date) - week_start(self.start)).num_weeks();
+                weeks % interval == 0 && weekdays.contains(&date.weekday())
+            }
+            Frequency::Monthly => {
+                months_between(self.start, date) % interval == 0 && self.occurs_on_day_of_month(date)
+            }
+            Frequency::Yearly => {
+                (date.year() - self.start.year()) as i64 % interval == 0 &&
+                    date.month() == self.start.month() &&
+                    self.occurs_on_day_of_month(date)
+            }
+        }
+    }
+
+    fn occurs_on_day_of_month(&self, date: NaiveDate) -> bool {
+        if self.month_days.is_empty() && self.nth_weekdays.is_empty() {
+            return date.day() == self.start.day();
+        }
+
+        let day = date.day() as i32;
+        let last_day = days_in_month(date) as i32;
+
+        let day_matches = self.month_days.iter().any(|d| *d == day || *d == day - last_day - 1);
+        let nth_matches = self.nth_weekdays.iter().any(|(n, wd)| {
+            let n = *n as i32;
+            *wd == date.weekday() &&
+                (n == (day - 1) / 7 + 1 || n == -((last_day - day) / 7 + 1))
+        });
+
+        day_matches || nth_matches
+    }
+
+    /// Returns the first date on or after the given date that the `Recurrence` occurs on, or `None`
+    /// if it doesn't occur within the following `MAX_PERIODS` periods. Only the days of the periods
+    /// the `Recurrence` can occur in are checked, so the search is equally fast for any interval.
+    pub(crate) fn next_occurrence(&self, from: NaiveDate, weekdays: &[Weekday]) -> Option<NaiveDate> {
+        let from = from.max(self.start);
+        let interval = self.interval as i64;
+
+        // The first period that can contain the date.
+        let first = match self.frequency {
+            Frequency::Daily => { ((from - self.start).num_days() + interval - 1) / interval }
+            Frequency::Weekly => { (week_start(from) - week_start(self.start)).num_weeks() / interval }
+            Frequency::Monthly => { months_between(self.start, from) / interval }
+            Frequency::Yearly => { (from.year() - self.start.year()) as i64 / interval }
+        };
+
+        (first..first + MAX_PERIODS)
+            .map_while(|period| self.period(period * interval))
+            .flat_map(|(start, end)| start.max(from).iter_days().take_while(move |date| *date < end))
+            .find(|date| self.occurs_on(*date, weekdays))
+    }
+
+    // Gets the first day and the day after the last day of the period the given number of units
+    // after the start. Returns None if the period is outside the supported dates.
+    fn period(&self, units: i64) -> Option<(NaiveDate, NaiveDate)> {
+        let start = match self.frequency {
+            Frequency::Daily => { add_days(self.start, units)? }
+            Frequency::Weekly => { add_days(week_start(self.start), units.checked_mul(7)?)? }
+            Frequency::Monthly => { add_months(self.start.with_day(1)?, units)? }
+            Frequency::Yearly => { add_months(self.start.with_day(1)?, units.checked_mul(12)?)? }
+        };
+        let end = match self.frequency {
+            Frequency::Daily => { add_days(start, 1)? }
+            Frequency::Weekly => { add_days(start, 7)? }
+            Frequency::Monthly | Frequency::Yearly => { add_months(start, 1)? }
+        };
+        Some((start, end))
+    }
+}
+
+impl Default for Recurrence {
+    // The default recurrence is the original behaviour of Tasks: every week on the Task's weekdays.
+    fn default() -> Self {
+        Self::weekly(1, NaiveDate::from_ymd(1970, 1, 1))
+    }
+}
+
+fn deserialize_interval<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    match u32::deserialize(deserializer)? {
+        0 => { Err(D::Error::custom("the interval of a recurrence cannot be zero")) }
+        interval => { Ok(interval) }
+    }
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)
+}
+
+// Returns None if the result is outside the supported dates.
+fn add_days(date: NaiveDate, days: i64) -> Option<NaiveDate> {
+    // Larger durations cannot be represented and would panic.
+    if days.abs() > (chrono::naive::MAX_DATE - chrono::naive::MIN_DATE).num_days() {
+        return None;
+    }
+    date.checked_add_signed(chrono::Duration::days(days))
+}
+
+// Adds months to the first day of a month. Returns None if the result is outside the supported
+// dates.
+fn add_months(first_day: NaiveDate, months: i64) -> Option<NaiveDate> {
+    let month = (first_day.year() as i64 * 12 + first_day.month0() as i64).checked_add(months)?;
+    NaiveDate::from_ymd_opt(i32::try_from(month.div_euclid(12)).ok()?, month.rem_euclid(12) as u32 + 1, 1)
+}
+
+fn months_between(from: NaiveDate, to: NaiveDate) -> i64 {
+    (to.year() as i64 * 12 + to.month0() as i64) - (from.year() as i64 * 12 + from.month0() as i64)
+}
+
+fn days_in_month(date: NaiveDate) -> u32 {
+    let (year, month) = if date.month() == 12 {
+        (date.year() + 1, 1)
+    } else {
+        (date.year(), date.month() + 1)
+    };
+    NaiveDate::from_ymd(year, month, 1).pred().day()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, Weekday};
+
+    use crate::recurrence::{Frequency, Recurrence};
+
+    #[test]
+    fn daily_with_interval_skips_days() {
+        let r = Recurrence::daily(3, NaiveDate::from_ymd(2022, 6, 1));
+
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 6, 1), &[]));
+        assert!(!r.occurs_on(NaiveDate::from_ymd(2022, 6, 2), &[]));
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 6, 4), &[]));
+        assert!(!r.occurs_on(NaiveDate::from_ymd(2022, 5, 29), &[])); // Before start
+    }
+
+    #[test]
+    fn weekly_without_weekdays_never_occurs() {
+        let r = Recurrence::weekly(1, NaiveDate::from_ymd(2022, 6, 1));
+
+        for day in 1..=7 {
+            assert!(!r.occurs_on(NaiveDate::from_ymd(2022, 6, day), &[]));
+        }
+    }
+
+    #[test]
+    fn biweekly_counts_weeks_from_start_week() {
+        // 2022-6-15 is a Wednesday, the Monday of the same week is an occurrence.
+        let r = Recurrence::weekly(2, NaiveDate::from_ymd(2022, 6, 13));
+
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 6, 17), &[Weekday::Fri]));
+        assert!(!r.occurs_on(NaiveDate::from_ymd(2022, 6, 24), &[Weekday::Fri]));
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 7, 1), &[Weekday::Fri]));
+    }
+
+    #[test]
+    fn monthly_on_days_supports_negative_days() {
+        let r = Recurrence::monthly_on_days(1, NaiveDate::from_ymd(2022, 1, 1), vec![15, -1]);
+
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 2, 15), &[]));
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 2, 28), &[]));
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 12, 31), &[]));
+        assert!(!r.occurs_on(NaiveDate::from_ymd(2022, 12, 30), &[]));
+    }
+
+    #[test]
+    fn monthly_defaults_to_start_day() {
+        let r = Recurrence::new(Frequency::Monthly, 2, NaiveDate::from_ymd(2022, 1, 10), vec![], vec![]);
+
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 3, 10), &[]));
+        assert!(!r.occurs_on(NaiveDate::from_ymd(2022, 2, 10), &[]));
+    }
+
+    #[test]
+    fn monthly_on_last_weekday_of_month() {
+        let r = Recurrence::monthly_on_nth_weekdays(1, NaiveDate::from_ymd(2022, 1, 1), vec![(-1, Weekday::Fri)]);
+
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 6, 24), &[]));
+        assert!(!r.occurs_on(NaiveDate::from_ymd(2022, 6, 17), &[]));
+        assert!(r.occurs_on(NaiveDate::from_ymd(2022, 9, 30), &[]));
+    }
+
+    #[test]
+    fn yearly_occurs_on_start_month_and_day() {
+        let r = Recurrence::yearly(1, NaiveDate::from_ymd(2020, 5, 3));
+
+        assert!(r.occurs_on(NaiveDate::from_ymd(2025, 5, 3), &[]));
+        assert!(!r.occurs_on(NaiveDate::from_ymd(2025, 6, 3), &[]));
+    }
+
+    #[test]
+    fn next_occurrence_jumps_to_periods_of_large_intervals() {
+        let r = Recurrence::daily(1_000_000, NaiveDate::from_ymd(2022, 6, 1));
+        assert_eq!(r.next_occurrence(NaiveDate::from_ymd(2022, 6, 1), &[]), Some(NaiveDate::from_ymd(2022, 6, 1)));
+        assert_eq!(r.next_occurrence(NaiveDate::from_ymd(2022, 6, 2), &[]), Some(NaiveDate::from_ymd(4760, 4, 28)));
+
+        let r = Recurrence::monthly_on_days(u32::MAX, NaiveDate::from_ymd(2022, 6, 1), vec![15]);
+        assert_eq!(r.next_occurrence(NaiveDate::from_ymd(2022, 6, 16), &[]), None);
+    }
+
+    #[test]
+    fn next_occurrence_finds_leap_days() {
+        let r = Recurrence::yearly(1, NaiveDate::from_ymd(2020, 2, 29));
+        assert_eq!(r.next_occurrence(NaiveDate::from_ymd(2020, 3, 1), &[]), Some(NaiveDate::from_ymd(2024, 2, 29)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn new_panics_with_zero_interval() {
+        Recurrence::daily(0, NaiveDate::from_ymd(2022, 1, 1));
+    }
+
+    #[test]
+    fn deserializing_zero_interval_returns_err() {
+        let json = r#"{"frequency":"Daily","interval":0,"start":"2022-01-01","month_days":[],"nth_weekdays":[]}"#;
+
+        assert!(serde_json::from_str::<Recurrence>(json).is_err());
+        assert!(serde_json::from_str::<Recurrence>(&json.replace(":0,", ":2,")).is_ok());
+    }
+}
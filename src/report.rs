@@ -0,0 +1,181 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining reports describing what a synchronization changed.
+
+use serde::{Deserialize, Serialize};
+
+/// A report of the changes a synchronization made to the `Todo`s and `Task`s of both the client and
+/// the server.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncReport {
+    pub(crate) todos: ListReport,
+    pub(crate) tasks: ListReport,
+}
+
+impl SyncReport {
+    /// Gets the changes made to `Todo`s.
+    pub fn todos(&self) -> &ListReport {
+        &self.todos
+    }
+
+    /// Gets the changes made to `Task`s.
+    pub fn tasks(&self) -> &ListReport {
+        &self.tasks
+    }
+
+    /// Returns `true` if the synchronization didn't change anything.
+    pub fn is_empty(&self) -> bool {
+        self.todos.is_empty() && self.tasks.is_empty()
+    }
+
+    // See ListReport::set_client_changes.
+    pub(crate) fn set_client_changes(&mut self, client: SyncReport) {
+        self.todos.set_client_changes(client.todos);
+        self.tasks.set_client_changes(client.tasks);
+    }
+}
+
+/// Changes made to a single type of items during a synchronization.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListReport {
+    pub(crate) added_to_client: Vec<ReportItem>,
+    pub(crate) removed_from_client: Vec<ReportItem>,
+    pub(crate) updated_on_client: Vec<ReportItem>,
+    pub(crate) added_to_server: Vec<ReportItem>,
+    pub(crate) removed_from_server: Vec<ReportItem>,
+    pub(crate) updated_on_server: Vec<ReportItem>,
+    pub(crate) conflicts: Vec<Conflict>,
+}
+
+impl ListReport {
+    /// Gets the items copied from the server to the client.
+    pub fn added_to_client(&self) -> &Vec<ReportItem> {
+        &self.added_to_client
+    }
+
+    /// Gets the items removed from the client because they were removed from the server.
+    pub fn removed_from_client(&self) -> &Vec<ReportItem> {
+        &self.removed_from_client
+    }
+
+    /// Gets the items on the client that were updated to match the server.
+    pub fn updated_on_client(&self) -> &Vec<ReportItem> {
+        &self.updated_on_client
+    }
+
+    /// Gets the items copied from the client to the server.
+    pub fn added_to_server(&self) -> &Vec<ReportItem> {
+        &self.added_to_server
+    }
+
+    /// Gets the items removed from the server because they were removed on the client.
+    pub fn removed_from_server(&self) -> &Vec<ReportItem> {
+        &self.removed_from_server
+    }
+
+    /// Gets the items on the server that were updated with modifications from the client.
+    pub fn updated_on_server(&self) -> &Vec<ReportItem> {
+        &self.updated_on_server
+    }
+
+    /// Gets the fields that were modified on both the client and the server since the client last
+    /// synchronized.
+    pub fn conflicts(&self) -> &Vec<Conflict> {
+        &self.conflicts
+    }
+
+    // Replaces the changes made to the client with the ones of another report. Used when the server
+    // and the client each report the changes made to their own items.
+    pub(crate) fn set_client_changes(&mut self, client: ListReport) {
+        self.added_to_client = client.added_to_client;
+        self.removed_from_client = client.removed_from_client;
+        self.updated_on_client = client.updated_on_client;
+    }
+
+    /// Returns `true` if the synchronization didn't change any items of this type.
+    pub fn is_empty(&self) -> bool {
+        self.added_to_client.is_empty() &&
+            self.removed_from_client.is_empty() &&
+            self.updated_on_client.is_empty() &&
+            self.added_to_server.is_empty() &&
+            self.removed_from_server.is_empty() &&
+            self.updated_on_server.is_empty() &&
+            self.conflicts.is_empty()
+    }
+}
+
+/// An item affected by a synchronization.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReportItem {
+    sync_id: u64,
+    body: String,
+}
+
+impl ReportItem {
+    pub(crate) fn new(sync_id: u64, body: &str) -> Self {
+        Self { sync_id, body: body.to_string() }
+    }
+
+    /// Gets the `sync_id` which identifies the item on every device.
+    pub fn sync_id(&self) -> u64 {
+        self.sync_id
+    }
+
+    /// Gets the body of the item after the synchronization.
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+}
+
+/// A field of an item that was modified both on the client and on the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Conflict {
+    item: ReportItem,
+    field: String,
+    resolution: Resolution,
+}
+
+impl Conflict {
+    pub(crate) fn new(item: ReportItem, field: &'static str, resolution: Resolution) -> Self {
+        Self { item, field: field.to_string(), resolution }
+    }
+
+    /// Gets the conflicting item.
+    pub fn item(&self) -> &ReportItem {
+        &self.item
+    }
+
+    /// Gets the name of the conflicting field, for example `"body"`.
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// Gets how the conflict was resolved.
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+}
+
+/// Describes which modification was kept when resolving a `Conflict`. The most recent modification is
+/// always kept.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Resolution {
+    /// The value modified on the client was kept.
+    KeptClient,
+    /// The value modified on the server was kept.
+    KeptServer,
+}
@@ -0,0 +1,228 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining the versioning of serialized `TdList`s and `Config`s. Every JSON document
+//! contains a `schema_version` field, documents without one being version 0. Older documents are
+//! upgraded on load by running the migrations from their version to the current one in order.
+//!
+//! To change the format of a document, add a migration to the end of its list. The schema version
+//! is the number of migrations, so it is incremented automatically.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+use crate::{Error, Result};
+
+/// A function upgrading a JSON document from one schema version to the next.
+type Migration = fn(&mut Value) -> Result<()>;
+
+/// Migrations for `TdList` documents. The migration at index `i` upgrades version `i` to `i + 1`.
+const TD_LIST_MIGRATIONS: &[Migration] = &[td_list_v0_to_v1, td_list_v1_to_v2];
+
+/// Migrations for `Config` documents. The migration at index `i` upgrades version `i` to `i + 1`.
+const CONFIG_MIGRATIONS: &[Migration] = &[config_v0_to_v1];
+
+/// The current schema version of serialized `TdList`s, both saved and sent over the network.
+pub const TD_LIST_SCHEMA_VERSION: u32 = TD_LIST_MIGRATIONS.len() as u32;
+
+/// The current schema version of serialized `Config`s.
+pub const CONFIG_SCHEMA_VERSION: u32 = CONFIG_MIGRATIONS.len() as u32;
+
+/// The name of the field containing the schema version.
+const VERSION_FIELD: &str = "schema_version";
+
+/// Serializes a `TdList` with the current schema version.
+pub(crate) fn td_list_to_json<T: Serialize>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string(&to_versioned_value(value, TD_LIST_SCHEMA_VERSION)?)?)
+}
+
+/// Deserializes a `TdList` of any supported schema version.
+pub(crate) fn td_list_from_json<T: DeserializeOwned>(json: &str) -> Result<T> {
+    from_versioned_json(json, TD_LIST_MIGRATIONS)
+}
+
+/// Serializes a `Config` with the current schema version.
+pub(crate) fn config_to_json<T: Serialize>(value: &T) -> Result<String> {
+    Ok(serde_json::to_string_pretty(&to_versioned_value(value, CONFIG_SCHEMA_VERSION)?)?)
+}
+
+/// Deserializes a `Config` of any supported schema version.
+pub(crate) fn config_from_json<T: DeserializeOwned>(json: &str) -> Result<T> {
+    from_versioned_json(json, CONFIG_MIGRATIONS)
+}
+
+fn to_versioned_value<T: Serialize>(value: &T, version: u32) -> Result<Value> {
+    let mut value = serde_json::to_value(value)?;
+    if let Value::Object(map) = &mut value {
+        map.insert(VERSION_FIELD.to_string(), version.into());
+    }
+    Ok(value)
+}
+
+fn from_versioned_json<T: DeserializeOwned>(json: &str, migrations: &[Migration]) -> Result<T> {
+    let mut value: Value = serde_json::from_str(json)?;
+
+    let current = migrations.len() as u32;
+    let version = version_of(&value, current)?;
+
+    if version > current {
+        return Err(Error::UnsupportedSchemaVersion(version, current));
+    }
+
+    for migration in &migrations[version as usize..] {
+        migration(&mut value)?;
+    }
+
+    if let Value::Object(map) = &mut value {
+        map.remove(VERSION_FIELD);
+    }
+
+    Ok(serde_json::from_value(value)?)
+}
+
+fn version_of(value: &Value, current: u32) -> Result<u32> {
+    match value.get(VERSION_FIELD) {
+        None => { Ok(0) }
+        Some(v) => {
+            v.as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or(Error::UnsupportedSchemaVersion(u32::MAX, current))
+        }
+    }
+}
+
+/// Version 0 lists were written before ids were kept stable, and don't store the next free id.
+fn td_list_v0_to_v1(value: &mut Value) -> Result<()> {
+    for list in ["todos", "tasks"] {
+        let list = match value.get_mut(list).and_then(Value::as_object_mut) {
+            Some(list) => { list }
+            None => { continue; }
+        };
+
+        if list.contains_key("next_id") {
+            continue;
+        }
+
+        let next_id = list.get("items")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|item| item.get("id").and_then(Value::as_u64))
+            .map(|id| id + 1)
+            .max()
+            .unwrap_or(0);
+        list.insert("next_id".to_string(), next_id.into());
+    }
+
+    Ok(())
+}
+
+/// Version 1 tasks only store the latest date they were done on for each weekday. The dates become
+/// the first entries of the completion history.
+fn td_list_v1_to_v2(value: &mut Value) -> Result<()> {
+    let items = value.get_mut("tasks")
+        .and_then(|tasks| tasks.get_mut("items"))
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten();
+
+    for task in items {
+        let task = match task.as_object_mut() {
+            Some(task) => { task }
+            None => { continue; }
+        };
+
+        let modified = task.get_mut("modified")
+            .and_then(Value::as_object_mut)
+            .and_then(|modified| modified.remove("done_map"))
+            .unwrap_or_else(|| "1970-01-01T00:00:00Z".into());
+
+        let history: Map<String, Value> = match task.remove("done_map") {
+            Some(Value::Object(done_map)) => {
+                done_map.into_iter()
+                    .filter_map(|(_, date)| date.as_str().map(str::to_string))
+                    .map(|date| (date, json!({ "status": "Done", "modified": modified })))
+                    .collect()
+            }
+            _ => { Map::new() }
+        };
+        task.insert("history".to_string(), history.into());
+    }
+
+    Ok(())
+}
+
+/// Version 0 configs only lack the fields that have defaults.
+fn config_v0_to_v1(_value: &mut Value) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use serde_json::Value;
+
+    use crate::{Config, Error, TD_LIST_SCHEMA_VERSION, TdList, Todo};
+
+    #[test]
+    fn td_list_json_contains_schema_version() {
+        let json = TdList::new_client().to_json().unwrap();
+        let value: Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(value["schema_version"], TD_LIST_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn unversioned_td_list_is_migrated() {
+        let json = r#"{"todos":{"items":[{"body":"Todo","date":"2022-07-01","id":4,"done":null,"sync_id":9,"state":"Unchanged"}],"server":false},"tasks":{"items":[],"server":false},"server":false}"#;
+
+        let mut list = TdList::new_from_json(json).unwrap();
+        list.add_todo(Todo::new_undated("New todo".to_string()));
+
+        assert_eq!(list.todos()[1].id(), 5);
+    }
+
+    #[test]
+    fn version_1_task_done_dates_are_migrated_to_history() {
+        let json = r#"{"schema_version":1,"todos":{"items":[],"server":false},"tasks":{"items":[{"body":"Task","weekdays":["Mon","Tue"],"done_map":{"Mon":"2022-06-13","Tue":"2022-06-07"},"id":0,"state":"Unchanged","sync_id":1,"modified":{"body":"2022-06-01T00:00:00Z","weekdays":"2022-06-01T00:00:00Z","recurrence":"2022-06-01T00:00:00Z","done_map":"2022-06-13T12:00:00Z"}}],"server":false},"server":false}"#;
+
+        let list = TdList::new_from_json(json).unwrap();
+        let task = &list.tasks()[0];
+
+        assert!(task.done(NaiveDate::from_ymd(2022, 6, 13)));
+        assert!(task.done(NaiveDate::from_ymd(2022, 6, 7)));
+        assert!(!task.done(NaiveDate::from_ymd(2022, 6, 14)));
+        assert_eq!(task.history().count(), 2);
+    }
+
+    #[test]
+    fn td_list_from_newer_version_returns_err() {
+        let json = format!(r#"{{"schema_version":{},"todos":{{"items":[],"server":false}},"tasks":{{"items":[],"server":false}},"server":false}}"#, TD_LIST_SCHEMA_VERSION + 1);
+
+        assert!(matches!(TdList::new_from_json(&json).unwrap_err(), Error::UnsupportedSchemaVersion(v, c) if v == TD_LIST_SCHEMA_VERSION + 1 && c == TD_LIST_SCHEMA_VERSION));
+    }
+
+    #[test]
+    fn config_roundtrips_and_unversioned_config_loads() {
+        let conf = Config::new_default(b"pw".to_vec(), "127.0.0.1:55995".to_string(), None);
+        let conf = Config::new_from_json(&conf.to_json().unwrap()).unwrap();
+        assert_eq!(conf.socket_addr(), "127.0.0.1:55995");
+
+        let json = r#"{"socket_addr":"127.0.0.1:1","encryption_password":[1],"timeout":{"secs":30,"nanos":0},"save_location":null,"local_only":true}"#;
+        assert!(Config::new_from_json(json).unwrap().local_only());
+    }
+}
@@ -0,0 +1,218 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining end-to-end encrypted storage of items on a server. Clients encrypt every item
+//! with a key derived from a password the server never sees, and the server only stores and relays
+//! the encrypted records by their sync ids. Since the server cannot read the items, clients merge
+//! them by opening the records into a server `TdList`, synchronizing with it and sealing the result.
+//! The tombstones of removed items are stored unencrypted, since the server already knows the sync
+//! ids of the records.
+
+use chrono::{DateTime, Utc};
+use rand::random;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{Limits, merge_tombstones, Result, SyncItem, TD_LIST_SCHEMA_VERSION, TdList, Tombstones};
+use crate::network::crypt;
+
+/// Encrypted `Todo`s and `Task`s stored on a server.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SealedList {
+    // The schema version of the encrypted items, so that older items can be migrated like saved
+    // lists.
+    schema_version: u32,
+    // The salt of the key. All clients need to derive their keys with the same salt, so the first
+    // client to seal items chooses it.
+    salt: Option<[u8; 16]>,
+    todos: Vec<SealedRecord>,
+    tasks: Vec<SealedRecord>,
+    #[serde(default)]
+    todo_tombstones: Tombstones,
+    #[serde(default)]
+    task_tombstones: Tombstones,
+}
+
+/// A single encrypted item. The ciphertext is bound to the kind of the item and its sync id, so the
+/// server cannot swap the contents of records.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct SealedRecord {
+    sync_id: u64,
+    ciphertext: Vec<u8>,
+}
+
+/// A key for sealing and opening the records of a `SealedList`.
+#[derive(Debug)]
+pub(crate) struct SealingKey {
+    salt: [u8; 16],
+    key: [u8; 32],
+}
+
+impl SealingKey {
+    /// Gets the salt the key was derived with.
+    pub(crate) fn salt(&self) -> [u8; 16] {
+        self.salt
+    }
+}
+
+impl SealedList {
+    /// Returns `true` if no client has sealed items to the list.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.salt.is_none()
+    }
+
+    /// Decrypts the records into a server `TdList`. Returns the list and the key for sealing it
+    /// again. Returns `Error::DecryptingFailed` if the password is wrong or a record was modified.
+    /// The decrypted items are checked against the limits before the list is parsed.
+    pub(crate) fn open(&self, password: &[u8], limits: &Limits) -> Result<(TdList, SealingKey)> {
+        let salt = self.salt.unwrap_or_else(random);
+        let key = SealingKey { salt, key: crypt::derive_key(password, &salt)? };
+
+        let todos = open_records(&self.todos, &key, "todo")?;
+        let tasks = open_records(&self.tasks, &key, "task")?;
+
+        // The items are parsed as a whole list, so they are migrated from older schema versions.
+        let list = json!({
+            "schema_version": self.schema_version,
+            "todos": { "items": todos, "server": true, "tombstones": self.todo_tombstones },
+            "tasks": { "items": tasks, "server": true, "tombstones": self.task_tombstones },
+            "server": true,
+        });
+        limits.check_list(&list)?;
+
+        Ok((TdList::new_from_json(&list.to_string())?, key))
+    }
+
+    /// Encrypts the items of a server `TdList` into a new `SealedList`.
+    pub(crate) fn seal(list: &TdList, key: &SealingKey) -> Result<Self> {
+        Ok(Self {
+            schema_version: TD_LIST_SCHEMA_VERSION,
+            salt: Some(key.salt),
+            todos: seal_records(&list.todos.items, key, "todo")?,
+            tasks: seal_records(&list.tasks.items, key, "task")?,
+            todo_tombstones: list.todos.tombstones.clone(),
+            task_tombstones: list.tasks.tombstones.clone(),
+        })
+    }
+
+    /// Returns `true` if both lists contain the same records sealed with the same key. Tombstones are
+    /// not compared, since they are kept when the records are replaced.
+    pub(crate) fn same_records(&self, other: &SealedList) -> bool {
+        self.salt == other.salt && self.todos == other.todos && self.tasks == other.tasks
+    }
+
+    /// Keeps the tombstones of an earlier version of the list and removes the records they belong
+    /// to, so that a client which doesn't know about the tombstones cannot add removed items back.
+    pub(crate) fn keep_tombstones(&mut self, old: &SealedList) {
+        merge_tombstones(&mut self.todo_tombstones, &old.todo_tombstones);
+        merge_tombstones(&mut self.task_tombstones, &old.task_tombstones);
+
+        self.todos.retain(|r| !self.todo_tombstones.contains_key(&r.sync_id));
+        self.tasks.retain(|r| !self.task_tombstones.contains_key(&r.sync_id));
+    }
+
+    /// Forgets the tombstones of items removed before the given time.
+    pub(crate) fn expire_tombstones(&mut self, before: DateTime<Utc>) {
+        self.todo_tombstones.retain(|_, removed| *removed >= before);
+        self.task_tombstones.retain(|_, removed| *removed >= before);
+    }
+}
+
+fn open_records(records: &[SealedRecord], key: &SealingKey, kind: &str) -> Result<Vec<Value>> {
+    records.iter()
+        .map(|record| {
+            let plaintext = crypt::decrypt_with_key(&record.ciphertext, &key.key, &record_aad(kind, record.sync_id))?;
+            Ok(serde_json::from_slice(&plaintext)?)
+        })
+        .collect()
+}
+
+fn seal_records<T: Serialize + SyncItem>(items: &[T], key: &SealingKey, kind: &str) -> Result<Vec<SealedRecord>> {
+    items.iter()
+        .map(|item| {
+            let plaintext = serde_json::to_vec(item)?;
+            Ok(SealedRecord {
+                sync_id: item.sync_id(),
+                ciphertext: crypt::encrypt_with_key(&plaintext, &key.key, &record_aad(kind, item.sync_id()))?,
+            })
+        })
+        .collect()
+}
+
+fn record_aad(kind: &str, sync_id: u64) -> Vec<u8> {
+    [kind.as_bytes(), &sync_id.to_le_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Error, Limits, TdList, Todo};
+    use crate::sealed::SealedList;
+
+    fn sealed_list(password: &[u8]) -> SealedList {
+        let (mut server, key) = SealedList::default().open(password, &Limits::default()).unwrap();
+        let mut client = TdList::new_client();
+        client.add_todo(Todo::new_undated("Todo 1".to_string()));
+        client.sync(&mut server);
+
+        SealedList::seal(&server, &key).unwrap()
+    }
+
+    #[test]
+    fn opening_sealed_list_returns_items() {
+        let sealed = sealed_list(b"Very secure passwd");
+
+        let (server, _) = sealed.open(b"Very secure passwd", &Limits::default()).unwrap();
+
+        assert!(server.is_server());
+        assert_eq!(server.todos(), vec![&Todo::new_undated("Todo 1".to_string())]);
+        assert!(!serde_json::to_string(&sealed).unwrap().contains("Todo 1"));
+    }
+
+    #[test]
+    fn opening_sealed_list_returns_err_with_wrong_password() {
+        let sealed = sealed_list(b"Very secure passwd");
+
+        assert!(matches!(sealed.open(b"Incorrect passwd", &Limits::default()).unwrap_err(), Error::DecryptingFailed));
+    }
+
+    #[test]
+    fn opening_sealed_list_returns_err_if_records_are_swapped() {
+        let mut sealed = sealed_list(b"Very secure passwd");
+
+        sealed.todos[0].sync_id += 1;
+
+        assert!(matches!(sealed.open(b"Very secure passwd", &Limits::default()).unwrap_err(), Error::DecryptingFailed));
+    }
+
+    #[test]
+    fn sealed_list_keeps_tombstones_of_removed_items() {
+        let sealed = sealed_list(b"Very secure passwd");
+        let (mut server, key) = sealed.open(b"Very secure passwd", &Limits::default()).unwrap();
+        let mut client = TdList::new_client();
+        client.sync(&mut server);
+
+        server.remove_todo(0).unwrap();
+        let sealed = SealedList::seal(&server, &key).unwrap();
+
+        // The client modifies the todo without knowing that it has been removed.
+        client.get_todo_mut(0).unwrap().set_body("New Todo 1".to_string());
+        let (mut server, _) = sealed.open(b"Very secure passwd", &Limits::default()).unwrap();
+        client.sync(&mut server);
+
+        assert!(client.todos().is_empty());
+        assert!(server.todos().is_empty());
+    }
+}
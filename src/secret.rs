@@ -0,0 +1,196 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining where secrets such as passwords are read from, so that they don't need to be
+//! stored in the config, and keys derived from passwords which can be stored instead of them.
+
+use std::{env, fs, io};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use rand::random;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+use crate::network::crypt;
+
+/// Where a secret is read from. Only the source is stored in a `Config`, never the secret itself.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SecretSource {
+    /// The secret is asked from the user.
+    Prompt,
+    /// The secret is read from the environment variable with the given name.
+    Env(String),
+    /// The secret is read from the given file, without the trailing newline. On Unix, the file must
+    /// only be accessible by its owner.
+    File(PathBuf),
+    /// The secret is the standard output of the given shell command, without the trailing newline.
+    Command(String),
+}
+
+impl SecretSource {
+    /// Reads the secret. `prompt` is called for asking the secret from the user if the source is
+    /// `Prompt`.
+    pub fn read(&self, prompt: impl FnOnce() -> io::Result<String>) -> Result<Vec<u8>> {
+        let secret = match self {
+            SecretSource::Prompt => { prompt()?.into_bytes() }
+            SecretSource::Env(name) => {
+                env::var_os(name)
+                    .ok_or_else(|| Error::SecretUnavailable(format!("Environment variable \"{}\" is not set.", name)))?
+                    .into_string()
+                    .map_err(|_| Error::SecretUnavailable(format!("Environment variable \"{}\" is not valid unicode.", name)))?
+                    .into_bytes()
+            }
+            SecretSource::File(path) => { SecretSource::read_file(path)? }
+            SecretSource::Command(command) => { SecretSource::run_command(command)? }
+        };
+
+        if secret.is_empty() {
+            return Err(Error::SecretUnavailable("The secret is empty.".to_string()));
+        }
+
+        Ok(secret)
+    }
+
+    fn read_file(path: &Path) -> Result<Vec<u8>> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            if fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+                return Err(Error::SecretUnavailable(format!("Secret file \"{}\" must only be accessible by its owner (chmod 600).", path.display())));
+            }
+        }
+
+        Ok(trim_newline(fs::read(path)?))
+    }
+
+    fn run_command(command: &str) -> Result<Vec<u8>> {
+        let output = if cfg!(windows) {
+            Command::new("cmd").arg("/C").arg(command).output()?
+        } else {
+            Command::new("sh").arg("-c").arg(command).output()?
+        };
+
+        if !output.status.success() {
+            return Err(Error::SecretUnavailable(format!("Command \"{}\" failed with {}.", command, output.status)));
+        }
+
+        Ok(trim_newline(output.stdout))
+    }
+}
+
+fn trim_newline(mut secret: Vec<u8>) -> Vec<u8> {
+    if secret.last() == Some(&b'\n') {
+        secret.pop();
+        if secret.last() == Some(&b'\r') {
+            secret.pop();
+        }
+    }
+    secret
+}
+
+/// A key derived from a password with Argon2. Storing a `DerivedKey` in a `Config` instead of the
+/// password allows syncing without revealing the password itself. The server and all clients need
+/// to derive their keys with the same salt.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DerivedKey {
+    salt: [u8; 16],
+    key: [u8; 32],
+}
+
+impl DerivedKey {
+    /// Derives a key from a password and a salt.
+    pub fn derive(password: &[u8], salt: [u8; 16]) -> Result<Self> {
+        Ok(Self { salt, key: crypt::derive_key(password, &salt)? })
+    }
+
+    /// Derives a key from a password and a new random salt.
+    pub fn derive_with_random_salt(password: &[u8]) -> Result<Self> {
+        DerivedKey::derive(password, random())
+    }
+
+    /// Gets the salt the key was derived with.
+    pub fn salt(&self) -> [u8; 16] {
+        self.salt
+    }
+
+    pub(crate) fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::{DerivedKey, Error, SecretSource};
+
+    #[test]
+    fn secret_source_reads_prompt() {
+        assert_eq!(SecretSource::Prompt.read(|| Ok("hunter42".to_string())).unwrap(), b"hunter42");
+    }
+
+    #[test]
+    fn secret_source_reads_env() {
+        env::set_var("MTD_SECRET_SOURCE_TEST", "hunter42");
+
+        let secret = SecretSource::Env("MTD_SECRET_SOURCE_TEST".to_string()).read(|| unreachable!()).unwrap();
+
+        assert_eq!(secret, b"hunter42");
+    }
+
+    #[test]
+    fn secret_source_returns_err_if_env_is_not_set() {
+        let source = SecretSource::Env("MTD_SECRET_SOURCE_TEST_NOT_SET".to_string());
+
+        assert!(matches!(source.read(|| unreachable!()).unwrap_err(), Error::SecretUnavailable(_)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_source_reads_command_output() {
+        let source = SecretSource::Command("echo hunter42".to_string());
+
+        assert_eq!(source.read(|| unreachable!()).unwrap(), b"hunter42");
+        assert!(SecretSource::Command("exit 1".to_string()).read(|| unreachable!()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_source_reads_file_only_if_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join("mtd-secret-source-test-file");
+        fs::write(&path, "hunter42\n").unwrap();
+        let source = SecretSource::File(path.clone());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(source.read(|| unreachable!()).unwrap_err(), Error::SecretUnavailable(_)));
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(source.read(|| unreachable!()).unwrap(), b"hunter42");
+    }
+
+    #[test]
+    fn derived_key_depends_on_password_and_salt() {
+        let key = DerivedKey::derive(b"hunter42", [1; 16]).unwrap();
+
+        assert_eq!(key, DerivedKey::derive(b"hunter42", [1; 16]).unwrap());
+        assert_ne!(key, DerivedKey::derive(b"hunter43", [1; 16]).unwrap());
+        assert_ne!(key.key(), DerivedKey::derive(b"hunter42", [2; 16]).unwrap().key());
+    }
+}
@@ -0,0 +1,426 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining statistics of how `Task`s and `Todo`s have been completed, such as streaks of
+//! done occurrences and completion rates. The statistics are computed from the completion history of
+//! `Task`s and the done dates of `Todo`s.
+
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use chrono::naive::MAX_DATE;
+use serde::{Deserialize, Serialize};
+
+use crate::{CompletionStatus, Task, TdList};
+
+/// The length of the periods completed `Todo`s are counted in.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+pub enum Period {
+    /// A single day.
+    Day,
+    /// A week starting on Monday.
+    Week,
+    /// A calendar month.
+    Month,
+}
+
+/// Completion statistics of a `TdList` between two dates.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Stats {
+    from: NaiveDate,
+    to: NaiveDate,
+    tasks: Vec<TaskStats>,
+    weekdays: Vec<WeekdayStats>,
+    todos: Vec<PeriodStats>,
+}
+
+impl Stats {
+    /// Gets the first date of the statistics.
+    pub fn from(&self) -> NaiveDate {
+        self.from
+    }
+
+    /// Gets the last date of the statistics.
+    pub fn to(&self) -> NaiveDate {
+        self.to
+    }
+
+    /// Gets the statistics of each `Task`.
+    pub fn tasks(&self) -> &Vec<TaskStats> {
+        &self.tasks
+    }
+
+    /// Gets the statistics of the occurrences of all `Task`s by weekday, starting from Monday.
+    pub fn weekdays(&self) -> &Vec<WeekdayStats> {
+        &self.weekdays
+    }
+
+    /// Gets the number of `Todo`s completed in each period.
+    pub fn todos(&self) -> &Vec<PeriodStats> {
+        &self.todos
+    }
+}
+
+/// The number of occurrences of `Task`s and how they were completed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Occurrences {
+    total: u64,
+    done: u64,
+    skipped: u64,
+}
+
+impl Occurrences {
+    /// Gets the number of occurrences.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Gets the number of done occurrences.
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    /// Gets the number of skipped occurrences.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Returns the share of the occurrences that were done. Skipped occurrences are not counted.
+    /// Returns `None` if there are no occurrences to count.
+    pub fn completion_rate(&self) -> Option<f64> {
+        let counted = self.total - self.skipped;
+        if counted == 0 {
+            None
+        } else {
+            Some(self.done as f64 / counted as f64)
+        }
+    }
+
+    fn add(&mut self, status: Option<CompletionStatus>) {
+        self.total += 1;
+        match status {
+            Some(CompletionStatus::Done) => { self.done += 1; }
+            Some(CompletionStatus::Skipped) => { self.skipped += 1; }
+            Some(CompletionStatus::Undone) | None => {}
+        }
+    }
+}
+
+/// Completion statistics of a single `Task`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TaskStats {
+    id: u64,
+    body: String,
+    occurrences: Occurrences,
+    completion_rate: Option<f64>,
+    current_streak: u64,
+    longest_streak: u64,
+}
+
+impl TaskStats {
+    /// Gets the `id` of the `Task`.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Gets the `body` of the `Task`.
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    /// Gets the occurrences of the `Task` between the dates of the statistics.
+    pub fn occurrences(&self) -> Occurrences {
+        self.occurrences
+    }
+
+    /// Returns the share of the occurrences of the `Task` that were done. See
+    /// `Occurrences::completion_rate`.
+    pub fn completion_rate(&self) -> Option<f64> {
+        self.completion_rate
+    }
+
+    /// Gets the number of the latest occurrences in a row that were done. Skipped occurrences don't
+    /// break a streak, and neither does the occurrence on the last date of the statistics if it
+    /// isn't done yet.
+    pub fn current_streak(&self) -> u64 {
+        self.current_streak
+    }
+
+    /// Gets the largest number of occurrences in a row that were done. See `current_streak`.
+    pub fn longest_streak(&self) -> u64 {
+        self.longest_streak
+    }
+}
+
+/// Completion statistics of the occurrences of all `Task`s on a single weekday.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WeekdayStats {
+    weekday: Weekday,
+    occurrences: Occurrences,
+    completion_rate: Option<f64>,
+}
+
+impl WeekdayStats {
+    /// Gets the weekday.
+    pub fn weekday(&self) -> Weekday {
+        self.weekday
+    }
+
+    /// Gets the occurrences of all `Task`s on the weekday.
+    pub fn occurrences(&self) -> Occurrences {
+        self.occurrences
+    }
+
+    /// Returns the share of the occurrences on the weekday that were done. See
+    /// `Occurrences::completion_rate`.
+    pub fn completion_rate(&self) -> Option<f64> {
+        self.completion_rate
+    }
+}
+
+/// The number of `Todo`s completed during a period.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PeriodStats {
+    start: NaiveDate,
+    end: NaiveDate,
+    todos_done: u64,
+}
+
+impl PeriodStats {
+    /// Gets the first date of the period.
+    pub fn start(&self) -> NaiveDate {
+        self.start
+    }
+
+    /// Gets the last date of the period.
+    pub fn end(&self) -> NaiveDate {
+        self.end
+    }
+
+    /// Gets the number of `Todo`s done during the period.
+    pub fn todos_done(&self) -> u64 {
+        self.todos_done
+    }
+}
+
+impl TdList {
+    /// Computes the completion statistics of the `Task`s and `Todo`s between the given dates, both
+    /// included. Streaks are counted from the whole completion history of each `Task` up to `to`.
+    /// Completed `Todo`s are counted in periods of the given length, the first and the last period
+    /// being cut to the dates.
+    ///
+    /// Done `Todo`s are removed a day after their completion, so clients remember the done dates
+    /// of the `Todo`s they remove. `Todo`s that were done and removed on other devices before this
+    /// client synced them are not counted.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use chrono::{NaiveDate, Weekday};
+    /// use mtd::{Period, Task, TdList};
+    ///
+    /// let mut list = TdList::new_client();
+    /// list.add_task(Task::new("Run".to_string(), vec![Weekday::Mon]));
+    ///
+    /// // Done on three Mondays in a row.
+    /// for day in [6, 13, 20] {
+    ///     list.get_task_mut(0).unwrap().set_done(true, NaiveDate::from_ymd(2022, 6, day));
+    /// }
+    ///
+    /// let stats = list.stats(NaiveDate::from_ymd(2022, 6, 1), NaiveDate::from_ymd(2022, 6, 30), Period::Week);
+    ///
+    /// assert_eq!(stats.tasks()[0].current_streak(), 0); // Not done on 2022-06-27.
+    /// assert_eq!(stats.tasks()[0].longest_streak(), 3);
+    /// assert_eq!(stats.tasks()[0].completion_rate(), Some(0.75));
+    /// ```
+    pub fn stats(&self, from: NaiveDate, to: NaiveDate, period: Period) -> Stats {
+        let mut weekdays: BTreeMap<u32, Occurrences> = BTreeMap::new();
+        let mut tasks = Vec::new();
+
+        for task in self.tasks() {
+            let mut occurrences = Occurrences::default();
+            for date in dates(from, to).filter(|date| task.for_date(*date)) {
+                let status = task.completion(date).map(|c| c.status());
+                occurrences.add(status);
+                weekdays.entry(date.weekday().num_days_from_monday()).or_default().add(status);
+            }
+
+            let (current_streak, longest_streak) = streaks(task, to);
+            tasks.push(TaskStats {
+                id: task.id(),
+                body: task.body().to_string(),
+                occurrences,
+                completion_rate: occurrences.completion_rate(),
+                current_streak,
+                longest_streak,
+            });
+        }
+
+        let weekdays = (0..7)
+            .map(|n| {
+                let occurrences = weekdays.get(&n).copied().unwrap_or_default();
+                WeekdayStats { weekday: weekday_from_monday(n), occurrences, completion_rate: occurrences.completion_rate() }
+            })
+            .collect();
+
+        Stats { from, to, tasks, weekdays, todos: self.todo_periods(from, to, period) }
+    }
+
+    // Counts the done todos in each period between the dates. The todos are matched by their sync
+    // ids, so that a todo that is both remembered and still in the list is counted once.
+    fn todo_periods(&self, from: NaiveDate, to: NaiveDate, period: Period) -> Vec<PeriodStats> {
+        let mut done_dates = self.completed_todos.clone();
+        for todo in &self.todos.items {
+            if let Some(done) = todo.done {
+                done_dates.insert(todo.sync_id, done);
+            }
+        }
+
+        let mut periods = Vec::new();
+        let mut start = from;
+        while start <= to {
+            let end = period_end(start, period).min(to);
+            let todos_done = done_dates.values().filter(|done| (start..=end).contains(*done)).count() as u64;
+            periods.push(PeriodStats { start, end, todos_done });
+
+            start = match end.succ_opt() {
+                Some(next) => { next }
+                None => { break; }
+            };
+        }
+
+        periods
+    }
+}
+
+fn dates(from: NaiveDate, to: NaiveDate) -> impl Iterator<Item=NaiveDate> {
+    from.iter_days().take_while(move |date| *date <= to)
+}
+
+// Returns the current and the longest streak of the task up to the given date.
+fn streaks(task: &Task, to: NaiveDate) -> (u64, u64) {
+    let first = match task.history().next() {
+        Some((date, _)) => { date }
+        None => { return (0, 0); }
+    };
+
+    let mut current = 0;
+    let mut longest = 0;
+
+    for date in dates(first, to).filter(|date| task.for_date(*date)) {
+        match task.completion(date).map(|c| c.status()) {
+            Some(CompletionStatus::Done) => {
+                current += 1;
+                longest = longest.max(current);
+            }
+            Some(CompletionStatus::Skipped) => {}
+            // The last occurrence can still be done.
+            _ if date == to => {}
+            _ => { current = 0; }
+        }
+    }
+
+    (current, longest)
+}
+
+// Returns the last date of the period containing the given date.
+fn period_end(date: NaiveDate, period: Period) -> NaiveDate {
+    let end = match period {
+        Period::Day => { Some(date) }
+        Period::Week => { date.checked_add_signed(chrono::Duration::days(6 - date.weekday().num_days_from_monday() as i64)) }
+        Period::Month => {
+            let (year, month) = if date.month() == 12 { (date.year() + 1, 1) } else { (date.year(), date.month() + 1) };
+            NaiveDate::from_ymd_opt(year, month, 1).and_then(|first| first.pred_opt())
+        }
+    };
+    end.unwrap_or(MAX_DATE)
+}
+
+fn weekday_from_monday(n: u32) -> Weekday {
+    (0..n).fold(Weekday::Mon, |weekday, _| weekday.succ())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, Weekday};
+
+    use crate::{CompletionStatus, Period, Task, TdList, Todo};
+
+    #[test]
+    fn stats_streaks_ignore_skipped_and_pending_occurrences() {
+        let mut list = TdList::new_client();
+        list.add_task(Task::new("Task".to_string(), vec![Weekday::Mon]));
+        let task = list.get_task_mut(0).unwrap();
+
+        task.set_done(true, NaiveDate::from_ymd(2022, 5, 30));
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 6));
+        task.set_completion(NaiveDate::from_ymd(2022, 6, 13), CompletionStatus::Skipped, None);
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 20));
+
+        let stats = list.stats(NaiveDate::from_ymd(2022, 6, 1), NaiveDate::from_ymd(2022, 6, 27), Period::Week);
+        let task = &stats.tasks()[0];
+
+        assert_eq!(task.current_streak(), 3);
+        assert_eq!(task.longest_streak(), 3);
+        assert_eq!(task.occurrences().total(), 4);
+        assert_eq!(task.occurrences().skipped(), 1);
+        assert_eq!(task.completion_rate(), Some(2.0 / 3.0));
+    }
+
+    #[test]
+    fn stats_breaks_streak_on_missed_occurrence() {
+        let mut list = TdList::new_client();
+        list.add_task(Task::new("Task".to_string(), vec![Weekday::Mon, Weekday::Wed]));
+        let task = list.get_task_mut(0).unwrap();
+
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 6));
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 8));
+        task.set_done(true, NaiveDate::from_ymd(2022, 6, 15));
+
+        let stats = list.stats(NaiveDate::from_ymd(2022, 6, 6), NaiveDate::from_ymd(2022, 6, 16), Period::Day);
+
+        assert_eq!(stats.tasks()[0].current_streak(), 1);
+        assert_eq!(stats.tasks()[0].longest_streak(), 2);
+
+        let monday = &stats.weekdays()[0];
+        assert_eq!(monday.weekday(), Weekday::Mon);
+        assert_eq!(monday.completion_rate(), Some(0.5));
+        assert_eq!(stats.weekdays()[2].completion_rate(), Some(1.0));
+        assert_eq!(stats.weekdays()[1].completion_rate(), None);
+    }
+
+    #[test]
+    fn stats_counts_removed_done_todos_per_period() {
+        let mut list = TdList::new_client();
+        list.add_todo(Todo::new_undated("Todo 1".to_string()));
+        list.add_todo(Todo::new_undated("Todo 2".to_string()));
+        list.add_todo(Todo::new_undated("Todo 3".to_string()));
+
+        list.get_todo_mut(0).unwrap().set_done_wtd(true, NaiveDate::from_ymd(2022, 6, 30));
+        list.get_todo_mut(1).unwrap().set_done_wtd(true, NaiveDate::from_ymd(2022, 7, 1));
+        list.remove_old_todos_wtd(NaiveDate::from_ymd(2022, 7, 2));
+        list.self_sync();
+
+        let stats = list.stats(NaiveDate::from_ymd(2022, 6, 15), NaiveDate::from_ymd(2022, 7, 10), Period::Month);
+        let periods: Vec<_> = stats.todos().iter().map(|p| (p.start(), p.end(), p.todos_done())).collect();
+
+        assert_eq!(list.todos().len(), 1);
+        assert_eq!(periods, vec![
+            (NaiveDate::from_ymd(2022, 6, 15), NaiveDate::from_ymd(2022, 6, 30), 1),
+            (NaiveDate::from_ymd(2022, 7, 1), NaiveDate::from_ymd(2022, 7, 10), 1),
+        ]);
+    }
+}
@@ -0,0 +1,300 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining crash-safe reading and writing of saved files. Files are never overwritten in
+//! place, and the previous version of a file is kept as a backup. Processes sharing a file can
+//! coordinate their access with a `FileLock`. Saved data can optionally be encrypted.
+
+use std::{fs, thread};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use fs2::FileExt;
+
+use crate::{Error, Result};
+use crate::network::crypt;
+
+/// Bytes that start an encrypted file.
+const ENCRYPTED_MAGIC: &[u8; 8] = b"MTDCRYPT";
+
+/// Writes `contents` to `path` so that a crash or a full disk never leaves a partially written file.
+/// The contents are first written and flushed to a temporary file which then replaces `path`. The
+/// previous file is kept as a backup at `backup_path(path)`.
+pub fn save_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = sibling_path(path, "tmp");
+
+    let mut tmp = fs::File::create(&tmp_path)?;
+    tmp.write_all(contents)?;
+    tmp.sync_all()?;
+    drop(tmp);
+
+    if path.exists() {
+        // Linking keeps `path` in place, so there is no moment without a saved file.
+        let backup = backup_path(path);
+        if backup.exists() {
+            fs::remove_file(&backup)?;
+        }
+        if fs::hard_link(path, &backup).is_err() {
+            fs::copy(path, &backup)?;
+        }
+    }
+
+    fs::rename(&tmp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// Encrypts the contents of a file with a password using AES-GCM and a key derived with Argon2.
+pub(crate) fn encrypt_contents(plaintext: &[u8], passwd: &[u8]) -> Result<Vec<u8>> {
+    Ok([ENCRYPTED_MAGIC.as_slice(), &crypt::encrypt(plaintext, passwd)?].concat())
+}
+
+/// Decrypts file contents encrypted with `encrypt_contents`.
+pub(crate) fn decrypt_contents(data: &[u8], passwd: &[u8]) -> Result<Vec<u8>> {
+    match data.strip_prefix(ENCRYPTED_MAGIC.as_slice()) {
+        Some(ciphertext) => { crypt::decrypt(ciphertext, passwd) }
+        None => { Err(Error::DecryptingFailed) }
+    }
+}
+
+/// Returns `true` if the file contents were encrypted with `encrypt_contents`.
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// Reads and parses the file at `path` with `parse`. If the file cannot be read or parsed, the backup
+/// written by `save_atomic` is used instead. Returns `None` if the file doesn't exist.
+///
+/// Returns an `Err` containing the original error if neither the file nor its backup can be used.
+/// The backup isn't used if the file was written by a newer version of mtd or cannot be decrypted,
+/// since the file may still be intact.
+pub fn load_with_backup<T>(path: &Path, parse: impl Fn(&[u8]) -> Result<T>) -> Result<Option<Loaded<T>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let error = match fs::read(path).map_err(Error::from).and_then(|data| parse(&data)) {
+        Ok(value) => {
+            return Ok(Some(Loaded { value, main_error: None }));
+        }
+        // A newer version or a wrong password doesn't mean that the file is damaged. Falling back to
+        // an older copy would lose the newer data when the file is saved again.
+        Err(e @ (Error::UnsupportedSchemaVersion(..) | Error::DecryptingFailed | Error::SecretUnavailable(_))) => {
+            return Err(e);
+        }
+        Err(e) => { e }
+    };
+
+    match fs::read(backup_path(path)).map_err(Error::from).and_then(|data| parse(&data)) {
+        Ok(value) => { Ok(Some(Loaded { value, main_error: Some(error) })) }
+        Err(_) => { Err(error) }
+    }
+}
+
+/// Gets the path of the backup `save_atomic` keeps of the previous version of the file at `path`.
+pub fn backup_path(path: &Path) -> PathBuf {
+    sibling_path(path, "bak")
+}
+
+/// A value loaded with `load_with_backup`.
+#[derive(Debug)]
+pub struct Loaded<T> {
+    value: T,
+    main_error: Option<Error>,
+}
+
+impl<T> Loaded<T> {
+    /// Gets the loaded value.
+    pub fn into_value(self) -> T {
+        self.value
+    }
+
+    /// Returns the error that prevented loading the file if the value was loaded from the backup
+    /// instead.
+    pub fn main_error(&self) -> Option<&Error> {
+        self.main_error.as_ref()
+    }
+}
+
+/// An advisory lock preventing other processes from modifying a file at the same time. The lock is
+/// released when the `FileLock` is dropped.
+///
+/// The lock is taken on a separate `.lock` file next to the locked file, because `save_atomic`
+/// replaces the locked file itself.
+#[derive(Debug)]
+pub struct FileLock {
+    file: fs::File,
+}
+
+impl FileLock {
+    /// Locks the file at `path`. If another process holds the lock, waits until it is released or
+    /// `timeout` has passed. Returns `Error::FileLocked` if the lock couldn't be acquired in time.
+    pub fn acquire(path: &Path, timeout: Duration) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(sibling_path(path, "lock"))?;
+
+        let start = Instant::now();
+        while file.try_lock_exclusive().is_err() {
+            if start.elapsed() >= timeout {
+                return Err(Error::FileLocked(path.to_path_buf()));
+            }
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+
+        Ok(Self { file })
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+/// How often `FileLock::acquire` retries taking a lock held by another process.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Gets a path in the same directory as `path` with `extension` appended to the file name.
+fn sibling_path(path: &Path, extension: &str) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+    path.with_file_name(file_name)
+}
+
+/// Makes sure that a rename in the directory of `path` is persisted.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            fs::File::open(parent)?.sync_all()?;
+        }
+        _ => {
+            fs::File::open(".")?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, thread};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::{Error, Result, TdList, Todo};
+    use crate::storage::{backup_path, decrypt_contents, encrypt_contents, FileLock, is_encrypted, load_with_backup, save_atomic, sibling_path};
+
+    fn parse_list(data: &[u8]) -> Result<TdList> {
+        TdList::new_from_json(&String::from_utf8_lossy(data))
+    }
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(backup_path(&path));
+        path
+    }
+
+    #[test]
+    fn save_atomic_keeps_previous_version_as_backup() {
+        let path = test_path("mtd-storage-test-backup");
+
+        save_atomic(&path, b"First").unwrap();
+        save_atomic(&path, b"Second").unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Second");
+        assert_eq!(fs::read_to_string(backup_path(&path)).unwrap(), "First");
+        assert!(!sibling_path(&path, "tmp").exists());
+    }
+
+    #[test]
+    fn load_with_backup_returns_none_if_file_does_not_exist() {
+        let path = test_path("mtd-storage-test-missing");
+
+        assert!(load_with_backup(&path, parse_list).unwrap().is_none());
+    }
+
+    #[test]
+    fn load_with_backup_falls_back_to_backup_if_file_is_corrupted() {
+        let path = test_path("mtd-storage-test-corrupted");
+
+        let mut list = TdList::new_client();
+        list.add_todo(Todo::new_undated("Todo 1".to_string()));
+        save_atomic(&path, list.to_json().unwrap().as_bytes()).unwrap();
+        save_atomic(&path, list.to_json().unwrap().as_bytes()).unwrap();
+
+        // Simulate a truncated write done by an earlier version.
+        fs::write(&path, &list.to_json().unwrap()[..20]).unwrap();
+
+        let loaded = load_with_backup(&path, parse_list).unwrap().unwrap();
+        assert!(loaded.main_error().is_some());
+        assert_eq!(loaded.into_value().todos(), vec![&Todo::new_undated("Todo 1".to_string())]);
+    }
+
+    #[test]
+    fn load_with_backup_returns_err_if_file_and_backup_are_corrupted() {
+        let path = test_path("mtd-storage-test-all-corrupted");
+
+        fs::write(&path, "{").unwrap();
+        fs::write(backup_path(&path), "{").unwrap();
+
+        assert!(load_with_backup(&path, parse_list).is_err());
+    }
+
+    #[test]
+    fn file_lock_fails_after_timeout_if_file_is_locked() {
+        let path = test_path("mtd-storage-test-locked");
+
+        let _lock = FileLock::acquire(&path, Duration::from_secs(1)).unwrap();
+
+        assert!(matches!(FileLock::acquire(&path, Duration::from_millis(100)).unwrap_err(), Error::FileLocked(_)));
+    }
+
+    #[test]
+    fn file_lock_waits_until_lock_is_released() {
+        let path = test_path("mtd-storage-test-released");
+
+        let lock = FileLock::acquire(&path, Duration::from_secs(1)).unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+
+        assert!(FileLock::acquire(&path, Duration::from_secs(5)).is_ok());
+        release.join().unwrap();
+    }
+
+    #[test]
+    fn decrypting_encrypted_contents_returns_original() {
+        let contents = TdList::new_client().to_json().unwrap();
+
+        let encrypted = encrypt_contents(contents.as_bytes(), b"Very secure passwd").unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!is_encrypted(contents.as_bytes()));
+        assert_eq!(decrypt_contents(&encrypted, b"Very secure passwd").unwrap(), contents.as_bytes());
+        assert!(decrypt_contents(&encrypted, b"Incorrect passwd").is_err());
+    }
+}
@@ -0,0 +1,608 @@
+/*
+This file is a part of mtd.
+
+Copyright (C) 2022 Windore
+
+Mtd is free software: you can redistribute it and/or modify it under the terms of the GNU General Public
+License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later
+version.
+
+Mtd is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied
+warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License along with this program. If not,
+see <https://www.gnu.org/licenses/>.
+ */
+
+//! A module defining the transports `MtdNetMgr` can synchronize over. A transport only needs to
+//! provide reliable, ordered byte streams between a client and a server, since the sync protocol
+//! encrypts and authenticates everything itself. Besides sockets, a client can sync through a
+//! command such as SSH.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::time::Duration;
+
+/// A way of connecting a client to a server.
+pub trait Transport {
+    /// The type of the connections of the transport.
+    type Stream: Stream;
+    /// The type of the listener accepting connections on the server.
+    type Listener: Listener<Stream = Self::Stream>;
+
+    /// Connects to a server.
+    fn connect(&self) -> io::Result<Self::Stream>;
+
+    /// Starts listening to connections from clients.
+    fn listen(&self) -> io::Result<Self::Listener>;
+}
+
+/// Accepts connections on a server.
+pub trait Listener {
+    /// The type of the accepted connections.
+    type Stream: Stream;
+
+    /// Accepts a connection without blocking. Returns an error of the kind
+    /// `io::ErrorKind::WouldBlock` if there are no connections to accept.
+    fn accept(&self) -> io::Result<Self::Stream>;
+}
+
+/// A connection between a client and a server.
+pub trait Stream: Read + Write + Send {
+    /// Sets how long reading and writing may block before they fail.
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+
+    /// Describes the other end of the connection for logging.
+    fn peer(&self) -> String;
+}
+
+/// A transport over TCP.
+#[derive(Clone, Debug)]
+pub struct TcpTransport {
+    addr: String,
+}
+
+impl TcpTransport {
+    /// Creates a new `TcpTransport` connecting to or listening on the socket address `addr`.
+    pub fn new(addr: String) -> Self {
+        Self { addr }
+    }
+}
+
+impl Transport for TcpTransport {
+    type Stream = TcpStream;
+    type Listener = TcpListener;
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        TcpStream::connect(&self.addr)
+    }
+
+    fn listen(&self) -> io::Result<TcpListener> {
+        let listener = TcpListener::bind(&self.addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        let (stream, _) = TcpListener::accept(self)?;
+        // Accepted streams may inherit the listener's non-blocking mode on some platforms.
+        stream.set_nonblocking(false)?;
+        Ok(stream)
+    }
+}
+
+impl Stream for TcpStream {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))?;
+        self.set_write_timeout(Some(timeout))
+    }
+
+    fn peer(&self) -> String {
+        self.peer_addr().map(|addr| addr.to_string()).unwrap_or_else(|_| "an unknown address".to_string())
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{UnixSocketListener, UnixTransport};
+
+#[cfg(unix)]
+mod unix {
+    use std::{fs, io};
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use crate::transport::{Listener, Stream, Transport};
+
+    /// A transport over a Unix domain socket.
+    #[derive(Clone, Debug)]
+    pub struct UnixTransport {
+        path: PathBuf,
+    }
+
+    impl UnixTransport {
+        /// Creates a new `UnixTransport` connecting to or listening on the socket at `path`.
+        pub fn new(path: PathBuf) -> Self {
+            Self { path }
+        }
+    }
+
+    impl Transport for UnixTransport {
+        type Stream = UnixStream;
+        type Listener = UnixSocketListener;
+
+        fn connect(&self) -> io::Result<UnixStream> {
+            UnixStream::connect(&self.path)
+        }
+
+        fn listen(&self) -> io::Result<UnixSocketListener> {
+            // A socket left behind by a server that didn't exit normally prevents binding, but a
+            // socket of a running server must not be taken over. Other files are never removed, as
+            // the path may simply be mistyped.
+            match fs::symlink_metadata(&self.path) {
+                Ok(metadata) if metadata.file_type().is_socket() => {
+                    if UnixStream::connect(&self.path).is_err() {
+                        fs::remove_file(&self.path)?;
+                    }
+                }
+                Ok(_) => {
+                    let msg = format!("'{}' exists and is not a socket.", self.path.display());
+                    return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => { return Err(e); }
+            }
+
+            let listener = UnixListener::bind(&self.path)?;
+            listener.set_nonblocking(true)?;
+            Ok(UnixSocketListener { listener, path: self.path.clone() })
+        }
+    }
+
+    /// A listener of a `UnixTransport`. The socket is removed when the listener is dropped.
+    #[derive(Debug)]
+    pub struct UnixSocketListener {
+        listener: UnixListener,
+        path: PathBuf,
+    }
+
+    impl Listener for UnixSocketListener {
+        type Stream = UnixStream;
+
+        fn accept(&self) -> io::Result<UnixStream> {
+            let (stream, _) = self.listener.accept()?;
+            stream.set_nonblocking(false)?;
+            Ok(stream)
+        }
+    }
+
+    impl Drop for UnixSocketListener {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    impl Stream for UnixStream {
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.set_read_timeout(Some(timeout))?;
+            self.set_write_timeout(Some(timeout))
+        }
+
+        fn peer(&self) -> String {
+            "a local socket".to_string()
+        }
+    }
+}
+
+/// A transport over the standard input and output of a command, such as
+/// `ssh host mtd serve-stdio`. The command is run with the system shell for every connection, and
+/// should run a server handling a single session over its standard input and output. Servers cannot
+/// listen on a `CommandTransport`.
+#[derive(Clone, Debug)]
+pub struct CommandTransport {
+    command: String,
+}
+
+impl CommandTransport {
+    /// Creates a new `CommandTransport` running the given shell command.
+    pub fn new(command: String) -> Self {
+        Self { command }
+    }
+}
+
+impl Transport for CommandTransport {
+    type Stream = CommandStream;
+    type Listener = NoListener;
+
+    fn connect(&self) -> io::Result<CommandStream> {
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command.arg("/C");
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c");
+            command
+        };
+
+        // Errors of the command, such as a failed SSH login, are shown to the user.
+        let mut child = command.arg(&self.command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        match (stdin, stdout) {
+            (Some(stdin), Some(stdout)) => { Ok(CommandStream { child, stdin: Some(stdin), stdout, command: self.command.clone() }) }
+            _ => { Err(io::Error::from(io::ErrorKind::BrokenPipe)) }
+        }
+    }
+
+    fn listen(&self) -> io::Result<NoListener> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Servers cannot listen on a command."))
+    }
+}
+
+/// A connection to the standard input and output of a command run by a `CommandTransport`. Dropping
+/// the stream closes the standard input of the command and waits for it to exit.
+#[derive(Debug)]
+pub struct CommandStream {
+    child: Child,
+    // Only None while dropping.
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+    command: String,
+}
+
+impl Read for CommandStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Write for CommandStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.stdin {
+            Some(stdin) => { stdin.write(buf) }
+            None => { Err(io::Error::from(io::ErrorKind::BrokenPipe)) }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stdin {
+            Some(stdin) => { stdin.flush() }
+            None => { Ok(()) }
+        }
+    }
+}
+
+impl Stream for CommandStream {
+    /// Pipes don't support timeouts, so a command that stops responding has to be stopped by the
+    /// command itself, for example with the `ServerAliveInterval` option of SSH.
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        format!("the command \"{}\"", self.command)
+    }
+}
+
+impl Drop for CommandStream {
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
+
+/// The listener of transports servers cannot listen on. No value of this type can exist.
+#[derive(Debug)]
+pub enum NoListener {}
+
+impl Listener for NoListener {
+    type Stream = CommandStream;
+
+    fn accept(&self) -> io::Result<CommandStream> {
+        match *self {}
+    }
+}
+
+/// A connection over the standard input and output of the current process. A server started by a
+/// `CommandTransport` handles its session over a `StdioStream`. Nothing else may be written to the
+/// standard output while the stream is used.
+#[derive(Debug)]
+pub struct StdioStream {
+    stdin: io::Stdin,
+    stdout: io::Stdout,
+}
+
+impl StdioStream {
+    /// Creates a new `StdioStream`.
+    pub fn new() -> Self {
+        Self { stdin: io::stdin(), stdout: io::stdout() }
+    }
+}
+
+impl Default for StdioStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Read for StdioStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for StdioStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+impl Stream for StdioStream {
+    /// Timeouts aren't supported, see `CommandStream::set_timeout`.
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        "the standard input and output".to_string()
+    }
+}
+
+/// A transport over in-memory pipes within a single process, mainly for testing. Clones of a
+/// `MemoryTransport` connect to the same server. Clients can connect before the server starts
+/// listening, and their connections are accepted once it does.
+#[derive(Clone, Debug)]
+pub struct MemoryTransport {
+    connections: Sender<MemoryStream>,
+    pending: Arc<Mutex<Option<Receiver<MemoryStream>>>>,
+}
+
+impl MemoryTransport {
+    /// Creates a new `MemoryTransport`.
+    pub fn new() -> Self {
+        let (connections, pending) = channel();
+        Self { connections, pending: Arc::new(Mutex::new(Some(pending))) }
+    }
+}
+
+impl Default for MemoryTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Stream = MemoryStream;
+    type Listener = MemoryListener;
+
+    fn connect(&self) -> io::Result<MemoryStream> {
+        let (client, server) = MemoryStream::pair();
+        self.connections.send(server).map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+
+    /// Starts listening to connections. Only one listener can exist for the clones of a
+    /// `MemoryTransport`.
+    fn listen(&self) -> io::Result<MemoryListener> {
+        let pending = self.pending.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match pending {
+            Some(connections) => { Ok(MemoryListener { connections }) }
+            None => { Err(io::Error::from(io::ErrorKind::AddrInUse)) }
+        }
+    }
+}
+
+/// A listener of a `MemoryTransport`.
+#[derive(Debug)]
+pub struct MemoryListener {
+    connections: Receiver<MemoryStream>,
+}
+
+impl Listener for MemoryListener {
+    type Stream = MemoryStream;
+
+    fn accept(&self) -> io::Result<MemoryStream> {
+        match self.connections.try_recv() {
+            Ok(stream) => { Ok(stream) }
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => { Err(io::Error::from(io::ErrorKind::WouldBlock)) }
+        }
+    }
+}
+
+/// One end of an in-memory pipe. Reading returns end of file once the other end has been dropped.
+#[derive(Debug)]
+pub struct MemoryStream {
+    sending: Sender<Vec<u8>>,
+    receiving: Receiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    timeout: Option<Duration>,
+}
+
+impl MemoryStream {
+    /// Creates the two connected ends of a pipe.
+    pub fn pair() -> (Self, Self) {
+        let (first_sending, second_receiving) = channel();
+        let (second_sending, first_receiving) = channel();
+
+        (
+            Self { sending: first_sending, receiving: first_receiving, buffer: Vec::new(), timeout: None },
+            Self { sending: second_sending, receiving: second_receiving, buffer: Vec::new(), timeout: None },
+        )
+    }
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buffer.is_empty() {
+            let received = match self.timeout {
+                Some(timeout) => {
+                    self.receiving.recv_timeout(timeout).map_err(|e| match e {
+                        RecvTimeoutError::Timeout => { io::Error::from(io::ErrorKind::TimedOut) }
+                        RecvTimeoutError::Disconnected => { io::Error::from(io::ErrorKind::UnexpectedEof) }
+                    })
+                }
+                None => { self.receiving.recv().map_err(|_| io::Error::from(io::ErrorKind::UnexpectedEof)) }
+            };
+
+            match received {
+                Ok(data) => { self.buffer = data; }
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => { return Ok(0); }
+                Err(e) => { return Err(e); }
+            }
+        }
+
+        let len = buf.len().min(self.buffer.len());
+        buf[..len].copy_from_slice(&self.buffer[..len]);
+        self.buffer.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.sending.send(buf.to_vec()).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Stream for MemoryStream {
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        "an in-memory pipe".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::time::Duration;
+
+    use crate::transport::{Listener, MemoryStream, MemoryTransport, Stream, Transport};
+
+    #[test]
+    fn memory_stream_transfers_bytes_in_order() {
+        let (mut client, mut server) = MemoryStream::pair();
+
+        client.write_all(b"Hello ").unwrap();
+        client.write_all(b"server").unwrap();
+
+        let mut buf = [0; 12];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hello server");
+    }
+
+    #[test]
+    fn memory_stream_reads_eof_after_other_end_is_dropped() {
+        let (mut client, server) = MemoryStream::pair();
+        drop(server);
+
+        assert_eq!(client.read(&mut [0; 4]).unwrap(), 0);
+        assert_eq!(client.write(b"Hello").unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn memory_stream_read_times_out() {
+        let (mut client, _server) = MemoryStream::pair();
+        client.set_timeout(Duration::from_millis(10)).unwrap();
+
+        assert_eq!(client.read(&mut [0; 4]).unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn memory_transport_accepts_connections_made_before_listening() {
+        let transport = MemoryTransport::new();
+
+        let mut client = transport.connect().unwrap();
+        let listener = transport.listen().unwrap();
+        let mut server = listener.accept().unwrap();
+
+        client.write_all(b"Hi").unwrap();
+        let mut buf = [0; 2];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hi");
+
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(transport.clone().listen().unwrap_err().kind(), ErrorKind::AddrInUse);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_transport_connects_to_command_stdio() {
+        use crate::transport::CommandTransport;
+
+        let mut stream = CommandTransport::new("tr a-z A-Z".to_string()).connect().unwrap();
+
+        stream.write_all(b"hello").unwrap();
+        drop(stream.stdin.take());
+
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "HELLO");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_transport_connects_to_listener() {
+        use crate::transport::UnixTransport;
+
+        let path = std::env::temp_dir().join(format!("mtd-transport-test-{}.sock", std::process::id()));
+        let transport = UnixTransport::new(path.clone());
+
+        let listener = transport.listen().unwrap();
+        let mut client = transport.connect().unwrap();
+        let mut server = listener.accept().unwrap();
+
+        client.write_all(b"Hi").unwrap();
+        let mut buf = [0; 2];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"Hi");
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_transport_does_not_remove_other_files() {
+        use crate::transport::UnixTransport;
+
+        let path = std::env::temp_dir().join(format!("mtd-unix-transport-test-{}.json", std::process::id()));
+        std::fs::write(&path, "{}").unwrap();
+
+        let err = UnixTransport::new(path.clone()).listen().unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{}");
+        std::fs::remove_file(path).unwrap();
+    }
+}